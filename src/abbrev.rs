@@ -0,0 +1,175 @@
+use mapping;
+
+/// Whether `c` can be part of a word, as `iskeyword` defaults to.
+pub fn is_keyword(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether `lhs` can be abbreviated: a word (`foo`), non-word characters
+/// ending in a word character (`#i`), or anything without white space
+/// ending in a non-word character (`def#`).
+pub fn valid_lhs(lhs: &str) -> bool {
+    let mut chars = lhs.chars().rev();
+    match chars.next() {
+        None => false,
+        Some(last) if is_keyword(last) => {
+            let rest: Vec<char> = chars.collect();
+            rest.iter().all(|&c| { is_keyword(c) }) || rest.iter().all(|&c| { !is_keyword(c) && !c.is_whitespace() })
+        },
+        Some(last) => !last.is_whitespace() && chars.all(|c| { !c.is_whitespace() }),
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Abbreviation {
+    pub lhs: String,
+    pub rhs: String,
+    /// `mapping::INSERT` and `mapping::CMDLINE` bits.
+    pub modes: u8,
+}
+
+impl Abbreviation {
+    /// The line `:abbreviate` lists the abbreviation with.
+    pub fn describe(&self) -> String {
+        let modes = match self.modes {
+            m if m == mapping::INSERT | mapping::CMDLINE => "!",
+            mapping::INSERT => "i",
+            _ => "c",
+        };
+        format!("{:<3}{:<13}  {}", modes, self.lhs, self.rhs)
+    }
+}
+
+/// The abbreviations defined with `:abbreviate` and its relatives.
+#[derive(Debug,Default)]
+pub struct Abbreviations {
+    entries: Vec<Abbreviation>,
+}
+
+impl Abbreviations {
+    pub fn new() -> Self {
+        Abbreviations { entries: vec![] }
+    }
+
+    /// Add an abbreviation, replacing what `lhs` expanded to in its modes.
+    pub fn add(&mut self, abbreviation: Abbreviation) {
+        self.remove(&abbreviation.lhs, abbreviation.modes);
+        self.entries.push(abbreviation);
+    }
+
+    /// Remove the abbreviations of `lhs` in `modes`.  Returns false if
+    /// there were none.
+    pub fn remove(&mut self, lhs: &str, modes: u8) -> bool {
+        let mut found = false;
+        for abbreviation in self.entries.iter_mut().filter(|abbreviation| { abbreviation.lhs == lhs }) {
+            found |= abbreviation.modes & modes != 0;
+            abbreviation.modes &= !modes;
+        }
+        self.entries.retain(|abbreviation| { abbreviation.modes != 0 });
+        found
+    }
+
+    /// Remove every abbreviation in `modes`.
+    pub fn clear(&mut self, modes: u8) {
+        for abbreviation in self.entries.iter_mut() {
+            abbreviation.modes &= !modes;
+        }
+        self.entries.retain(|abbreviation| { abbreviation.modes != 0 });
+    }
+
+    /// The abbreviations in any of `modes` starting with `prefix`, ordered
+    /// by what they abbreviate.
+    pub fn list(&self, modes: u8, prefix: &str) -> Vec<&Abbreviation> {
+        let mut found: Vec<&Abbreviation> = self.entries.iter()
+            .filter(|abbreviation| { abbreviation.modes & modes != 0 && abbreviation.lhs.starts_with(prefix) })
+            .collect();
+        found.sort_by(|a, b| { a.lhs.cmp(&b.lhs) });
+        found
+    }
+
+    /// The abbreviation `text`, what was typed before the cursor, ends
+    /// with in `mode` when `trigger` is typed next, or `None` for Enter or
+    /// Escape.  Returns where the abbreviation starts in `text` and what
+    /// it expands to.
+    ///
+    /// Only a non-word character ends an abbreviation, and white space is
+    /// needed after one that itself ends in a non-word character.  The
+    /// abbreviation must follow white space or start the text; a word may
+    /// also follow a non-word character.
+    pub fn expand(&self, text: &str, mode: u8, trigger: Option<char>) -> Option<(usize, String)> {
+        if trigger.is_some_and(is_keyword) {
+            return None;
+        }
+        self.entries.iter().filter(|abbreviation| { abbreviation.modes & mode != 0 }).find_map(|abbreviation| {
+            let lhs = &abbreviation.lhs;
+            if !text.ends_with(lhs.as_str()) {
+                return None;
+            }
+            let word = lhs.chars().all(is_keyword);
+            let ends_in_keyword = lhs.chars().next_back().is_some_and(is_keyword);
+            if !ends_in_keyword && trigger.is_some_and(|c| { !c.is_whitespace() }) {
+                return None;
+            }
+            let start = text.len() - lhs.len();
+            let follows = match text[.. start].chars().next_back() {
+                None => true,
+                Some(c) => c.is_whitespace() || (word && !is_keyword(c)),
+            };
+            if follows { Some((start, abbreviation.rhs.clone())) } else { None }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapping::{INSERT, CMDLINE};
+
+    fn abbreviations() -> Abbreviations {
+        let mut abbreviations = Abbreviations::new();
+        for &(lhs, rhs, modes) in [("teh", "the", INSERT | CMDLINE), ("#i", "#include", INSERT), ("dt/", "date", INSERT), ("W", "w", CMDLINE)].iter() {
+            abbreviations.add(Abbreviation { lhs: lhs.to_string(), rhs: rhs.to_string(), modes });
+        }
+        abbreviations
+    }
+
+    #[test]
+    fn valid_abbreviations() {
+        assert!(valid_lhs("foo"));
+        assert!(valid_lhs("#i"));
+        assert!(valid_lhs("..f"));
+        assert!(valid_lhs("def#"));
+        assert!(!valid_lhs("a.b"));
+        assert!(!valid_lhs("a b"));
+        assert!(!valid_lhs(""));
+    }
+
+    #[test]
+    fn expansion() {
+        let abbreviations = abbreviations();
+        assert_eq!(abbreviations.expand("fix teh", INSERT, Some(' ')), Some((4, "the".to_string())));
+        assert_eq!(abbreviations.expand("(teh", INSERT, Some(')')), Some((1, "the".to_string())));
+        assert_eq!(abbreviations.expand("teh", INSERT, None), Some((0, "the".to_string())));
+        assert_eq!(abbreviations.expand("teh", INSERT, Some('n')), None);
+        assert_eq!(abbreviations.expand("steh", INSERT, Some(' ')), None);
+        assert_eq!(abbreviations.expand("#i", INSERT, Some(' ')), Some((0, "#include".to_string())));
+        assert_eq!(abbreviations.expand("x#i", INSERT, Some(' ')), None);
+        assert_eq!(abbreviations.expand("dt/", INSERT, Some('/')), None);
+        assert_eq!(abbreviations.expand("on dt/", INSERT, Some(' ')), Some((3, "date".to_string())));
+        assert_eq!(abbreviations.expand("W", INSERT, Some(' ')), None);
+        assert_eq!(abbreviations.expand("W", CMDLINE, Some(' ')), Some((0, "w".to_string())));
+    }
+
+    #[test]
+    fn add_remove_and_list() {
+        let mut abbreviations = abbreviations();
+        abbreviations.add(Abbreviation { lhs: "teh".to_string(), rhs: "THE".to_string(), modes: CMDLINE });
+        let lines: Vec<String> = abbreviations.list(INSERT | CMDLINE, "t").iter().map(|abbreviation| { abbreviation.describe() }).collect();
+        assert_eq!(lines, vec!["i  teh            the", "c  teh            THE"]);
+        assert!(abbreviations.remove("teh", INSERT));
+        assert!(!abbreviations.remove("teh", INSERT));
+        assert_eq!(abbreviations.list(INSERT, "").len(), 2);
+        abbreviations.clear(INSERT);
+        assert_eq!(abbreviations.list(INSERT | CMDLINE, "").len(), 2);
+    }
+}
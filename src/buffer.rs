@@ -1,6 +1,317 @@
 use std::io;
 use std::io::{Read,Write};
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{chown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use compress::Codec;
+use config;
+
+use encoding::{self, Encoding};
+use options::{self, Options, Value};
+
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FileFormat {
+    /// Lines end with `\n`.
+    Unix,
+    /// Lines end with `\r\n`.
+    Dos,
+    /// Lines end with `\r`.
+    Mac,
+}
+
+impl FileFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(FileFormat::Unix),
+            "dos" => Some(FileFormat::Dos),
+            "mac" => Some(FileFormat::Mac),
+            _ => None,
+        }
+    }
+
+    /// The `fileformat` option value for this format.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FileFormat::Unix => "unix",
+            FileFormat::Dos => "dos",
+            FileFormat::Mac => "mac",
+        }
+    }
+
+    pub fn line_ending(&self) -> &'static str {
+        match *self {
+            FileFormat::Unix => "\n",
+            FileFormat::Dos => "\r\n",
+            FileFormat::Mac => "\r",
+        }
+    }
+
+    /// Guess the format of `text` the way vim does: DOS if every line feed
+    /// is preceded by a carriage return, Unix if there are any other line
+    /// feeds, and Mac if there are only carriage returns.  Stray carriage
+    /// returns in a Unix file are kept as part of the line.
+    pub fn detect(text: &str) -> Self {
+        let line_feeds = text.matches('\n').count();
+        if line_feeds > 0 && text.matches("\r\n").count() == line_feeds {
+            FileFormat::Dos
+        } else if line_feeds > 0 || !text.contains('\r') {
+            FileFormat::Unix
+        } else {
+            FileFormat::Mac
+        }
+    }
+}
+
+/// How lines are terminated and encoded when a buffer is written.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct WriteFormat {
+    pub fileformat: FileFormat,
+    /// Whether the last line gets a line ending.
+    pub endofline: bool,
+    pub encoding: Encoding,
+    /// Whether to start the file with a byte order mark.
+    pub bomb: bool,
+}
+
+impl WriteFormat {
+    /// Build a format from the `fileformat`, `endofline`, `fixendofline`,
+    /// `fileencoding` and `bomb` values returned by `option`.
+    pub fn from_options<F>(option: F) -> Result<Self, String> where F: Fn(&str) -> Value {
+        let fileencoding = option("fileencoding").to_string();
+        let encoding = match Encoding::from_name(&fileencoding) {
+            Some(encoding) => encoding,
+            None => return Err(format!("E474: Invalid argument: fileencoding={}", fileencoding)),
+        };
+        Ok(WriteFormat {
+            fileformat: FileFormat::from_name(&option("fileformat").to_string()).unwrap_or(FileFormat::Unix),
+            endofline: option("endofline") == Value::Bool(true) || option("fixendofline") == Value::Bool(true),
+            encoding,
+            bomb: option("bomb") == Value::Bool(true),
+        })
+    }
+
+    pub fn join(&self, lines: &[String]) -> String {
+        let ending = self.fileformat.line_ending();
+        let mut text = lines.join(ending);
+        if self.endofline && !lines.is_empty() {
+            text.push_str(ending);
+        }
+        text
+    }
+
+    /// The bytes to write for `lines`.
+    pub fn encode(&self, lines: &[String]) -> Result<Vec<u8>, String> {
+        encoding::encode(&self.join(lines), self.encoding, self.bomb)
+    }
+}
+
+/// Whether and where to keep a copy of the original file when saving.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Backup {
+    /// Keep the backup after a successful write.
+    pub backup: bool,
+    /// Make a backup before writing, deleting it afterwards unless `backup`
+    /// is also set.
+    pub writebackup: bool,
+    /// Comma-separated directories to try for the backup; `.` is the
+    /// directory of the file itself.
+    pub backupdir: String,
+    pub backupext: String,
+}
+
+impl Backup {
+    /// Build the backup settings from the `backup`, `writebackup`,
+    /// `backupdir` and `backupext` values returned by `option`.
+    pub fn from_options<F>(option: F) -> Self where F: Fn(&str) -> Value {
+        Backup {
+            backup: option("backup") == Value::Bool(true),
+            writebackup: option("writebackup") == Value::Bool(true),
+            backupdir: option("backupdir").to_string(),
+            backupext: option("backupext").to_string(),
+        }
+    }
+
+    /// Copy `path` into the first usable backup directory, returning the
+    /// backup's path.
+    fn make(&self, path: &Path) -> io::Result<PathBuf> {
+        let name = match path.file_name() {
+            Some(name) => format!("{}{}", name.to_string_lossy(), self.backupext),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot back up a directory")),
+        };
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No usable backupdir");
+        for dir in self.backupdir.split(',').filter(|dir| { !dir.is_empty() }) {
+            let dir = if dir == "." {
+                path.parent().map(|parent| { parent.to_path_buf() }).unwrap_or_default()
+            } else {
+                config::expand_home(dir)
+            };
+            let target = dir.join(&name);
+            match fs::copy(path, &target) {
+                Ok(_) => return Ok(target),
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Replace the file at `path` with `bytes` without ever leaving it
+/// half-written.  The data goes to a temporary file in the same directory
+/// which is synced to disk, given the original's permissions and owner,
+/// and then renamed over the original.  When the directory cannot be
+/// written to, the original is overwritten in place, but only if `force`
+/// is set, as by `:w!`.
+pub fn save(path: &Path, bytes: &[u8], backup: &Backup, force: bool) -> io::Result<()> {
+    // Write through symlinks rather than replacing them.
+    let path = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => path.to_path_buf(),
+    };
+    let original = fs::metadata(&path).ok();
+    match original {
+        Some(ref metadata) if metadata.is_dir() => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "E502: is a directory"));
+        },
+        // Devices and pipes, e.g. /dev/null, are written to as they are.
+        Some(ref metadata) if !metadata.is_file() => {
+            return OpenOptions::new().write(true).open(&path).and_then(|mut file| { file.write_all(bytes) });
+        },
+        _ => {},
+    }
+    let backup_path = match original {
+        Some(ref metadata) if metadata.is_file() && (backup.backup || backup.writebackup) => Some(backup.make(&path)?),
+        _ => None,
+    };
+    let result = match original {
+        // Renaming a new file over one with other names would split it
+        // from them.
+        Some(ref metadata) if metadata.nlink() > 1 => overwrite(&path, bytes, metadata),
+        _ => replace(&path, bytes, original.as_ref(), force),
+    };
+    if let Some(backup_path) = backup_path {
+        if result.is_ok() && !backup.backup {
+            fs::remove_file(backup_path).ok();
+        }
+    }
+    result
+}
+
+/// Write `bytes` to a temporary file next to `path` and rename it over
+/// `path`, keeping the permissions and owner of the `original` file.
+fn replace(path: &Path, bytes: &[u8], original: Option<&fs::Metadata>, force: bool) -> io::Result<()> {
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a file name")),
+    };
+    let temp = path.with_file_name(format!(".{}.{}.virs-tmp", file_name, process::id()));
+    // Never readable by more people than the original, even briefly.
+    let mode = original.map_or(0o666, |metadata| { metadata.mode() & 0o777 });
+    if let Err(err) = write_synced(&temp, bytes, mode) {
+        fs::remove_file(&temp).ok();
+        // Without a writable directory the file can only be overwritten.
+        return match original {
+            Some(metadata) if force && err.kind() == io::ErrorKind::PermissionDenied => overwrite(path, bytes, metadata),
+            _ => Err(err),
+        };
+    }
+    if let Some(metadata) = original {
+        // Only root can give a file away.  Rather than hand someone else's
+        // file to the current user, it is then overwritten in place.
+        let kept = fs::set_permissions(&temp, metadata.permissions())
+            .and_then(|_| { chown(&temp, Some(metadata.uid()), Some(metadata.gid())) });
+        if kept.is_err() {
+            fs::remove_file(&temp).ok();
+            return overwrite(path, bytes, metadata);
+        }
+    }
+    let result = fs::rename(&temp, path);
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Write `bytes` over the existing file at `path`, briefly adding the
+/// owner's write permission if the file is read-only.
+fn overwrite(path: &Path, bytes: &[u8], metadata: &fs::Metadata) -> io::Result<()> {
+    let permissions = metadata.permissions();
+    if permissions.readonly() {
+        let mut writable = permissions.clone();
+        writable.set_mode(permissions.mode() | 0o200);
+        fs::set_permissions(path, writable)?;
+    }
+    let result = File::create(path).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if permissions.readonly() {
+        fs::set_permissions(path, permissions).ok();
+    }
+    result
+}
+
+/// Directories, devices and pipes cannot be edited as buffers.
+fn check_regular_file(metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.is_dir() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "E502: is a directory"))
+    } else if !metadata.is_file() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "E503: is not a file"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the file at `path` can be written.  A file with no write
+/// permission bits counts as read-only even for root.
+pub fn writable(path: &Path, metadata: &fs::Metadata) -> bool {
+    !metadata.permissions().readonly() && OpenOptions::new().write(true).open(path).is_ok()
+}
+
+fn write_synced(path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// What is remembered about a file to notice when something else changes it.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct FileStat {
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub size: u64,
+    pub inode: u64,
+}
+
+impl FileStat {
+    pub fn of(path: &Path) -> Option<Self> {
+        fs::metadata(path).ok().map(|metadata| {
+            FileStat {
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                size: metadata.size(),
+                inode: metadata.ino(),
+            }
+        })
+    }
+}
+
+/// Split `text` into lines using `format`.  Also returns whether the last
+/// line was terminated.
+pub fn split_lines(text: &str, format: FileFormat) -> (Vec<String>, bool) {
+    if text.is_empty() {
+        return (vec![], true);
+    }
+    let ending = format.line_ending();
+    let mut lines: Vec<String> = text.split(ending).map(|x| { x.to_string() }).collect();
+    let endofline = text.ends_with(ending);
+    if endofline {
+        lines.pop();
+    }
+    (lines, endofline)
+}
 
 
 #[derive(Debug,PartialEq)]
@@ -8,26 +319,141 @@ pub struct Buffer {
     pub filename: Option<String>,
     pub content: Vec<String>,
     pub top_visible: usize,
+    /// Buffer-local option values set with `:setlocal` or when the file was read.
+    pub options: Options,
+    /// Counts changes to `content`.
+    pub changedtick: u64,
+    /// The value of `changedtick` when the buffer was last read or written.
+    pub savedtick: u64,
+    /// The file did not exist when the buffer was opened and has not been
+    /// written since.
+    pub new_file: bool,
+    /// The state of the file when it was last read or written.
+    pub stat: Option<FileStat>,
+    /// How the file was compressed, so it is written back the same way.
+    pub compression: Option<Codec>,
 }
 
 
 impl Buffer {
     pub fn new() -> Self {
-        Buffer { filename: None, content: vec![], top_visible: 0 }
+        Buffer { filename: None, content: vec![], top_visible: 0, options: Options::new(), changedtick: 0, savedtick: 0, new_file: false, stat: None, compression: None }
     }
 
+    /// Open `filename`, or start an empty buffer with that name if the file
+    /// does not exist yet.
     pub fn open(filename: &str) -> io::Result<Self> {
-        // TODO:
-        // * Handle opening a new file
-        // * Handle file permissions
-        let mut file = try!(File::open(filename));
-        let mut buf = String::new();
-        try!(file.read_to_string(&mut buf));
-        let lines: Vec<String> = buf.lines().map(|x|{ x.to_string() }).collect();
-        Ok(Buffer { filename: Some(filename.to_string()), content: lines, top_visible: 0 })
+        Buffer::open_with(filename, &default_fileencodings())
+    }
+
+    /// Open `filename`, decoding it with the first encoding from the
+    /// comma-separated `fileencodings` that fits.
+    /// Files that cannot be written are opened with `readonly` set.
+    pub fn open_with(filename: &str, fileencodings: &str) -> io::Result<Self> {
+        let metadata = match fs::metadata(filename) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut buffer = Buffer::new();
+                buffer.filename = Some(filename.to_string());
+                buffer.new_file = true;
+                return Ok(buffer);
+            },
+            Err(err) => return Err(err),
+        };
+        check_regular_file(&metadata)?;
+        let mut bytes = vec![];
+        File::open(filename)?.read_to_end(&mut bytes)?;
+        // Data that only looks compressed is edited as it is.
+        let mut compression = Codec::from_magic(&bytes);
+        if let Some(codec) = compression {
+            match codec.decompress(&bytes) {
+                Ok(decompressed) => bytes = decompressed,
+                Err(_) => compression = None,
+            }
+        }
+        let mut buffer = Buffer::read_with(&bytes[..], fileencodings)?;
+        buffer.compression = compression;
+        buffer.filename = Some(filename.to_string());
+        buffer.stat = FileStat::of(Path::new(filename));
+        if !writable(Path::new(filename), &metadata) {
+            buffer.options.set("readonly", Value::Bool(true));
+        }
+        Ok(buffer)
+    }
+
+    /// Read an unnamed buffer from `reader`, e.g. stdin.
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        Buffer::read_with(reader, &default_fileencodings())
     }
 
+    /// Read an unnamed buffer from `reader`.  The detected `fileencoding`,
+    /// `bomb`, `fileformat` and `endofline` are recorded as buffer-local
+    /// options so the file is written back the same way.
+    pub fn read_with<R: Read>(mut reader: R, fileencodings: &str) -> io::Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let (text, encoding, bomb) = match encoding::decode(&bytes, fileencodings) {
+            Ok(decoded) => decoded,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        let fileformat = FileFormat::detect(&text);
+        let (lines, endofline) = split_lines(&text, fileformat);
+        let mut options = Options::new();
+        options.set("fileencoding", Value::Str(encoding.name().to_string()));
+        options.set("bomb", Value::Bool(bomb));
+        options.set("fileformat", Value::Str(fileformat.name().to_string()));
+        options.set("endofline", Value::Bool(endofline));
+        Ok(Buffer { filename: None, content: lines, top_visible: 0, options, changedtick: 0, savedtick: 0, new_file: false, stat: None, compression: None })
+    }
+
+    /// Record a change to `content`.
+    pub fn changed(&mut self) {
+        self.changedtick += 1;
+    }
+
+    /// Record that `content` now matches the file.
+    pub fn saved(&mut self) {
+        self.savedtick = self.changedtick;
+        self.new_file = false;
+    }
+
+    pub fn modified(&self) -> bool {
+        self.changedtick != self.savedtick
+    }
+
+    /// Whether the file was changed or removed by something else since it
+    /// was last read or written.
+    pub fn changed_on_disk(&self) -> bool {
+        match (self.filename.as_ref(), self.stat) {
+            (Some(filename), Some(stat)) => FileStat::of(Path::new(filename)) != Some(stat),
+            _ => false,
+        }
+    }
+
+    /// The format recorded for this buffer, falling back to the option
+    /// defaults for settings it has no local value for.
+    pub fn write_format(&self) -> Result<WriteFormat, String> {
+        WriteFormat::from_options(|name| {
+            match self.options.get(name) {
+                Some(value) => value.clone(),
+                None => options::lookup(name).unwrap().default_value(),
+            }
+        })
+    }
+
+    /// Write the whole buffer using its own recorded format.
     pub fn write(&self, filename: Option<&str>) -> io::Result<()> {
+        let backup = Backup::from_options(|name| { options::lookup(name).unwrap().default_value() });
+        match self.write_format() {
+            Ok(format) => self.write_lines(filename, 0, self.content.len(), &format, &backup, false),
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
+        }
+    }
+
+    /// Write lines `start .. end` (counted from 0) to `filename`, or to the
+    /// buffer's own file if none is given.  `force` is passed on to `save`.
+    pub fn write_lines(&self, filename: Option<&str>, start: usize, end: usize,
+                       format: &WriteFormat, backup: &Backup, force: bool) -> io::Result<()> {
         let filename = match filename {
             Some(filename) => filename,
             None => match self.filename {
@@ -35,12 +461,41 @@ impl Buffer {
                 None => return Err(io::Error::new(io::ErrorKind::Other, "No file specified")),
             }
         };
-        let mut file = try!(File::create(filename));
-        for line in self.content.iter() {
-            try!(file.write(line.as_bytes()));
+        let bytes = self.encode_for(filename, start, end, format)?;
+        save(Path::new(filename), &bytes, backup, force)
+    }
+
+    /// The bytes to write for lines `start .. end` to `filename`,
+    /// compressed if the name asks for it or it is the buffer's own
+    /// compressed file.
+    fn encode_for(&self, filename: &str, start: usize, end: usize, format: &WriteFormat) -> io::Result<Vec<u8>> {
+        let bytes = match format.encode(&self.content[start .. end]) {
+            Ok(bytes) => bytes,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        let own = self.filename.as_deref() == Some(filename);
+        match Codec::from_path(Path::new(filename)).or(if own { self.compression } else { None }) {
+            Some(codec) => codec.compress(&bytes),
+            None => Ok(bytes),
         }
-        Ok(())
     }
+
+    /// Append lines `start .. end` (counted from 0) to `filename`, creating
+    /// it only if `create` is set.  No byte order mark is written.
+    pub fn append_lines(&self, filename: &str, start: usize, end: usize,
+                        format: &WriteFormat, create: bool) -> io::Result<()> {
+        let format = WriteFormat { bomb: false, ..*format };
+        // Compressed formats allow concatenated streams, so appending a
+        // separately compressed chunk works.
+        let bytes = self.encode_for(filename, start, end, &format)?;
+        let mut file = OpenOptions::new().append(true).create(create).open(filename)?;
+        file.write_all(&bytes)?;
+        file.sync_all()
+    }
+}
+
+fn default_fileencodings() -> String {
+    options::lookup("fileencodings").unwrap().default.to_string()
 }
 
 
@@ -52,10 +507,77 @@ mod tests {
     fn new_buffer() {
         assert_eq!(
             Buffer::new(),
-            Buffer { filename: None, content: vec![], top_visible: 0 }
+            Buffer { filename: None, content: vec![], top_visible: 0, options: Options::new(), changedtick: 0, savedtick: 0, new_file: false, stat: None, compression: None }
         );
     }
 
+    #[test]
+    fn modified_tracking() {
+        let mut buf = Buffer::new();
+        assert!(!buf.modified());
+        buf.changed();
+        assert!(buf.modified());
+        buf.saved();
+        assert!(!buf.modified());
+    }
+
+    #[test]
+    fn notice_changes_on_disk() {
+        let path = "/tmp/virs-changed-on-disk";
+        File::create(path).unwrap().write_all(b"one\n").unwrap();
+        let buf = Buffer::open(path).unwrap();
+        assert!(!buf.changed_on_disk());
+        File::create(path).unwrap().write_all(b"one\ntwo\n").unwrap();
+        assert!(buf.changed_on_disk());
+        fs::remove_file(path).unwrap();
+        assert!(buf.changed_on_disk());
+        assert!(!Buffer::new().changed_on_disk());
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let path = "/tmp/virs-compressed.log.gz";
+        let compressed = Codec::Gzip.compress(b"line one\nline two\n").unwrap();
+        File::create(path).unwrap().write_all(&compressed).unwrap();
+        let mut buf = Buffer::open(path).unwrap();
+        assert_eq!(buf.compression, Some(Codec::Gzip));
+        assert_eq!(buf.content, vec!["line one".to_string(), "line two".to_string()]);
+        buf.content.push("line three".to_string());
+        buf.write(None).unwrap();
+        let mut bytes = vec![];
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert_eq!(Codec::from_magic(&bytes), Some(Codec::Gzip));
+        assert_eq!(Buffer::open(path).unwrap().content.len(), 3);
+
+        // Plain copies stay plain; a new name chooses its own codec.
+        buf.write(Some("/tmp/virs-decompressed.log")).unwrap();
+        assert_eq!(Buffer::open("/tmp/virs-decompressed.log").unwrap().compression, None);
+        buf.write(Some("/tmp/virs-recompressed.log.xz")).unwrap();
+        assert_eq!(Buffer::open("/tmp/virs-recompressed.log.xz").unwrap().compression, Some(Codec::Xz));
+
+        File::create("/tmp/virs-fake.gz").unwrap().write_all(b"\x1f\x8bnot really\n").unwrap();
+        let fake = Buffer::open("/tmp/virs-fake.gz").unwrap();
+        assert_eq!(fake.compression, None);
+        assert_eq!(fake.content.len(), 1);
+    }
+
+    #[test]
+    fn open_missing_file() {
+        let buf = match Buffer::open("/tmp/virs-no-such-file") {
+            Ok(buf) => buf,
+            Err(_) => panic!("couldn't open buffer"),
+        };
+        assert_eq!(buf.filename, Some("/tmp/virs-no-such-file".to_string()));
+        assert_eq!(buf.content.len(), 0);
+    }
+
+    #[test]
+    fn read_buffer() {
+        let buf = Buffer::read("one\ntwo\n".as_bytes()).unwrap();
+        assert_eq!(buf.filename, None);
+        assert_eq!(buf.content, vec!["one".to_string(), "two".to_string()]);
+    }
+
     #[test]
     fn open_buffer() {
         let buf = match Buffer::open("/etc/hostname") {
@@ -66,6 +588,114 @@ mod tests {
         assert_eq!(buf.content.len(), 1);  // Actual contents depend on your hostname
     }
 
+    #[test]
+    fn detect_fileformat() {
+        assert_eq!(FileFormat::detect("a\nb\n"), FileFormat::Unix);
+        assert_eq!(FileFormat::detect("a\r\nb\r\n"), FileFormat::Dos);
+        assert_eq!(FileFormat::detect("a\r\nb\n"), FileFormat::Unix);
+        assert_eq!(FileFormat::detect("a\rb\r"), FileFormat::Mac);
+        assert_eq!(FileFormat::detect("a"), FileFormat::Unix);
+    }
+
+    #[test]
+    fn split_and_join() {
+        assert_eq!(split_lines("a\r\nb", FileFormat::Dos), (vec!["a".to_string(), "b".to_string()], false));
+        assert_eq!(split_lines("a\r\nb\n", FileFormat::Unix), (vec!["a\r".to_string(), "b".to_string()], true));
+        assert_eq!(split_lines("\n", FileFormat::Unix), (vec!["".to_string()], true));
+        assert_eq!(split_lines("", FileFormat::Unix), (vec![], true));
+        let dos = WriteFormat { fileformat: FileFormat::Dos, endofline: false, encoding: Encoding::Utf8, bomb: false };
+        assert_eq!(dos.join(&["a".to_string(), "b".to_string()]), "a\r\nb");
+        let mac = WriteFormat { fileformat: FileFormat::Mac, endofline: true, encoding: Encoding::Utf8, bomb: false };
+        assert_eq!(mac.join(&["a".to_string(), "b".to_string()]), "a\rb\r");
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in ["one\r\ntwo\r\n", "one\ntwo", "one\rtwo\r", "mixed\r\nendings\n", ""].iter() {
+            let buf = Buffer::read(text.as_bytes()).unwrap();
+            buf.write(Some("/tmp/virs-round-trip")).unwrap();
+            let mut written = String::new();
+            File::open("/tmp/virs-round-trip").unwrap().read_to_string(&mut written).unwrap();
+            assert_eq!(&written, text);
+        }
+    }
+
+    #[test]
+    fn round_trip_encodings() {
+        let files: [&[u8]; 3] = [b"caf\xe9\n", b"\xff\xfeh\x00i\x00\n\x00", b"\xef\xbb\xbfhi\n"];
+        for bytes in files.iter() {
+            let buf = Buffer::read(*bytes).unwrap();
+            buf.write(Some("/tmp/virs-round-trip-encoding")).unwrap();
+            let mut written = vec![];
+            File::open("/tmp/virs-round-trip-encoding").unwrap().read_to_end(&mut written).unwrap();
+            assert_eq!(&written, bytes);
+        }
+    }
+
+    #[test]
+    fn read_undecodable() {
+        assert!(Buffer::read_with(&b"caf\xe9"[..], "utf-8").is_err());
+    }
+
+    #[test]
+    fn save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = Path::new("/tmp/virs-permissions");
+        File::create(path).unwrap().write_all(b"old\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o640)).unwrap();
+        let mut buf = Buffer::new();
+        buf.content.push("new".to_string());
+        buf.write(Some("/tmp/virs-permissions")).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(Buffer::open("/tmp/virs-permissions").unwrap().content, vec!["new".to_string()]);
+        assert!(!Path::new("/tmp/virs-permissions~").exists());
+    }
+
+    #[test]
+    fn save_keeps_hard_links() {
+        let backup = Backup { backup: false, writebackup: false, backupdir: ".".to_string(), backupext: "~".to_string() };
+        let path = Path::new("/tmp/virs-linked");
+        fs::remove_file("/tmp/virs-link").ok();
+        File::create(path).unwrap().write_all(b"old\n").unwrap();
+        fs::hard_link(path, "/tmp/virs-link").unwrap();
+        save(path, b"new\n", &backup, false).unwrap();
+        let mut linked = String::new();
+        File::open("/tmp/virs-link").unwrap().read_to_string(&mut linked).unwrap();
+        assert_eq!(linked, "new\n");
+    }
+
+    #[test]
+    fn save_with_backup() {
+        fs::create_dir_all("/tmp/virs-backups").unwrap();
+        let path = Path::new("/tmp/virs-backed-up");
+        File::create(path).unwrap().write_all(b"old\n").unwrap();
+        let backup = Backup {
+            backup: true,
+            writebackup: true,
+            backupdir: "/nonexistent,/tmp/virs-backups".to_string(),
+            backupext: ".bak".to_string(),
+        };
+        save(path, b"new\n", &backup, false).unwrap();
+        let mut saved = String::new();
+        File::open("/tmp/virs-backups/virs-backed-up.bak").unwrap().read_to_string(&mut saved).unwrap();
+        assert_eq!(saved, "old\n");
+    }
+
+    #[test]
+    fn save_to_device() {
+        let backup = Backup { backup: true, writebackup: true, backupdir: ".".to_string(), backupext: "~".to_string() };
+        save(Path::new("/dev/null"), b"discarded\n", &backup, false).unwrap();
+        let err = save(Path::new("/tmp"), b"x", &backup, false).unwrap_err();
+        assert_eq!(err.to_string(), "E502: is a directory");
+    }
+
+    #[test]
+    fn save_reports_real_error() {
+        let backup = Backup { backup: false, writebackup: false, backupdir: ".".to_string(), backupext: "~".to_string() };
+        let err = save(Path::new("/tmp/virs-no-such-dir/file"), b"x", &backup, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
     #[test]
     fn write_buffer() {
         let mut buf = Buffer::new();
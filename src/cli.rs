@@ -0,0 +1,120 @@
+pub const USAGE: &str = "\
+Usage: virs [options] [file ...]
+
+Options:
+   +              Start at the last line of the first file
+   +N             Start at line N of the first file
+   +/pattern      Start at the first line matching pattern
+   +cmd, -c cmd   Execute ex command cmd after loading the first file
+   -R             Read-only mode
+   -n             Do not use a swap file
+   -r             List swap files
+   -r file        Recover file from its swap file
+   -u rcfile      Use rcfile instead of the usual startup files (NONE to skip them)
+   -              Read the buffer from stdin
+   --help         Print this message and exit
+   --version      Print version information and exit";
+
+#[derive(Debug,Default,PartialEq)]
+pub struct Args {
+    pub files: Vec<String>,
+    pub commands: Vec<String>,
+    pub readonly: bool,
+    pub noswap: bool,
+    pub recover: bool,
+    pub rcfile: Option<String>,
+    pub stdin: bool,
+    pub help: bool,
+    pub version: bool,
+}
+
+impl Args {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+/// Parse the command line arguments, not including the program name.
+pub fn parse(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::new();
+    let mut args = args.iter();
+    let mut options_done = false;
+    while let Some(arg) = args.next() {
+        if options_done {
+            parsed.files.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--" => options_done = true,
+            "--help" | "-h" => parsed.help = true,
+            "--version" => parsed.version = true,
+            "-" => parsed.stdin = true,
+            "-R" => parsed.readonly = true,
+            "-n" => parsed.noswap = true,
+            "-r" => parsed.recover = true,
+            "-c" => match args.next() {
+                Some(command) => parsed.commands.push(command.clone()),
+                None => return Err("Argument missing after: \"-c\"".to_string()),
+            },
+            "-u" => match args.next() {
+                Some(rcfile) => parsed.rcfile = Some(rcfile.clone()),
+                None => return Err("Argument missing after: \"-u\"".to_string()),
+            },
+            "+" => parsed.commands.push("$".to_string()),
+            _ if arg.starts_with('+') => parsed.commands.push(arg[1..].to_string()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option argument: \"{}\"", arg)),
+            _ => parsed.files.push(arg.clone()),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|x| { x.to_string() }).collect()
+    }
+
+    #[test]
+    fn no_arguments() {
+        assert_eq!(parse(&[]), Ok(Args::new()));
+    }
+
+    #[test]
+    fn multiple_files() {
+        let parsed = parse(&args(&["one.txt", "two.txt"])).unwrap();
+        assert_eq!(parsed.files, args(&["one.txt", "two.txt"]));
+    }
+
+    #[test]
+    fn startup_commands() {
+        let parsed = parse(&args(&["+12", "file", "-c", "3y", "+/main", "+"])).unwrap();
+        assert_eq!(parsed.files, args(&["file"]));
+        assert_eq!(parsed.commands, args(&["12", "3y", "/main", "$"]));
+    }
+
+    #[test]
+    fn flags() {
+        let parsed = parse(&args(&["-R", "-n", "-r", "-u", "NONE", "-"])).unwrap();
+        assert!(parsed.readonly);
+        assert!(parsed.noswap);
+        assert!(parsed.recover);
+        assert!(parsed.stdin);
+        assert_eq!(parsed.rcfile, Some("NONE".to_string()));
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let parsed = parse(&args(&["--", "-R", "+3"])).unwrap();
+        assert!(!parsed.readonly);
+        assert_eq!(parsed.files, args(&["-R", "+3"]));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(parse(&args(&["-c"])).is_err());
+        assert!(parse(&args(&["--frobnicate"])).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use rustbox::Key;
+
+use complete::Completion;
+use edit;
+
+/// What a key typed on the command line did.
+#[derive(Debug,PartialEq)]
+pub enum Outcome {
+    /// Keep reading keys.
+    Continue,
+    /// Enter: run the line.
+    Accept,
+    /// Escape, or Backspace on an empty line.
+    Cancel,
+    /// `Ctrl-R {reg}`: insert the contents of register `reg`.
+    Register(char),
+    /// `Ctrl-F`: continue in the command-line window.
+    Window,
+    /// Tab: complete the word before the cursor.
+    Complete,
+    /// `Ctrl-N` or `Ctrl-P` while completing: show the next or previous
+    /// match.
+    Cycle(bool),
+}
+
+/// The text typed at a `:` or `/` prompt and the keys that edit it.
+pub struct LineEditor {
+    pub text: String,
+    /// The byte offset of the cursor in `text`.
+    pub cursor: usize,
+    /// Set by `Ctrl-R` until the register name is typed.
+    register: bool,
+    /// Set by `Ctrl-V` until the key to insert literally is typed.
+    literal: bool,
+    /// While browsing the history with Up and Down: the text typed before
+    /// and the index of the entry shown.
+    browsing: Option<(String, usize)>,
+    /// The completion Tab started, until another key is typed.
+    pub completion: Option<Completion>,
+}
+
+impl LineEditor {
+    pub fn new(initial: &str) -> Self {
+        LineEditor { text: initial.to_string(), cursor: initial.len(), register: false, literal: false, browsing: None, completion: None }
+    }
+
+    /// The cursor position counted in characters, for drawing.
+    pub fn column(&self) -> usize {
+        self.text[.. self.cursor].chars().count()
+    }
+
+    pub fn insert_str(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Whether the next key is inserted literally, after `Ctrl-V`.
+    pub fn quoting(&self) -> bool {
+        self.literal
+    }
+
+    /// Replace the text from byte `start` to the cursor with `word`.
+    pub fn replace_word(&mut self, start: usize, word: &str) {
+        self.text.replace_range(start .. self.cursor, word);
+        self.cursor = start + word.len();
+    }
+
+    /// Handle a key, with `history` the entries Up and Down, or `Ctrl-P`
+    /// and `Ctrl-N` when not completing, go through.
+    pub fn key(&mut self, key: Key, history: &History) -> Outcome {
+        if self.register {
+            self.register = false;
+            return match key {
+                Key::Char(name) => Outcome::Register(name),
+                _ => Outcome::Continue,
+            };
+        }
+        if self.literal {
+            self.literal = false;
+            if let Some(c) = edit::literal_char(key) {
+                self.text.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            }
+            return Outcome::Continue;
+        }
+        match key {
+            Key::Tab => return Outcome::Complete,
+            Key::Ctrl('n') | Key::Ctrl('p') if self.completion.is_some() => return Outcome::Cycle(key == Key::Ctrl('n')),
+            _ => self.completion = None,
+        }
+        match key {
+            Key::Up | Key::Ctrl('p') => {
+                self.browse(history, true);
+                return Outcome::Continue;
+            },
+            Key::Down | Key::Ctrl('n') => {
+                self.browse(history, false);
+                return Outcome::Continue;
+            },
+            _ => self.browsing = None,
+        }
+        match key {
+            Key::Enter | Key::Char('\n') | Key::Ctrl('j') | Key::Ctrl('m') => return Outcome::Accept,
+            Key::Esc | Key::Ctrl('c') => return Outcome::Cancel,
+            Key::Backspace | Key::Ctrl('h') if self.text.is_empty() => return Outcome::Cancel,
+            Key::Backspace | Key::Ctrl('h') => {
+                if let Some(c) = self.text[.. self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.text.remove(self.cursor);
+                }
+            },
+            Key::Delete if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            },
+            Key::Left => {
+                if let Some(c) = self.text[.. self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            },
+            Key::Right => {
+                if let Some(c) = self.text[self.cursor ..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            },
+            Key::Home | Key::Ctrl('b') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.text.len(),
+            Key::Ctrl('w') => {
+                let start = edit::word_start(&self.text[.. self.cursor]);
+                self.text.replace_range(start .. self.cursor, "");
+                self.cursor = start;
+            },
+            Key::Ctrl('u') => {
+                self.text.replace_range(.. self.cursor, "");
+                self.cursor = 0;
+            },
+            Key::Ctrl('r') => self.register = true,
+            Key::Ctrl('v') | Key::Ctrl('q') => self.literal = true,
+            Key::Ctrl('f') => return Outcome::Window,
+            Key::Char(c) => {
+                self.text.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            },
+            _ => {},
+        }
+        Outcome::Continue
+    }
+
+    /// Show the next older or newer history entry that starts with what
+    /// was typed before browsing began.  Going past the newest entry
+    /// brings back the typed text.
+    fn browse(&mut self, history: &History, older: bool) {
+        let entries = history.entries();
+        let (prefix, index) = self.browsing.take().unwrap_or_else(|| { (self.text.clone(), entries.len()) });
+        let found = if older {
+            entries[.. index].iter().rposition(|entry| { entry.starts_with(&prefix) })
+        } else {
+            entries.iter().enumerate().skip(index + 1)
+                .find(|&(_, entry)| { entry.starts_with(&prefix) })
+                .map(|(i, _)| { i })
+        };
+        let index = match found {
+            Some(i) => {
+                self.text = entries[i].clone();
+                i
+            },
+            None if older => index,
+            None => {
+                self.text = prefix.clone();
+                entries.len()
+            },
+        };
+        self.cursor = self.text.len();
+        self.browsing = Some((prefix, index));
+    }
+}
+
+/// Lines entered at one kind of prompt, oldest first.
+#[derive(Debug,Default,PartialEq)]
+pub struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History { entries: vec![] }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Remember `entry` as the newest, dropping an older copy of it and
+    /// the oldest entries beyond `max`.
+    pub fn add(&mut self, entry: &str, max: usize) {
+        if entry.is_empty() {
+            return;
+        }
+        self.entries.retain(|old| { old != entry });
+        self.entries.push(entry.to_string());
+        let excess = self.entries.len().saturating_sub(max);
+        self.entries.drain(.. excess);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.entries.len() {
+            self.entries.remove(index);
+        }
+    }
+}
+
+/// Read the command and search histories saved by `save`, keeping the
+/// newest `max` entries of each.
+pub fn load(path: &Path, max: usize) -> io::Result<(History, History)> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let (mut commands, mut searches) = (History::new(), History::new());
+    for line in text.lines() {
+        if let Some(command) = line.strip_prefix(':') {
+            commands.add(command, max);
+        } else if let Some(pattern) = line.strip_prefix('/') {
+            searches.add(pattern, max);
+        }
+    }
+    Ok((commands, searches))
+}
+
+/// Save the histories, one entry per line marked with its prompt.  Like
+/// the swap file the histories may hold secrets, so they are written to a
+/// new file only the user can read, which then replaces the old one.
+pub fn save(path: &Path, commands: &History, searches: &History) -> io::Result<()> {
+    let mut text = String::new();
+    for (prompt, history) in [(':', commands), ('/', searches)].iter() {
+        for entry in history.entries.iter() {
+            text.push(*prompt);
+            text.push_str(entry);
+            text.push('\n');
+        }
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".new");
+    let temp = path.with_file_name(name);
+    let result = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)
+        .and_then(|mut fh| { fh.write_all(text.as_bytes()) })
+        .and_then(|_| { fs::rename(&temp, path) });
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn type_keys(editor: &mut LineEditor, keys: &[Key], history: &History) -> Outcome {
+        let mut outcome = Outcome::Continue;
+        for key in keys {
+            outcome = editor.key(*key, history);
+        }
+        outcome
+    }
+
+    #[test]
+    fn editing() {
+        let history = History::new();
+        let mut editor = LineEditor::new("");
+        type_keys(&mut editor, &[Key::Char('w'), Key::Char('q'), Key::Left, Key::Char('x'), Key::Home, Key::Char('%')], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("%wxq", 1));
+        type_keys(&mut editor, &[Key::Delete, Key::End, Key::Backspace], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("%x", 2));
+        let mut editor = LineEditor::new("s/foo.bar  ");
+        type_keys(&mut editor, &[Key::Ctrl('w')], &history);
+        assert_eq!(editor.text, "s/foo.");
+        type_keys(&mut editor, &[Key::Ctrl('w')], &history);
+        assert_eq!(editor.text, "s/foo");
+        type_keys(&mut editor, &[Key::Left, Key::Left, Key::Ctrl('u')], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("oo", 0));
+        assert_eq!(editor.key(Key::Ctrl('r'), &history), Outcome::Continue);
+        assert_eq!(editor.key(Key::Char('"'), &history), Outcome::Register('"'));
+        assert_eq!(editor.key(Key::Enter, &history), Outcome::Accept);
+        assert_eq!(LineEditor::new("").key(Key::Backspace, &history), Outcome::Cancel);
+        let mut editor = LineEditor::new("e ma | q");
+        type_keys(&mut editor, &[Key::Left, Key::Left, Key::Left, Key::Left], &history);
+        editor.replace_word(2, "main.rs");
+        assert_eq!((editor.text.as_str(), editor.cursor), ("e main.rs | q", 9));
+        let mut editor = LineEditor::new("");
+        type_keys(&mut editor, &[Key::Ctrl('v'), Key::Tab, Key::Ctrl('v'), Key::Ctrl('a'), Key::Ctrl('v')], &history);
+        assert!(editor.quoting());
+        assert_eq!(editor.key(Key::Esc, &history), Outcome::Continue);
+        assert_eq!(editor.text, "\t\x01\x1b");
+        let mut editor = LineEditor::new("né");
+        type_keys(&mut editor, &[Key::Left, Key::Char('a')], &history);
+        assert_eq!((editor.text.as_str(), editor.column()), ("naé", 2));
+    }
+
+    #[test]
+    fn history_browsing() {
+        let mut history = History::new();
+        for entry in ["set ts=4", "w", "set sw=4", "w", "e x"].iter() {
+            history.add(entry, 4);
+        }
+        assert_eq!(history.entries(), &["set ts=4", "set sw=4", "w", "e x"]);
+        history.add("q", 3);
+        assert_eq!(history.entries(), &["w", "e x", "q"]);
+
+        let mut history = History::new();
+        for entry in ["set ts=4", "w", "set sw=4", "e x"].iter() {
+            history.add(entry, 50);
+        }
+        let mut editor = LineEditor::new("se");
+        editor.key(Key::Up, &history);
+        assert_eq!(editor.text, "set sw=4");
+        editor.key(Key::Up, &history);
+        assert_eq!(editor.text, "set ts=4");
+        editor.key(Key::Up, &history);
+        assert_eq!(editor.text, "set ts=4");
+        editor.key(Key::Down, &history);
+        assert_eq!(editor.text, "set sw=4");
+        editor.key(Key::Down, &history);
+        assert_eq!(editor.text, "se");
+        editor.key(Key::Up, &history);
+        editor.key(Key::Char('!'), &history);
+        editor.key(Key::Up, &history);
+        assert_eq!(editor.text, "set sw=4!");
+        let mut editor = LineEditor::new("set t");
+        editor.key(Key::Ctrl('p'), &history);
+        assert_eq!(editor.text, "set ts=4");
+    }
+
+    #[test]
+    fn completion_keys() {
+        let history = History::new();
+        let mut editor = LineEditor::new("se");
+        assert_eq!(editor.key(Key::Ctrl('n'), &history), Outcome::Continue);
+        assert_eq!(editor.key(Key::Tab, &history), Outcome::Complete);
+        editor.completion = Some(Completion::new(0, "se", vec!["set".to_string(), "setlocal".to_string()]));
+        assert_eq!(editor.key(Key::Ctrl('p'), &history), Outcome::Cycle(false));
+        assert_eq!(editor.key(Key::Tab, &history), Outcome::Complete);
+        assert!(editor.completion.is_some());
+        editor.key(Key::Char(' '), &history);
+        assert_eq!(editor.completion, None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (mut commands, mut searches) = (History::new(), History::new());
+        commands.add("w", 50);
+        commands.add("set ts=4", 50);
+        searches.add("fn main", 50);
+        let path = Path::new("/tmp/virs-history");
+        fs::remove_file(path).ok();
+        save(path, &commands, &searches).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(load(path, 50).unwrap(), (commands, searches));
+
+        let (commands, searches) = load(path, 1).unwrap();
+        assert_eq!(commands.entries(), ["set ts=4"]);
+        assert_eq!(searches.entries(), ["fn main"]);
+        assert!(!Path::new("/tmp/virs-history.new").exists());
+    }
+}
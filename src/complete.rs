@@ -0,0 +1,282 @@
+use std::fs;
+use std::path::Path;
+
+use abbrev::is_keyword;
+use config;
+
+/// One part of `wildmode`: what a press of Tab does.
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub struct WildStage {
+    /// Complete as far as all matches agree.
+    pub longest: bool,
+    /// Complete the next full match.
+    pub full: bool,
+    /// List all matches.
+    pub list: bool,
+}
+
+/// Parse `wildmode`, whose comma-separated parts say what the first,
+/// second and later presses of Tab do, e.g. `longest:full,full`.
+pub fn parse_wildmode(value: &str) -> Vec<WildStage> {
+    let mut stages: Vec<WildStage> = value.split(',').filter(|part| { !part.is_empty() }).map(|part| {
+        let mut stage = WildStage::default();
+        for flag in part.split(':') {
+            match flag {
+                "longest" => stage.longest = true,
+                "full" => stage.full = true,
+                "list" => stage.list = true,
+                _ => {},
+            }
+        }
+        stage
+    }).collect();
+    if stages.is_empty() {
+        stages.push(WildStage { full: true, ..WildStage::default() });
+    }
+    stages
+}
+
+/// How the matches are shown after a press of Tab.
+#[derive(Debug,PartialEq)]
+pub enum Menu {
+    Hidden,
+    /// A single line above the command line, with the selected match
+    /// highlighted.
+    Wild(Option<usize>),
+    /// All matches in columns.
+    List(Option<usize>),
+}
+
+/// Completion of the word before the cursor on the command line, kept
+/// while Tab is pressed repeatedly, or of a keyword in Insert mode.
+#[derive(Debug,PartialEq)]
+pub struct Completion {
+    /// Where the word starts on the command line or in the line.
+    pub start: usize,
+    pub matches: Vec<String>,
+    /// The match shown, if any.
+    pub selected: Option<usize>,
+    /// The word as typed.
+    original: String,
+    /// The word as completed so far, before any match was selected.
+    current: String,
+    presses: usize,
+}
+
+impl Completion {
+    pub fn new(start: usize, word: &str, matches: Vec<String>) -> Self {
+        Completion {
+            start,
+            matches,
+            selected: None,
+            original: word.to_string(),
+            current: word.to_string(),
+            presses: 0,
+        }
+    }
+
+    /// Handle a press of Tab, doing what `stages` says for the number of
+    /// presses so far.  Returns the new word and how to show the matches.
+    pub fn tab(&mut self, stages: &[WildStage], wildmenu: bool) -> (String, Menu) {
+        let stage = stages[self.presses.min(stages.len() - 1)];
+        self.presses += 1;
+        if self.matches.len() == 1 {
+            self.selected = Some(0);
+            return (self.matches[0].clone(), Menu::Hidden);
+        }
+        if self.matches.is_empty() {
+            return (self.original.clone(), Menu::Hidden);
+        }
+        if stage.longest {
+            let longest = longest_common_prefix(&self.matches);
+            if longest.len() > self.current.len() {
+                self.current = longest;
+            }
+        } else if stage.full {
+            self.cycle(true);
+        }
+        let menu = if stage.list {
+            Menu::List(self.selected)
+        } else if wildmenu && stage.full {
+            Menu::Wild(self.selected)
+        } else {
+            Menu::Hidden
+        };
+        (self.word(), menu)
+    }
+
+    /// `Ctrl-N` and `Ctrl-P`: select the next or previous match, going
+    /// back to what was typed after the last one.
+    pub fn cycle(&mut self, forward: bool) -> (String, Menu) {
+        let count = self.matches.len();
+        self.selected = match (self.selected, forward) {
+            _ if count == 0 => None,
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            _ => None,
+        };
+        (self.word(), Menu::Wild(self.selected))
+    }
+
+    /// The word as typed, before completion.
+    pub fn typed(&self) -> &str {
+        &self.original
+    }
+
+    fn word(&self) -> String {
+        match self.selected {
+            Some(i) => self.matches[i].clone(),
+            None => self.current.clone(),
+        }
+    }
+}
+
+/// The longest string all of `words` start with.
+pub fn longest_common_prefix(words: &[String]) -> String {
+    let mut prefix = match words.first() {
+        Some(first) => first.as_str(),
+        None => return String::new(),
+    };
+    for word in words[1 ..].iter() {
+        let common = prefix.char_indices().zip(word.chars())
+            .find(|&((_, a), b)| { a != b })
+            .map(|((i, _), _)| { i })
+            .unwrap_or_else(|| { prefix.len().min(word.len()) });
+        prefix = &prefix[.. common];
+    }
+    prefix.to_string()
+}
+
+/// The `words` that start with `prefix`, sorted.
+pub fn matching<'a, I: Iterator<Item = &'a str>>(words: I, prefix: &str) -> Vec<String> {
+    let mut matches: Vec<String> = words.filter(|word| { word.starts_with(prefix) }).map(String::from).collect();
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+/// The words of `lines` starting with `prefix` and longer than it, for
+/// `Ctrl-N` in Insert mode: those after the word at `line` and byte `col`
+/// come first, then from the start of `lines` up to it, each only once.
+pub fn keywords(lines: &[String], line: usize, col: usize, prefix: &str) -> Vec<String> {
+    let mut words: Vec<(usize, usize, &str)> = vec![];
+    for (number, text) in lines.iter().enumerate() {
+        let mut start = None;
+        for (i, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+            match (start, is_keyword(c)) {
+                (None, true) => start = Some(i),
+                (Some(from), false) => {
+                    words.push((number, from, &text[from .. i]));
+                    start = None;
+                },
+                _ => {},
+            }
+        }
+    }
+    let split = words.iter().position(|&(number, from, _)| { number > line || (number == line && from > col) })
+        .unwrap_or(words.len());
+    let mut found: Vec<String> = vec![];
+    for &(number, from, word) in words[split ..].iter().chain(words[.. split].iter()) {
+        let own = number == line && from == col;
+        if !own && word.len() > prefix.len() && word.starts_with(prefix) && !found.iter().any(|w| { w == word }) {
+            found.push(word.to_string());
+        }
+    }
+    found
+}
+
+/// The paths that complete `word`, with a `/` after directories.  Hidden
+/// files are only offered when the name typed starts with a dot.
+pub fn files(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let path = if dir.is_empty() { Path::new(".").to_path_buf() } else { config::expand_home(dir) };
+    let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut matches: Vec<String> = entries.filter_map(|entry| { entry.ok() }).filter_map(|entry| {
+        let name = entry.file_name().into_string().ok()?;
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            return None;
+        }
+        let slash = if entry.path().is_dir() { "/" } else { "" };
+        Some(format!("{}{}{}", dir, name, slash))
+    }).collect();
+    matches.sort();
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| { word.to_string() }).collect()
+    }
+
+    #[test]
+    fn wildmode() {
+        let full = WildStage { full: true, ..WildStage::default() };
+        assert_eq!(parse_wildmode("full"), vec![full]);
+        assert_eq!(parse_wildmode(""), vec![full]);
+        assert_eq!(
+            parse_wildmode("longest:full,list"),
+            vec![WildStage { longest: true, full: true, list: false }, WildStage { list: true, ..WildStage::default() }]
+        );
+    }
+
+    #[test]
+    fn common_prefix() {
+        assert_eq!(longest_common_prefix(&strings(&["setlocal", "set", "setl"])), "set");
+        assert_eq!(longest_common_prefix(&strings(&["éa", "éb"])), "é");
+        assert_eq!(longest_common_prefix(&[]), "");
+        assert_eq!(matching(["wrap", "write", "wrapscan", "tabstop"].iter().cloned(), "wr"), strings(&["wrap", "wrapscan", "write"]));
+    }
+
+    #[test]
+    fn full_cycles_through_matches() {
+        let stages = parse_wildmode("full");
+        let mut completion = Completion::new(0, "w", strings(&["wnext", "write"]));
+        assert_eq!(completion.tab(&stages, true), ("wnext".to_string(), Menu::Wild(Some(0))));
+        assert_eq!(completion.tab(&stages, false), ("write".to_string(), Menu::Hidden));
+        assert_eq!(completion.tab(&stages, true), ("w".to_string(), Menu::Wild(None)));
+        assert_eq!(completion.cycle(false), ("write".to_string(), Menu::Wild(Some(1))));
+    }
+
+    #[test]
+    fn longest_then_list() {
+        let stages = parse_wildmode("longest,list:full");
+        let mut completion = Completion::new(4, "s", strings(&["shiftwidth", "shell", "shellcmdflag"]));
+        assert_eq!(completion.tab(&stages, true), ("sh".to_string(), Menu::Hidden));
+        assert_eq!(completion.tab(&stages, true), ("shiftwidth".to_string(), Menu::List(Some(0))));
+        let mut single = Completion::new(0, "chec", strings(&["checktime"]));
+        assert_eq!(single.tab(&stages, true), ("checktime".to_string(), Menu::Hidden));
+        let mut none = Completion::new(0, "zz", vec![]);
+        assert_eq!(none.tab(&stages, true), ("zz".to_string(), Menu::Hidden));
+    }
+
+    #[test]
+    fn buffer_keywords() {
+        let lines = strings(&["let total = totals(x);", "to", "print(total_count, tot)"]);
+        assert_eq!(keywords(&lines, 2, 18, "tot"), strings(&["total", "totals", "total_count"]));
+        assert_eq!(keywords(&lines, 0, 4, "tot"), strings(&["totals", "total_count"]));
+        assert_eq!(keywords(&lines, 1, 0, "z"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn file_names() {
+        fs::create_dir_all("/tmp/virs-complete/src").unwrap();
+        File::create("/tmp/virs-complete/main.rs").unwrap();
+        File::create("/tmp/virs-complete/.hidden").unwrap();
+        assert_eq!(files("/tmp/virs-complete/"), strings(&["/tmp/virs-complete/main.rs", "/tmp/virs-complete/src/"]));
+        assert_eq!(files("/tmp/virs-complete/m"), strings(&["/tmp/virs-complete/main.rs"]));
+        assert_eq!(files("/tmp/virs-complete/."), strings(&["/tmp/virs-complete/.hidden"]));
+        assert_eq!(files("/tmp/virs-no-such-dir/x"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::path::Path;
+use std::process;
+
+use pipe;
+
+/// A compression format handled by piping through its command line tool,
+/// the way vim's gzip plugin does.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Codec {
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+impl Codec {
+    /// Recognize compressed data by its magic bytes.  A bzip2 stream must
+    /// also give its block size and start with a block, or end at once,
+    /// so a text file starting with "BZh" is not taken for one.
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        let bzip2 = bytes.len() >= 10 && bytes.starts_with(b"BZh") && (b'1' ..= b'9').contains(&bytes[3])
+            && (&bytes[4 .. 10] == b"1AY&SY" || &bytes[4 .. 10] == b"\x17rE8P\x90");
+        if bytes.starts_with(b"\x1f\x8b") {
+            Some(Codec::Gzip)
+        } else if bzip2 {
+            Some(Codec::Bzip2)
+        } else if bytes.starts_with(b"\xfd7zXZ\x00") {
+            Some(Codec::Xz)
+        } else {
+            None
+        }
+    }
+
+    /// The codec a file name asks for, e.g. `Gzip` for `app.log.gz`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|ext| { ext.to_str() }) {
+            Some("gz") => Some(Codec::Gzip),
+            Some("bz2") => Some(Codec::Bzip2),
+            Some("xz") => Some(Codec::Xz),
+            _ => None,
+        }
+    }
+
+    pub fn program(&self) -> &'static str {
+        match *self {
+            Codec::Gzip => "gzip",
+            Codec::Bzip2 => "bzip2",
+            Codec::Xz => "xz",
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        self.run("-dc", bytes)
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        self.run("-c", bytes)
+    }
+
+    fn run(&self, flags: &str, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut command = process::Command::new(self.program());
+        command.arg(flags);
+        let output = pipe::run_piped(command, bytes)?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} failed: {}", self.program(), stderr.trim_end())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn detect() {
+        assert_eq!(Codec::from_magic(b"\x1f\x8b\x08\x00"), Some(Codec::Gzip));
+        assert_eq!(Codec::from_magic(b"BZh91AY&SY\x00"), Some(Codec::Bzip2));
+        assert_eq!(Codec::from_magic(b"BZh9\x17rE8P\x90\x00\x00\x00\x00"), Some(Codec::Bzip2));
+        assert_eq!(Codec::from_magic(b"BZh91AY"), None);
+        assert_eq!(Codec::from_magic(b"BZhello, world"), None);
+        assert_eq!(Codec::from_magic(b"\xfd7zXZ\x00\x00"), Some(Codec::Xz));
+        assert_eq!(Codec::from_magic(b"plain text"), None);
+        assert_eq!(Codec::from_path(Path::new("/var/log/syslog.2.gz")), Some(Codec::Gzip));
+        assert_eq!(Codec::from_path(Path::new("notes.txt")), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        for codec in [Codec::Gzip, Codec::Bzip2, Codec::Xz].iter() {
+            let compressed = codec.compress(b"hello\n").unwrap();
+            assert_eq!(Codec::from_magic(&compressed), Some(*codec));
+            assert_eq!(codec.decompress(&compressed).unwrap(), b"hello\n".to_vec());
+        }
+        assert!(Codec::Gzip.decompress(b"not gzip").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use libc;
+
+/// A place startup commands are read from.
+#[derive(Debug,PartialEq)]
+pub enum Source {
+    /// Commands taken from the `EXINIT` environment variable.
+    Exinit(String),
+    File(PathBuf),
+}
+
+/// The startup sources to run before editing, honoring `-u rcfile`.
+///
+/// `-u NONE` skips initialization entirely.  Otherwise `$EXINIT` is used if
+/// it is set, falling back to the first of `~/.virsrc` and
+/// `$XDG_CONFIG_HOME/virs/virsrc` that exists.
+pub fn global_sources(rcfile: Option<&str>) -> Vec<Source> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    find_global_sources(rcfile, env::var("EXINIT").ok(), home, xdg, |path| { path.is_file() })
+}
+
+fn find_global_sources<F>(rcfile: Option<&str>, exinit: Option<String>, home: Option<PathBuf>,
+                          xdg: Option<PathBuf>, exists: F) -> Vec<Source>
+    where F: Fn(&Path) -> bool {
+    match rcfile {
+        Some("NONE") => return vec![],
+        Some(rcfile) => return vec![Source::File(expand_home(rcfile))],
+        None => {},
+    }
+    if let Some(exinit) = exinit {
+        return vec![Source::Exinit(exinit)];
+    }
+    let mut candidates = vec![];
+    if let Some(ref home) = home {
+        candidates.push(home.join(".virsrc"));
+    }
+    match (xdg, home) {
+        (Some(xdg), _) => candidates.push(xdg.join("virs").join("virsrc")),
+        (None, Some(home)) => candidates.push(home.join(".config").join("virs").join("virsrc")),
+        (None, None) => {},
+    }
+    candidates.into_iter().filter(|path| { exists(path) }).take(1).map(Source::File).collect()
+}
+
+/// The project-local `.virsrc` in the working directory, if there is one
+/// and it was not already read as the user's own startup file.  Only read
+/// when the `exrc` option is set.
+pub fn local_source(globals: &[Source]) -> Option<Source> {
+    let local = PathBuf::from(".virsrc");
+    if !local.is_file() {
+        return None;
+    }
+    let canonical = local.canonicalize().ok();
+    for source in globals {
+        if let Source::File(ref path) = *source {
+            if path.canonicalize().ok() == canonical {
+                return None;
+            }
+        }
+    }
+    Some(Source::File(local))
+}
+
+/// Whether commands from `source` may be run without restriction: a file
+/// must be owned by the user and not writable by anyone else, as a
+/// `.virsrc` in a shared or downloaded directory may not be.
+pub fn trusted(source: &Source) -> bool {
+    match *source {
+        Source::Exinit(_) => true,
+        Source::File(ref path) => fs::metadata(path).map(|metadata| {
+            metadata.uid() == unsafe { libc::getuid() } && metadata.mode() & 0o022 == 0
+        }).unwrap_or(false),
+    }
+}
+
+/// Where the command and search histories are kept between sessions.
+pub fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| { PathBuf::from(home).join(".virs_history") })
+}
+
+/// Replace a leading `~/` with the user's home directory.
+pub fn expand_home(filename: &str) -> PathBuf {
+    if let Some(rest) = filename.strip_prefix("~/") {
+        if let Some(home) = env::var_os("HOME") {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+    PathBuf::from(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn home() -> Option<PathBuf> {
+        Some(PathBuf::from("/home/user"))
+    }
+
+    #[test]
+    fn trusted_sources() {
+        use std::fs::File;
+        use std::os::unix::fs::PermissionsExt;
+        let path = PathBuf::from("/tmp/virs-trusted-rc");
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(trusted(&Source::File(path.clone())));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(!trusted(&Source::File(path.clone())));
+        assert!(!trusted(&Source::File(PathBuf::from("/tmp/virs-no-such-rc"))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_initialization() {
+        assert_eq!(find_global_sources(Some("NONE"), Some("set ai".to_string()), home(), None, |_| true), vec![]);
+    }
+
+    #[test]
+    fn explicit_rcfile() {
+        assert_eq!(
+            find_global_sources(Some("/etc/virsrc"), Some("set ai".to_string()), home(), None, |_| true),
+            vec![Source::File(PathBuf::from("/etc/virsrc"))]
+        );
+    }
+
+    #[test]
+    fn exinit_wins() {
+        assert_eq!(
+            find_global_sources(None, Some("set ai".to_string()), home(), None, |_| true),
+            vec![Source::Exinit("set ai".to_string())]
+        );
+    }
+
+    #[test]
+    fn first_existing_file() {
+        assert_eq!(
+            find_global_sources(None, None, home(), None, |_| true),
+            vec![Source::File(PathBuf::from("/home/user/.virsrc"))]
+        );
+        assert_eq!(
+            find_global_sources(None, None, home(), None, |path: &Path| { path.ends_with("virsrc") }),
+            vec![Source::File(PathBuf::from("/home/user/.config/virs/virsrc"))]
+        );
+        assert_eq!(
+            find_global_sources(None, None, home(), Some(PathBuf::from("/xdg")), |path: &Path| { path.starts_with("/xdg") }),
+            vec![Source::File(PathBuf::from("/xdg/virs/virsrc"))]
+        );
+        assert_eq!(find_global_sources(None, None, home(), None, |_| false), vec![]);
+    }
+}
@@ -0,0 +1,65 @@
+/// The digraphs `Ctrl-K` enters, a subset of RFC 1345 as vim uses it:
+/// two characters and the one they stand for.
+const DIGRAPHS: &[(&str, char)] = &[
+    // Accented Latin letters: `!` grave, `'` acute, `>` circumflex, `?`
+    // tilde, `:` diaeresis, `a` ring, `,` cedilla.
+    ("A!", 'À'), ("A'", 'Á'), ("A>", 'Â'), ("A?", 'Ã'), ("A:", 'Ä'), ("AA", 'Å'),
+    ("a!", 'à'), ("a'", 'á'), ("a>", 'â'), ("a?", 'ã'), ("a:", 'ä'), ("aa", 'å'),
+    ("E!", 'È'), ("E'", 'É'), ("E>", 'Ê'), ("E:", 'Ë'),
+    ("e!", 'è'), ("e'", 'é'), ("e>", 'ê'), ("e:", 'ë'),
+    ("I!", 'Ì'), ("I'", 'Í'), ("I>", 'Î'), ("I:", 'Ï'),
+    ("i!", 'ì'), ("i'", 'í'), ("i>", 'î'), ("i:", 'ï'),
+    ("O!", 'Ò'), ("O'", 'Ó'), ("O>", 'Ô'), ("O?", 'Õ'), ("O:", 'Ö'),
+    ("o!", 'ò'), ("o'", 'ó'), ("o>", 'ô'), ("o?", 'õ'), ("o:", 'ö'),
+    ("U!", 'Ù'), ("U'", 'Ú'), ("U>", 'Û'), ("U:", 'Ü'),
+    ("u!", 'ù'), ("u'", 'ú'), ("u>", 'û'), ("u:", 'ü'),
+    ("Y'", 'Ý'), ("y'", 'ý'), ("y:", 'ÿ'),
+    ("N?", 'Ñ'), ("n?", 'ñ'), ("C,", 'Ç'), ("c,", 'ç'),
+    ("AE", 'Æ'), ("ae", 'æ'), ("O/", 'Ø'), ("o/", 'ø'), ("ss", 'ß'),
+    ("D-", 'Đ'), ("d-", 'đ'), ("TH", 'Þ'), ("th", 'þ'), ("OE", 'Œ'), ("oe", 'œ'),
+    // Greek letters.
+    ("a*", 'α'), ("b*", 'β'), ("g*", 'γ'), ("d*", 'δ'), ("e*", 'ε'), ("z*", 'ζ'),
+    ("y*", 'η'), ("h*", 'θ'), ("i*", 'ι'), ("k*", 'κ'), ("l*", 'λ'), ("m*", 'μ'),
+    ("n*", 'ν'), ("c*", 'ξ'), ("o*", 'ο'), ("p*", 'π'), ("r*", 'ρ'), ("s*", 'σ'),
+    ("t*", 'τ'), ("u*", 'υ'), ("f*", 'φ'), ("x*", 'χ'), ("q*", 'ψ'), ("w*", 'ω'),
+    ("G*", 'Γ'), ("D*", 'Δ'), ("H*", 'Θ'), ("L*", 'Λ'), ("P*", 'Π'), ("S*", 'Σ'),
+    ("F*", 'Φ'), ("Q*", 'Ψ'), ("W*", 'Ω'),
+    // Punctuation and symbols.
+    ("NS", '\u{a0}'), ("!I", '¡'), ("?I", '¿'), ("Ct", '¢'), ("Pd", '£'), ("Eu", '€'),
+    ("Ye", '¥'), ("SE", '§'), ("Co", '©'), ("Rg", '®'), ("DG", '°'), ("+-", '±'),
+    ("My", 'µ'), ("PI", '¶'), (".M", '·'), ("<<", '«'), (">>", '»'), ("NO", '¬'),
+    ("1S", '¹'), ("2S", '²'), ("3S", '³'), ("14", '¼'), ("12", '½'), ("34", '¾'),
+    ("*X", '×'), ("-:", '÷'), ("-N", '–'), ("-M", '—'), ("'6", '‘'), ("'9", '’'),
+    ("\"6", '“'), ("\"9", '”'), (",.", '…'), ("oo", '•'), ("TM", '™'),
+    ("<-", '←'), ("-!", '↑'), ("->", '→'), ("-v", '↓'), ("<=", '⇐'), ("=>", '⇒'),
+    ("FA", '∀'), ("dP", '∂'), ("TE", '∃'), ("/0", '∅'), ("(-", '∈'), ("00", '∞'),
+    ("!=", '≠'), ("=<", '≤'), (">=", '≥'), ("?2", '≈'), ("=3", '≡'), ("RT", '√'),
+    ("OK", '✓'), ("XX", '✗'),
+];
+
+/// The character the digraph `first`, `second` stands for.  Like vim, the
+/// two characters may also be typed the other way round.
+pub fn lookup(first: char, second: char) -> Option<char> {
+    let find = |a: char, b: char| {
+        DIGRAPHS.iter().find(|&&(digraph, _)| {
+            let mut chars = digraph.chars();
+            chars.next() == Some(a) && chars.next() == Some(b)
+        }).map(|&(_, c)| { c })
+    };
+    find(first, second).or_else(|| { find(second, first) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digraphs() {
+        assert_eq!(lookup('e', '\''), Some('é'));
+        assert_eq!(lookup('\'', 'e'), Some('é'));
+        assert_eq!(lookup('E', 'u'), Some('€'));
+        assert_eq!(lookup('p', '*'), Some('π'));
+        assert_eq!(lookup('-', '>'), Some('→'));
+        assert_eq!(lookup('q', 'q'), None);
+    }
+}
@@ -5,85 +5,318 @@ use super::buffer::Buffer;
 use super::engine::Engine;
 
 pub struct IO {
-    pub rustbox: RustBox,
+    /// `None` while the terminal is handed over to a child process.
+    rustbox: Option<RustBox>,
 }
 
 impl IO {
     pub fn new() -> Result<Self, InitError> {
         match RustBox::init(Default::default()) {
-            Ok(rustbox) => Ok(IO { rustbox: rustbox, }),
+            Ok(rustbox) => Ok(IO { rustbox: Some(rustbox), }),
             Err(err) => Err(err),
         }
     }
 
-    pub fn readline(&self) -> String {
-        let mut input = String::with_capacity(16);
-        loop {
-            match self.rustbox.poll_event(false) {
-                Ok(Event::KeyEvent(key)) => {
-                    match key {
-                        Key::Char('\n') | Key::Enter => break,
-                        Key::Char(c) => {
-                            input.push(c);
-                            self.rustbox.print_char(
-                                input.len() + 1,
-                                self.status_line(),
-                                rustbox::RB_BOLD,
-                                Color::White,
-                                Color::Black,
-                                c
-                            );
-                            self.rustbox.set_cursor((input.len() + 2) as isize, self.status_line() as isize);
-                            self.rustbox.present()
-
-                            //self.set_status(&format!(":{}", input));
-                        },
-                        Key::Backspace => {
-                            match input.pop() {
-                                Some(_) => {
-
-                                    self.rustbox.print_char(
-                                        input.len() + 2,
-                                        self.status_line(),
-                                        rustbox::RB_BOLD,
-                                        Color::White,
-                                        Color::Black,
-                                        ' '
-                                    );
-                                    self.rustbox.set_cursor((input.len() + 2) as isize, self.status_line() as isize);
-                                    self.rustbox.present()
-                                },
-                                None => {},
-                            }
-                        },
-                        Key::Esc => {
-                            input = "".to_string();
-                            break;
-                        },
-                        _ => break,
-                    }
-                },
-                _ => {},
-            };
+    /// An `IO` that never takes over the terminal, for running commands
+    /// when nothing will be drawn.
+    pub fn detached() -> Self {
+        IO { rustbox: None }
+    }
+
+    /// The terminal, or an error while it is handed to a child process or
+    /// if it could not be taken back.  Drawing does nothing meanwhile.
+    pub fn rustbox(&self) -> Result<&RustBox, String> {
+        self.rustbox.as_ref().ok_or_else(|| { "The screen is suspended".to_string() })
+    }
+
+    /// Restore the terminal to its normal state so a child process can use
+    /// it.  Nothing may be drawn until `resume` is called.
+    pub fn suspend(&mut self) {
+        self.rustbox = None;
+    }
+
+    /// Take over the terminal again after `suspend`.
+    pub fn resume(&mut self) -> Result<(), InitError> {
+        if self.rustbox.is_none() {
+            self.rustbox = Some(RustBox::init(Default::default())?);
+        }
+        Ok(())
+    }
+
+    /// Draw a command line being edited, with the cursor before character
+    /// `column` of `text`.  Long lines scroll to keep the cursor visible.
+    pub fn show_cmdline(&self, prompt: char, text: &str, column: usize) {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let skip = (column + 3).saturating_sub(screen.width());
+        let shown: String = text.chars().skip(skip).collect();
+        self.set_status(&format!("{}{}", prompt, shown));
+        screen.set_cursor((column - skip + 2) as isize, self.status_line() as isize);
+        screen.present();
+    }
+
+    /// Show completion matches on the row above the status line, with the
+    /// `selected` one highlighted and in view.
+    pub fn show_wildmenu(&self, matches: &[String], selected: Option<usize>) {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let width = screen.width();
+        let row = self.status_line().saturating_sub(1);
+        let shown_width = |first: usize, last: usize| -> usize {
+            matches[first ..= last].iter().map(|word| { word.chars().count() + 2 }).sum()
+        };
+        let mut first = 0;
+        if let Some(selected) = selected {
+            while first < selected && shown_width(first, selected) + 2 > width {
+                first += 1;
+            }
+        }
+        screen.print(0, row, rustbox::RB_NORMAL, Color::Black, Color::White, &" ".repeat(width));
+        if first > 0 {
+            screen.print(0, row, rustbox::RB_NORMAL, Color::Black, Color::White, "<");
         }
-        input
+        let mut x = 1;
+        for (i, word) in matches.iter().enumerate().skip(first) {
+            let len = word.chars().count();
+            if x + len + 2 > width {
+                screen.print(width - 1, row, rustbox::RB_NORMAL, Color::Black, Color::White, ">");
+                break;
+            }
+            let (fg, bg) = if Some(i) == selected { (Color::Black, Color::Yellow) } else { (Color::Black, Color::White) };
+            screen.print(x, row, rustbox::RB_BOLD, fg, bg, word);
+            x += len + 2;
+        }
+        screen.present();
+    }
+
+    /// List completion matches in columns above the status line, going
+    /// down each column first, with the `selected` one highlighted.
+    pub fn show_completion_list(&self, matches: &[String], selected: Option<usize>) {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let width = screen.width();
+        let column_width = matches.iter().map(|word| { word.chars().count() + 2 }).max().unwrap_or(1);
+        let columns = (width / column_width).max(1);
+        let rows = matches.len().div_ceil(columns);
+        let bottom = self.status_line();
+        let top = bottom.saturating_sub(rows);
+        let blank = " ".repeat(width);
+        for row in top .. bottom {
+            screen.print(0, row, rustbox::RB_NORMAL, Color::White, Color::Black, &blank);
+        }
+        for (i, word) in matches.iter().enumerate() {
+            let (column, row) = (i / rows, i % rows);
+            if top + row >= bottom {
+                continue;
+            }
+            let style = if Some(i) == selected { rustbox::RB_REVERSE } else { rustbox::RB_NORMAL };
+            screen.print(column * column_width, top + row, style, Color::White, Color::Black, word);
+        }
+        screen.present();
+    }
+
+    /// Show Insert mode completion matches in a popup menu below the
+    /// screen position `row`, `col`, or above it if there is more room
+    /// there, with the `selected` one highlighted and in view.
+    pub fn show_popup_menu(&self, matches: &[String], selected: Option<usize>, row: usize, col: usize) {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let below = self.text_height().saturating_sub(row);
+        let above = row.saturating_sub(1);
+        let height = matches.len().min(below.max(above));
+        if height == 0 {
+            return;
+        }
+        let top = if matches.len() <= below || below >= above { row + 1 } else { row - height };
+        let first = match selected {
+            Some(selected) if selected >= height => selected + 1 - height,
+            _ => 0,
+        };
+        let item_width = matches.iter().map(|word| { word.chars().count() }).max().unwrap_or(0) + 2;
+        let width = item_width.min(screen.width());
+        let x = col.min(screen.width() - width);
+        for (i, word) in matches.iter().enumerate().skip(first).take(height) {
+            let (fg, bg) = if Some(i) == selected { (Color::Black, Color::Yellow) } else { (Color::Black, Color::White) };
+            let item: String = format!(" {:<w$}", word, w = item_width - 1).chars().take(width).collect();
+            screen.print(x, top + i - first, rustbox::RB_NORMAL, fg, bg, &item);
+        }
+        screen.present();
     }
+
     pub fn show_buffer(&self, buffer: &Buffer, engine: &Engine) {
-        for i in 0 .. self.rustbox.height() - 3 {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let gutter = Gutter::new(engine, buffer.content.len());
+        let text_x = 1 + gutter.width;
+        let blank = " ".repeat(screen.width());
+        for i in 0 .. self.text_height() {
             let line = buffer.top_visible + i;
-            self.rustbox.print(1, i+1, rustbox::RB_BOLD, Color::White, Color::Black, &buffer.content[line]);
+            screen.print(0, i+1, rustbox::RB_NORMAL, Color::White, Color::Black, &blank);
+            if line >= buffer.content.len() {
+                screen.print(1, i+1, rustbox::RB_BOLD, Color::Blue, Color::Black, "~");
+                continue;
+            }
+            if gutter.width > 0 {
+                let label = gutter.label(line as u64 + 1, engine.cursor.line);
+                screen.print(1, i+1, rustbox::RB_NORMAL, Color::Yellow, Color::Black, &label);
+            }
+            screen.print(text_x, i+1, rustbox::RB_BOLD, Color::White, Color::Black, &buffer.content[line]);
         }
-        self.rustbox.set_cursor(engine.cursor.col as isize, engine.cursor.line as isize);
-        self.rustbox.present();
+        // The row between the text and the status line, used by menus.
+        screen.print(0, self.text_height() + 1, rustbox::RB_NORMAL, Color::White, Color::Black, &blank);
+        let row = (engine.cursor.line as usize).saturating_sub(buffer.top_visible);
+        let col = text_x + engine.cursor.col as usize - 1;
+        screen.set_cursor(col as isize, row as isize);
+        screen.present();
     }
 
     pub fn set_status(&self, status: &str) {
-        self.rustbox.clear();
-        self.rustbox.print(1, self.status_line(), rustbox::RB_BOLD, Color::White, Color::Black, status);
-        self.rustbox.present();
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let blank = " ".repeat(screen.width());
+        screen.print(0, self.status_line(), rustbox::RB_NORMAL, Color::White, Color::Black, &blank);
+        screen.print(1, self.status_line(), rustbox::RB_BOLD, Color::White, Color::Black, status);
+        screen.present();
+    }
+
+    /// Show `lines` above the status line, a screenful at a time, and wait
+    /// for a key after each.  `q` or Escape skips the rest.
+    pub fn show_messages(&self, lines: &[String]) {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return };
+        let rows = self.status_line().max(1);
+        let blank = " ".repeat(screen.width());
+        let pages = lines.chunks(rows).count();
+        for (i, page) in lines.chunks(rows).enumerate() {
+            let top = rows - page.len();
+            for (row, line) in page.iter().enumerate() {
+                screen.print(0, top + row, rustbox::RB_NORMAL, Color::White, Color::Black, &blank);
+                screen.print(0, top + row, rustbox::RB_NORMAL, Color::White, Color::Black, line);
+            }
+            let last = i + 1 == pages;
+            self.set_status(if last { "Press ENTER or type command to continue" } else { "-- More --" });
+            match screen.poll_event(false) {
+                Ok(Event::KeyEvent(Key::Char('q'))) | Ok(Event::KeyEvent(Key::Esc)) | Err(_) => return,
+                _ => {},
+            }
+        }
+    }
+
+    /// Show `message` on the status line and wait for one of the keys in
+    /// `choices`; Enter picks the first.  Returns `None` if input fails.
+    pub fn prompt(&self, message: &str, choices: &str) -> Option<char> {
+        let screen = match self.rustbox { Some(ref screen) => screen, None => return None };
+        self.set_status(message);
+        loop {
+            match screen.poll_event(false) {
+                Ok(Event::KeyEvent(Key::Char(c))) => {
+                    let c = c.to_ascii_lowercase();
+                    if choices.contains(c) {
+                        return Some(c);
+                    }
+                },
+                Ok(Event::KeyEvent(Key::Enter)) => return choices.chars().next(),
+                Ok(_) => {},
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// The number of screen rows available for buffer text.
+    pub fn text_height(&self) -> usize {
+        self.height().saturating_sub(3)
     }
 
     pub fn status_line(&self) -> usize {
-        self.rustbox.height() - 1
+        self.height().saturating_sub(1)
+    }
+
+    pub fn width(&self) -> usize {
+        self.rustbox.as_ref().map_or(0, |rustbox| { rustbox.width() })
+    }
+
+    fn height(&self) -> usize {
+        self.rustbox.as_ref().map_or(0, |rustbox| { rustbox.height() })
+    }
+}
+
+/// The line number column drawn to the left of the buffer text.
+pub struct Gutter {
+    pub width: usize,
+    pub number: bool,
+    pub relative: bool,
+}
+
+impl Gutter {
+    pub fn new(engine: &Engine, line_count: usize) -> Self {
+        let number = engine.option_bool("number");
+        let relative = engine.option_bool("relativenumber");
+        Gutter::with_options(number, relative, engine.option_number("numberwidth").max(1) as usize, line_count)
+    }
+
+    /// Like vim, the gutter is at least `numberwidth` wide and always has
+    /// room for the largest line number plus a separating space.
+    pub fn with_options(number: bool, relative: bool, numberwidth: usize, line_count: usize) -> Self {
+        let width = if number || relative {
+            let digits = line_count.to_string().len();
+            if numberwidth > digits + 1 { numberwidth } else { digits + 1 }
+        } else {
+            0
+        };
+        Gutter { width, number, relative }
+    }
+
+    /// The label for `line`.  Relative numbering counts distance from the
+    /// cursor; with both options set the cursor line shows its absolute
+    /// number, left aligned.
+    pub fn label(&self, line: u64, cursor_line: u64) -> String {
+        let distance = line.abs_diff(cursor_line);
+        let digits = self.width - 1;
+        if !self.relative {
+            format!("{:>1$} ", line, digits)
+        } else if distance == 0 && self.number {
+            format!("{:<1$} ", line, digits)
+        } else {
+            format!("{:>1$} ", distance, digits)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspended_screen() {
+        let mut io = IO::new().unwrap();
+        io.suspend();
+        assert!(io.rustbox().is_err());
+        io.set_status("not shown");
+        io.show_messages(&["not shown".to_string()]);
+        assert_eq!(io.prompt("Continue?", "yn"), None);
+        assert_eq!(io.text_height(), 0);
+    }
+
+    #[test]
+    fn gutter_width() {
+        assert_eq!(Gutter::with_options(false, false, 4, 100).width, 0);
+        assert_eq!(Gutter::with_options(true, false, 4, 100).width, 4);
+        assert_eq!(Gutter::with_options(false, true, 4, 12345).width, 6);
+        assert_eq!(Gutter::with_options(true, false, 1, 9).width, 2);
+    }
+
+    #[test]
+    fn absolute_labels() {
+        let gutter = Gutter::with_options(true, false, 4, 100);
+        assert_eq!(gutter.label(7, 3), "  7 ");
+        assert_eq!(gutter.label(3, 3), "  3 ");
+    }
+
+    #[test]
+    fn relative_labels() {
+        let gutter = Gutter::with_options(false, true, 4, 100);
+        assert_eq!(gutter.label(7, 3), "  4 ");
+        assert_eq!(gutter.label(1, 3), "  2 ");
+        assert_eq!(gutter.label(3, 3), "  0 ");
+    }
+
+    #[test]
+    fn hybrid_labels() {
+        let gutter = Gutter::with_options(true, true, 4, 100);
+        assert_eq!(gutter.label(7, 3), "  4 ");
+        assert_eq!(gutter.label(3, 3), "3   ");
     }
 }
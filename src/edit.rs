@@ -0,0 +1,247 @@
+use rustbox::Key;
+
+use abbrev::is_keyword;
+
+/// The byte offset of character `col`, counted from 0, in `text`, or the
+/// length of `text` past its end.
+pub fn byte_offset(text: &str, col: usize) -> usize {
+    text.char_indices().nth(col).map(|(i, _)| { i }).unwrap_or(text.len())
+}
+
+/// The number of characters in `text`.
+pub fn char_count(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// The index of the first character of `text` that is not white space.
+pub fn first_non_blank(text: &str) -> usize {
+    text.chars().take_while(|c| { c.is_whitespace() }).count()
+}
+
+/// The character `Ctrl-V` inserts for `key`, e.g. a tab for Tab.
+pub fn literal_char(key: Key) -> Option<char> {
+    match key {
+        Key::Char(c) => Some(c),
+        Key::Tab => Some('\t'),
+        Key::Enter => Some('\r'),
+        Key::Esc => Some('\x1b'),
+        Key::Backspace => Some('\x7f'),
+        Key::Ctrl(c) if c.is_ascii() => Some(((c as u8) & 0x1f) as char),
+        _ => None,
+    }
+}
+
+/// After `Ctrl-V`, the radix and the most digits of the character code
+/// `first` starts: decimal digits (`065`), `o` octal (`o101`), `x` two
+/// hex digits (`x41`), `u` four (`u2014`) or `U` eight (`U0001f600`).
+pub fn code_radix(first: char) -> Option<(u32, usize)> {
+    match first {
+        '0' ..= '9' => Some((10, 3)),
+        'o' | 'O' => Some((8, 3)),
+        'x' | 'X' => Some((16, 2)),
+        'u' => Some((16, 4)),
+        'U' => Some((16, 8)),
+        _ => None,
+    }
+}
+
+/// The character with the code `digits` in `radix`, if there is one.
+/// Decimal and octal codes go up to 255.
+pub fn code_char(digits: &str, radix: u32) -> Option<char> {
+    let code = u32::from_str_radix(digits, radix).ok()?;
+    if radix != 16 && code > 255 {
+        return None;
+    }
+    ::std::char::from_u32(code)
+}
+
+/// Where the word before the end of `text` starts, for `Ctrl-W`: white
+/// space is deleted along with the word, which is a run of keyword
+/// characters or of other characters.
+pub fn word_start(text: &str) -> usize {
+    let trimmed = text.trim_end();
+    let keyword = match trimmed.chars().next_back() {
+        Some(c) => is_keyword(c),
+        None => return 0,
+    };
+    trimmed.char_indices().rev()
+        .take_while(|&(_, c)| { !c.is_whitespace() && is_keyword(c) == keyword })
+        .last()
+        .map(|(i, _)| { i })
+        .unwrap_or(trimmed.len())
+}
+
+/// What a key typed in Replace mode did, for Backspace to undo.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Replaced {
+    /// Overwrote this character.
+    Char(char),
+    /// Added a character past the end of the line.
+    Appended,
+    /// Broke the line in two.
+    LineBreak,
+}
+
+/// Switch the case of `count` characters of `text` from character `col`.
+/// Returns the new text and the character after the last one switched.
+pub fn toggle_case(text: &str, col: usize, count: usize) -> (String, usize) {
+    let mut toggled = String::with_capacity(text.len());
+    let mut end = col;
+    for (i, c) in text.chars().enumerate() {
+        if i < col || i >= col.saturating_add(count) {
+            toggled.push(c);
+        } else if c.is_lowercase() {
+            toggled.extend(c.to_uppercase());
+            end = i + 1;
+        } else {
+            toggled.extend(c.to_lowercase());
+            end = i + 1;
+        }
+    }
+    (toggled, end)
+}
+
+/// The bases `Ctrl-A` and `Ctrl-X` recognize.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Base {
+    Decimal,
+    /// `0x1f`
+    Hex,
+    /// `017`
+    Octal,
+    /// `0b101`
+    Binary,
+}
+
+/// A number found in a line: where it starts, with its sign or prefix,
+/// where its digits start and where it ends, as character indices.
+#[derive(Debug,PartialEq)]
+struct Number {
+    start: usize,
+    digits: usize,
+    end: usize,
+    base: Base,
+}
+
+/// Find the numbers in `chars`, left to right.
+fn numbers(chars: &[char]) -> Vec<Number> {
+    let mut found = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_ascii_digit() {
+            i += 1;
+            continue;
+        }
+        let run = |from: usize, digit: &dyn Fn(char) -> bool| {
+            (from .. chars.len()).find(|&j| { !digit(chars[j]) }).unwrap_or(chars.len())
+        };
+        let prefixed = |letter: char, digit: &dyn Fn(char) -> bool| {
+            chars[i] == '0' && chars.get(i + 1).map(|c| { c.to_ascii_lowercase() }) == Some(letter)
+                && chars.get(i + 2).is_some_and(|&c| { digit(c) })
+        };
+        let is_hex = |c: char| { c.is_ascii_hexdigit() };
+        let is_binary = |c: char| { c == '0' || c == '1' };
+        let number = if prefixed('x', &is_hex) {
+            Number { start: i, digits: i + 2, end: run(i + 2, &is_hex), base: Base::Hex }
+        } else if prefixed('b', &is_binary) {
+            Number { start: i, digits: i + 2, end: run(i + 2, &is_binary), base: Base::Binary }
+        } else {
+            let end = run(i, &|c: char| { c.is_ascii_digit() });
+            let octal = chars[i] == '0' && end - i > 1 && chars[i .. end].iter().all(|&c| { c < '8' });
+            let negative = !octal && i > 0 && chars[i - 1] == '-';
+            Number {
+                start: if negative { i - 1 } else { i },
+                digits: i,
+                end,
+                base: if octal { Base::Octal } else { Base::Decimal },
+            }
+        };
+        i = number.end;
+        found.push(number);
+    }
+    found
+}
+
+/// `Ctrl-A` and `Ctrl-X`: add `delta` to the first number under or after
+/// character `col` of `text`.  Hex, octal and binary numbers keep their
+/// width and the case of their letters.  Returns the new text and the
+/// last character of the number, or `None` if there is no number.
+pub fn increment(text: &str, col: usize, delta: i64) -> Option<(String, usize)> {
+    let chars: Vec<char> = text.chars().collect();
+    let number = numbers(&chars).into_iter().find(|number| { number.end > col })?;
+    let digits: String = chars[number.digits .. number.end].iter().collect();
+    let width = digits.len();
+    let replaced = match number.base {
+        Base::Decimal => {
+            let sign: String = chars[number.start .. number.digits].iter().collect();
+            let value: i128 = format!("{}{}", sign, digits).parse().ok()?;
+            (value + delta as i128).to_string()
+        },
+        base => {
+            let (radix, prefix) = match base {
+                Base::Hex => (16, &chars[number.start .. number.digits]),
+                Base::Binary => (2, &chars[number.start .. number.digits]),
+                _ => (8, &chars[0 .. 0]),
+            };
+            let value = u64::from_str_radix(&digits, radix).ok()?.wrapping_add(delta as u64);
+            let formatted = match base {
+                Base::Hex if digits.chars().any(|c| { c.is_ascii_uppercase() }) => format!("{:0width$X}", value, width = width),
+                Base::Hex => format!("{:0width$x}", value, width = width),
+                Base::Binary => format!("{:0width$b}", value, width = width),
+                _ => format!("{:0width$o}", value, width = width),
+            };
+            let prefix: String = prefix.iter().collect();
+            format!("{}{}", prefix, formatted)
+        },
+    };
+    let before: String = chars[.. number.start].iter().collect();
+    let after: String = chars[number.end ..].iter().collect();
+    let last = number.start + char_count(&replaced) - 1;
+    Some((format!("{}{}{}", before, replaced, after), last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_and_case() {
+        assert_eq!(byte_offset("héllo", 2), 3);
+        assert_eq!(byte_offset("héllo", 9), 6);
+        assert_eq!(toggle_case("Hello World", 0, 3), ("hELlo World".to_string(), 3));
+        assert_eq!(toggle_case("ab", 1, 5), ("aB".to_string(), 2));
+        assert_eq!(toggle_case("straße", 4, 1), ("straSSe".to_string(), 5));
+    }
+
+    #[test]
+    fn character_codes() {
+        assert_eq!(code_radix('6'), Some((10, 3)));
+        assert_eq!(code_radix('u'), Some((16, 4)));
+        assert_eq!(code_radix('z'), None);
+        assert_eq!(code_char("065", 10), Some('A'));
+        assert_eq!(code_char("101", 8), Some('A'));
+        assert_eq!(code_char("2014", 16), Some('—'));
+        assert_eq!(code_char("999", 10), None);
+        assert_eq!(code_char("d800", 16), None);
+        assert_eq!(word_start("let foo_bar"), 4);
+        assert_eq!(word_start("x = (a  "), 5);
+    }
+
+    #[test]
+    fn increment_numbers() {
+        assert_eq!(increment("x = 41;", 0, 1), Some(("x = 42;".to_string(), 5)));
+        assert_eq!(increment("x = 41;", 5, 1), Some(("x = 42;".to_string(), 5)));
+        assert_eq!(increment("x = 41;", 6, 1), None);
+        assert_eq!(increment("item 1, item 9", 7, 1), Some(("item 1, item 10".to_string(), 14)));
+        assert_eq!(increment("-1", 1, 3), Some(("2".to_string(), 0)));
+        assert_eq!(increment("n = 0", 0, -2), Some(("n = -2".to_string(), 5)));
+        assert_eq!(increment("0xff", 0, 1), Some(("0x100".to_string(), 4)));
+        assert_eq!(increment("0x0F", 2, 1), Some(("0x10".to_string(), 3)));
+        assert_eq!(increment("0X00fe", 0, 1), Some(("0X00ff".to_string(), 5)));
+        assert_eq!(increment("007", 0, 1), Some(("010".to_string(), 2)));
+        assert_eq!(increment("08", 0, 1), Some(("9".to_string(), 0)));
+        assert_eq!(increment("0b0111", 0, 1), Some(("0b1000".to_string(), 5)));
+        assert_eq!(increment("0x0", 0, -1), Some(("0xffffffffffffffff".to_string(), 17)));
+        assert_eq!(increment("no digits", 0, 1), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::char;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+}
+
+/// Characters for bytes 0x80 to 0x9f in Windows-1252.  The rest of the
+/// code page matches Latin-1.  `None` marks the five unassigned bytes.
+static WINDOWS_1252: [Option<char>; 32] = [
+    Some('\u{20ac}'), None, Some('\u{201a}'), Some('\u{0192}'),
+    Some('\u{201e}'), Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'),
+    Some('\u{02c6}'), Some('\u{2030}'), Some('\u{0160}'), Some('\u{2039}'),
+    Some('\u{0152}'), None, Some('\u{017d}'), None,
+    None, Some('\u{2018}'), Some('\u{2019}'), Some('\u{201c}'),
+    Some('\u{201d}'), Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'),
+    Some('\u{02dc}'), Some('\u{2122}'), Some('\u{0161}'), Some('\u{203a}'),
+    Some('\u{0153}'), None, Some('\u{017e}'), Some('\u{0178}'),
+];
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16le" | "ucs-2le" => Some(Encoding::Utf16Le),
+            "utf-16" | "utf-16be" | "ucs-2" => Some(Encoding::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// The `fileencoding` option value for this encoding.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16",
+            Encoding::Latin1 => "latin1",
+            Encoding::Windows1252 => "cp1252",
+        }
+    }
+
+    pub fn bom(&self) -> &'static [u8] {
+        match *self {
+            Encoding::Utf8 => b"\xef\xbb\xbf",
+            Encoding::Utf16Le => b"\xff\xfe",
+            Encoding::Utf16Be => b"\xfe\xff",
+            Encoding::Latin1 | Encoding::Windows1252 => b"",
+        }
+    }
+
+    /// Decode `bytes`, or return `None` if they are not valid in this
+    /// encoding.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match *self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if bytes.len() & 1 != 0 {
+                    return None;
+                }
+                let units = bytes.chunks(2).map(|pair| {
+                    if *self == Encoding::Utf16Le {
+                        (pair[1] as u16) << 8 | pair[0] as u16
+                    } else {
+                        (pair[0] as u16) << 8 | pair[1] as u16
+                    }
+                });
+                char::decode_utf16(units).collect::<Result<String, _>>().ok()
+            },
+            Encoding::Latin1 => Some(bytes.iter().map(|&b| { b as char }).collect()),
+            Encoding::Windows1252 => bytes.iter().map(|&b| {
+                match b {
+                    0x80 ..= 0x9f => WINDOWS_1252[(b - 0x80) as usize],
+                    _ => Some(b as char),
+                }
+            }).collect(),
+        }
+    }
+
+    /// Encode `text`, failing on the first character this encoding cannot
+    /// represent.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match *self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let mut bytes = Vec::with_capacity(text.len() * 2);
+                for unit in text.encode_utf16() {
+                    let (high, low) = ((unit >> 8) as u8, unit as u8);
+                    if *self == Encoding::Utf16Le {
+                        bytes.push(low);
+                        bytes.push(high);
+                    } else {
+                        bytes.push(high);
+                        bytes.push(low);
+                    }
+                }
+                Ok(bytes)
+            },
+            Encoding::Latin1 | Encoding::Windows1252 => text.chars().map(|c| {
+                self.encode_char(c).ok_or_else(|| {
+                    format!("E513: write error, conversion failed: {:?} is not in {}", c, self.name())
+                })
+            }).collect(),
+        }
+    }
+
+    fn encode_char(&self, c: char) -> Option<u8> {
+        if *self == Encoding::Windows1252 {
+            if let Some(position) = WINDOWS_1252.iter().position(|&x| { x == Some(c) }) {
+                return Some(0x80 + position as u8);
+            }
+            if (c as u32) >= 0x80 && (c as u32) < 0xa0 {
+                return None;
+            }
+        }
+        if (c as u32) < 0x100 { Some(c as u8) } else { None }
+    }
+}
+
+/// Find an encoding announced by a byte order mark.
+pub fn detect_bom(bytes: &[u8]) -> Option<Encoding> {
+    [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be].iter()
+        .find(|encoding| { bytes.starts_with(encoding.bom()) })
+        .cloned()
+}
+
+/// Decode `bytes` with the first encoding in the comma-separated
+/// `fileencodings` list that accepts them.  The special entry `ucs-bom`
+/// checks for a byte order mark.  Returns the text, the encoding used and
+/// whether a BOM was found.
+pub fn decode(bytes: &[u8], fileencodings: &str) -> Result<(String, Encoding, bool), String> {
+    for name in fileencodings.split(',').filter(|name| { !name.is_empty() }) {
+        if name == "ucs-bom" {
+            if let Some(encoding) = detect_bom(bytes) {
+                if let Some(text) = encoding.decode(&bytes[encoding.bom().len() ..]) {
+                    return Ok((text, encoding, true));
+                }
+            }
+            continue;
+        }
+        let encoding = match Encoding::from_name(name) {
+            Some(encoding) => encoding,
+            None => return Err(format!("E474: Invalid argument: fileencodings={}", fileencodings)),
+        };
+        if let Some(text) = encoding.decode(bytes) {
+            return Ok((text, encoding, false));
+        }
+    }
+    Err(format!("Could not decode file as any of: {}", fileencodings))
+}
+
+/// Encode `text`, prefixing a BOM if `bomb` is set and the encoding has one.
+pub fn encode(text: &str, encoding: Encoding, bomb: bool) -> Result<Vec<u8>, String> {
+    let mut bytes = if bomb { encoding.bom().to_vec() } else { vec![] };
+    bytes.extend(encoding.encode(text)?);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: &str = "ucs-bom,utf-8,latin1";
+
+    #[test]
+    fn names() {
+        assert_eq!(Encoding::from_name("UTF-8"), Some(Encoding::Utf8));
+        assert_eq!(Encoding::from_name("iso-8859-1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("ebcdic"), None);
+        assert_eq!(Encoding::Windows1252.name(), "cp1252");
+    }
+
+    #[test]
+    fn decode_utf8_and_fallback() {
+        assert_eq!(decode("caf\u{e9}".as_bytes(), DEFAULT), Ok(("caf\u{e9}".to_string(), Encoding::Utf8, false)));
+        assert_eq!(decode(b"caf\xe9", DEFAULT), Ok(("caf\u{e9}".to_string(), Encoding::Latin1, false)));
+        assert_eq!(decode(b"\x93hi\x94", "utf-8,cp1252"), Ok(("\u{201c}hi\u{201d}".to_string(), Encoding::Windows1252, false)));
+        assert!(decode(b"caf\xe9", "utf-8").is_err());
+        assert!(decode(b"x", "klingon").is_err());
+    }
+
+    #[test]
+    fn decode_boms() {
+        assert_eq!(decode(b"\xef\xbb\xbfhi", DEFAULT), Ok(("hi".to_string(), Encoding::Utf8, true)));
+        assert_eq!(decode(b"\xff\xfeh\x00i\x00", DEFAULT), Ok(("hi".to_string(), Encoding::Utf16Le, true)));
+        assert_eq!(decode(b"\xfe\xff\x00h\x00i", DEFAULT), Ok(("hi".to_string(), Encoding::Utf16Be, true)));
+        assert_eq!(decode(b"\xef\xbb\xbfhi", "utf-8"), Ok(("\u{feff}hi".to_string(), Encoding::Utf8, false)));
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be, Encoding::Latin1, Encoding::Windows1252].iter() {
+            let text = "na\u{ef}ve\n";
+            let bytes = encode(text, *encoding, true).unwrap();
+            assert_eq!(decode(&bytes, &format!("ucs-bom,{}", encoding.name())), Ok((text.to_string(), *encoding, !encoding.bom().is_empty())));
+        }
+    }
+
+    #[test]
+    fn encode_unrepresentable() {
+        assert!(Encoding::Latin1.encode("\u{20ac}").is_err());
+        assert_eq!(Encoding::Windows1252.encode("\u{20ac}"), Ok(vec![0x80]));
+        assert!(Encoding::Windows1252.encode("\u{81}").is_err());
+        assert_eq!(Encoding::Utf16Be.encode("\u{1f600}"), Ok(vec![0xd8, 0x3d, 0xde, 0x00]));
+    }
+}
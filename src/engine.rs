@@ -1,20 +1,100 @@
+use abbrev::{self, Abbreviation, Abbreviations};
 use buffer;
+use cmdline::{History, LineEditor, Outcome};
+use complete::{self, Completion, Menu};
+use config;
 use ex;
-use std::fs::File;
-use std::io::{self, Write};
-use display::IO;
+use ex::ArgumentKind;
+use mapping::{self, Mapping, Mappings, Resolver};
+use ex::parser;
+use options::{self, Options, SetArg, Value};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+use digraph;
+use display::{Gutter, IO};
+use edit::{self, Replaced};
+use encoding::Encoding;
+use pipe;
+use rustbox::{Event, Key};
+use sort;
+use swap::{self, SwapFile};
+use viewport::{Scroll, Viewport};
+use watch::Watcher;
 
+/// How long to wait for a key before looking for changes to the file.
+pub const IDLE: Duration = Duration::from_millis(500);
+
+/// The largest count Normal mode accepts, as in vim.
+const MAX_COUNT: usize = 999_999_999;
+
+/// How deeply `:source` may nest before giving up on a file that
+/// sources itself.
+const MAX_SOURCING: usize = 50;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Mode {
     Ex,
     Normal,
+    Insert,
+    Replace,
 }
 
 pub struct Engine<'a> {
     pub buffer: buffer::Buffer,
+    pub arglist: Vec<String>,
+    /// Index in `arglist` of the file being edited.
+    pub argidx: usize,
     pub cursor: CursorLocator,
     pub clipboard: String,
     pub io: &'a mut IO,
     pub mode: Mode,
+    /// The first key of an unfinished two-key Normal mode command.
+    pub pending: Option<char>,
+    /// The count typed before a Normal mode command.
+    pub count: Option<usize>,
+    /// The count typed before a pending operator, which multiplies the
+    /// count of its motion.
+    operator_count: Option<usize>,
+    /// Global option values.
+    pub options: Options,
+    /// Window-local option values set with `:setlocal`.
+    pub window_options: Options,
+    /// The swap file journaling the current buffer, if it has one.
+    pub swap: Option<SwapFile>,
+    /// The previously edited file, substituted for `#` in shell commands.
+    pub alternate: Option<String>,
+    /// The last shell command run, substituted for `!`.
+    pub last_shell: Option<String>,
+    /// Text to start the next ex command line with, set by Normal mode
+    /// commands such as `!{motion}`.
+    pub cmdline: Option<String>,
+    /// Notices changes to the current file made by other programs.
+    pub watcher: Option<Watcher>,
+    /// Output of commands such as `:p`, shown by `flush_messages`.
+    pub messages: Vec<String>,
+    /// An ex command picked in the command-line window, for the main loop
+    /// to run.
+    pub command: Option<String>,
+    pub command_history: History,
+    pub search_history: History,
+    /// The pattern of the last `/` search.
+    pub last_search: Option<String>,
+    pub mappings: Mappings,
+    pub abbreviations: Abbreviations,
+    /// Typed keys not yet used, and mapped keys to be used before them.
+    pub keys: Resolver,
+    /// Set while running commands from a `.virsrc` that is not trusted or
+    /// with `secure` on: shell and write commands, and changes to the
+    /// options `options::is_secure` names, are refused.
+    pub restricted: bool,
+    /// How many command files are being run, one inside the other.
+    sourcing: usize,
+    /// When the last key was used, for `updatetime`.
+    pub typed_at: Instant,
 }
 
 pub struct CursorLocator {
@@ -26,159 +106,3460 @@ impl <'a> Engine<'a> {
     pub fn new(io: &'a mut IO) -> Self {
         Engine {
             buffer: buffer::Buffer::new(),
+            arglist: vec![],
+            argidx: 0,
             cursor: CursorLocator::new(),
             clipboard: String::new(),
             io: io,
             mode: Mode::Normal,
+            pending: None,
+            count: None,
+            operator_count: None,
+            options: Options::defaults(),
+            window_options: Options::new(),
+            swap: None,
+            alternate: None,
+            last_shell: None,
+            cmdline: None,
+            watcher: Watcher::new().ok(),
+            messages: vec![],
+            command: None,
+            command_history: History::new(),
+            search_history: History::new(),
+            last_search: None,
+            mappings: Mappings::new(),
+            abbreviations: Abbreviations::new(),
+            keys: Resolver::new(),
+            restricted: false,
+            sourcing: 0,
+            typed_at: Instant::now(),
         }
     }
 
-    pub fn execute(&mut self, command: &ex::Command) -> Result<bool, String> {
-        let range = self.get_selection(&command.selector);
-        match command.action {
-            ex::Action::Edit(ref filename) => self.execute_edit(filename),
-            ex::Action::Write(ref filename) => self.execute_write(range, Some(filename)),
-            ex::Action::Go => self.execute_go(range),
-            ex::Action::Yank => self.execute_yank(range),
-            ex::Action::Print => self.execute_print(range),
-            ex::Action::Quit => self.execute_quit(),
-            _ => self.execute_unknown(command)
+    fn viewport(&self) -> Viewport {
+        Viewport {
+            top: self.buffer.top_visible,
+            height: self.io.text_height(),
+            line_count: self.buffer.content.len(),
+            scrolloff: self.option_number("scrolloff").max(0) as usize,
         }
     }
 
-    fn get_selection(&self, selector: &ex::Selector) -> (u64, Option<u64>) {
-        let start = match selector.start {
-            ex::Locator::All => 1,
-            ex::Locator::Here => self.cursor.line,
-            ex::Locator::Last => self.buffer.content.len() as u64,
-            ex::Locator::Line(x) => x,
-            ex::Locator::Ahead(offset) => self.cursor.line + offset,
-            ex::Locator::Back(offset) => self.cursor.line - offset,
-        };
-        let end = match selector.end {
-            Some(ref location) => match location {
-                &ex::Locator::Here => Some(self.cursor.line),
-                &ex::Locator::All | &ex::Locator::Last => Some(self.buffer.content.len() as u64),
-                &ex::Locator::Line(x) => Some(x),
-                &ex::Locator::Ahead(offset) => Some(self.cursor.line + offset),
-                &ex::Locator::Back(offset) => Some(self.cursor.line - offset),
+    /// Scroll the buffer so the cursor is visible and draw it.
+    pub fn redraw(&mut self) {
+        let cursor = self.cursor.line.saturating_sub(1) as usize;
+        self.buffer.top_visible = self.viewport().follow(cursor);
+        self.io.show_buffer(&self.buffer, self);
+    }
+
+    /// Add a line to the message area.
+    pub fn message(&mut self, text: String) {
+        self.messages.push(text);
+    }
+
+    /// Show the pending messages: a single one on the status line, more in
+    /// the message area, after which the buffer is drawn again.
+    pub fn flush_messages(&mut self) {
+        let messages = mem::take(&mut self.messages);
+        match messages.len() {
+            0 => {},
+            1 => self.io.set_status(&messages[0]),
+            _ => {
+                self.io.show_messages(&messages);
+                self.redraw();
             },
-            None => match selector.start {
-                ex::Locator::All => Some(self.buffer.content.len() as u64),
-                _ => None,
+        }
+    }
+
+    pub fn scroll(&mut self, scroll: Scroll) {
+        let cursor = self.cursor.line.saturating_sub(1) as usize;
+        let (top, cursor) = self.viewport().scroll(cursor, scroll);
+        self.buffer.top_visible = top;
+        self.cursor.line = cursor as u64 + 1;
+    }
+
+    /// The next key for `mode`, one of the `mapping` mode bits, with
+    /// mappings applied.  Waits for a key for `idle`, or until one is
+    /// typed.  Returns `None` if none was, or input failed.
+    pub fn next_key(&mut self, mode: u8, idle: Option<Duration>) -> Option<Key> {
+        let timeout = if self.option_bool("timeout") {
+            Some(Duration::from_millis(self.option_number("timeoutlen").max(0) as u64))
+        } else {
+            None
+        };
+        let max_depth = self.option_number("maxmapdepth").max(1) as usize;
+        let rustbox = self.io.rustbox().ok();
+        let read = |wait: Option<Duration>| {
+            let rustbox = rustbox?;
+            loop {
+                let event = match wait {
+                    Some(wait) => rustbox.peek_event(wait, false),
+                    None => rustbox.poll_event(false),
+                };
+                match event {
+                    Ok(Event::KeyEvent(key)) => return Some(key),
+                    Ok(Event::NoEvent) | Err(_) => return None,
+                    Ok(_) if wait.is_some() => return None,
+                    Ok(_) => {},
+                }
             }
         };
-        return (start, end)
+        match self.keys.next(&self.mappings, mode, idle, timeout, max_depth, read) {
+            Ok(Some(key)) => {
+                self.typed_at = Instant::now();
+                Some(key)
+            },
+            Ok(None) => None,
+            Err(message) => {
+                self.io.set_status(&message);
+                None
+            },
+        }
+    }
+
+    /// Show `message` and wait for one of `choices`, or Enter for the
+    /// first.  Keys already waiting, e.g. from a mapping, answer before
+    /// typed ones.
+    pub fn prompt(&mut self, message: &str, choices: &str) -> Option<char> {
+        while let Some(key) = self.keys.take() {
+            match key {
+                Key::Char(c) if choices.contains(c.to_ascii_lowercase()) => return Some(c.to_ascii_lowercase()),
+                Key::Enter => return choices.chars().next(),
+                _ => {},
+            }
+        }
+        self.io.prompt(message, choices)
     }
 
+    /// The mode Normal mode keys are mapped in: Operator-pending while an
+    /// operator waits for its motion.
+    pub fn key_mode(&self) -> u8 {
+        match self.pending {
+            Some('!') => mapping::OPERATOR,
+            // The character `r` replaces with is taken as typed.
+            Some('r') => 0,
+            _ => mapping::NORMAL,
+        }
+    }
 
-    fn execute_edit(&mut self, filename: &str) -> Result<bool, String> {
-        match buffer::Buffer::open(filename) {
-            Ok(buffer) => {
-                self.buffer = buffer;
-                self.cursor = CursorLocator::new();
-                self.io.show_buffer(&self.buffer, self);
-                Ok(true)
-            },
-            Err(_) => Err(format!("Could not open specified file: {}", filename))
+    /// Handle a key pressed in Normal mode.  Digits typed before a command
+    /// give its count.  Returns false if the key is not bound to anything.
+    pub fn normal_key(&mut self, key: Key) -> bool {
+        if let (None, Key::Char(digit @ '0' ..= '9')) | (Some('!'), Key::Char(digit @ '0' ..= '9')) = (self.pending, key) {
+            if digit != '0' || self.count.is_some() {
+                let count = (self.count.unwrap_or(0) * 10 + (digit as usize - '0' as usize)).min(MAX_COUNT);
+                self.count = Some(count);
+                return true;
+            }
+        }
+        let handled = self.normal_command(key);
+        if self.pending.is_none() {
+            self.count = None;
         }
+        self.clamp_cursor();
+        handled
+    }
+
+    fn normal_command(&mut self, key: Key) -> bool {
+        let count = self.count.unwrap_or(1).max(1);
+        let scroll = match (self.pending.take(), key) {
+            (None, Key::Char('h')) | (None, Key::Left) | (None, Key::Backspace) => {
+                let col = self.col().saturating_sub(count);
+                self.set_col(col);
+                return true;
+            },
+            (None, Key::Char('l')) | (None, Key::Right) | (None, Key::Char(' ')) => {
+                let col = self.col() + count;
+                self.set_col(col);
+                return true;
+            },
+            (None, Key::Char('0')) | (None, Key::Home) => {
+                self.set_col(0);
+                return true;
+            },
+            (None, Key::Char('$')) | (None, Key::End) => {
+                let line = self.line_index() + count - 1;
+                self.cursor.line = line as u64 + 1;
+                let end = self.buffer.content.get(line).map(|text| { edit::char_count(text) }).unwrap_or(0);
+                self.set_col(end);
+                return true;
+            },
+            (None, Key::Char('j')) | (None, Key::Down) => {
+                self.cursor.line += count as u64;
+                return true;
+            },
+            (None, Key::Char('k')) | (None, Key::Up) => {
+                self.cursor.line = self.cursor.line.saturating_sub(count as u64).max(1);
+                return true;
+            },
+            (None, Key::Char('i')) | (None, Key::Insert) => {
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('a')) => {
+                if self.buffer.content.get(self.line_index()).is_some_and(|text| { !text.is_empty() }) {
+                    let col = self.col() + 1;
+                    self.set_col(col);
+                }
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('I')) => {
+                let col = self.buffer.content.get(self.line_index()).map(|text| { edit::first_non_blank(text) }).unwrap_or(0);
+                self.set_col(col);
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('A')) => {
+                let col = self.buffer.content.get(self.line_index()).map(|text| { edit::char_count(text) }).unwrap_or(0);
+                self.set_col(col);
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('R')) => {
+                self.text_entry(true);
+                return true;
+            },
+            (None, Key::Char('r')) => {
+                self.pending = Some('r');
+                return true;
+            },
+            (Some('r'), key) => {
+                self.replace_chars(key, count);
+                return true;
+            },
+            (None, Key::Char('x')) | (None, Key::Delete) => {
+                self.delete_chars(count, false);
+                return true;
+            },
+            (None, Key::Char('X')) => {
+                self.delete_chars(count, true);
+                return true;
+            },
+            (None, Key::Char('s')) => {
+                let col = self.col();
+                self.delete_chars(count, false);
+                self.set_col(col);
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('S')) => {
+                self.substitute_lines(count);
+                return true;
+            },
+            (None, Key::Char('D')) => {
+                self.delete_to_end(count);
+                return true;
+            },
+            (None, Key::Char('C')) => {
+                let col = self.col();
+                self.delete_to_end(count);
+                self.set_col(col);
+                self.text_entry(false);
+                return true;
+            },
+            (None, Key::Char('~')) => {
+                self.toggle_case(count);
+                return true;
+            },
+            (None, Key::Char('J')) => {
+                self.join_lines(count, false);
+                return true;
+            },
+            (Some('g'), Key::Char('J')) => {
+                self.join_lines(count, true);
+                return true;
+            },
+            (None, Key::Char('p')) => {
+                self.put(count, true, false);
+                return true;
+            },
+            (None, Key::Char('P')) => {
+                self.put(count, false, false);
+                return true;
+            },
+            (Some('g'), Key::Char('p')) => {
+                self.put(count, true, true);
+                return true;
+            },
+            (Some('g'), Key::Char('P')) => {
+                self.put(count, false, true);
+                return true;
+            },
+            (None, Key::Ctrl('a')) => {
+                self.increment(count as i64);
+                return true;
+            },
+            (None, Key::Ctrl('x')) => {
+                self.increment(-(count as i64));
+                return true;
+            },
+            (None, Key::Ctrl('e')) => Scroll::LineDown,
+            (None, Key::Ctrl('y')) => Scroll::LineUp,
+            (None, Key::Ctrl('d')) => Scroll::HalfPageDown,
+            (None, Key::Ctrl('u')) => Scroll::HalfPageUp,
+            (None, Key::Ctrl('f')) | (None, Key::PageDown) => Scroll::PageDown,
+            (None, Key::Ctrl('b')) | (None, Key::PageUp) => Scroll::PageUp,
+            (None, Key::Char('z')) => {
+                self.pending = Some('z');
+                return true;
+            },
+            (Some('z'), Key::Char('t')) | (Some('z'), Key::Enter) => Scroll::CursorTop,
+            (Some('z'), Key::Char('z')) | (Some('z'), Key::Char('.')) => Scroll::CursorMiddle,
+            (Some('z'), Key::Char('b')) | (Some('z'), Key::Char('-')) => Scroll::CursorBottom,
+            (None, Key::Char('!')) => {
+                self.pending = Some('!');
+                self.operator_count = self.count.take();
+                return true;
+            },
+            (None, Key::Char('g')) => {
+                self.pending = Some('g');
+                return true;
+            },
+            (None, Key::Char('q')) => {
+                self.pending = Some('q');
+                return true;
+            },
+            (Some('q'), Key::Char(':')) => {
+                self.command = self.cmdline_window(':', None);
+                return true;
+            },
+            (Some('q'), Key::Char('/')) => {
+                if let Some(pattern) = self.cmdline_window('/', None) {
+                    self.search_key(&pattern);
+                }
+                return true;
+            },
+            (None, Key::Char('/')) => {
+                if let Some(pattern) = self.read_cmdline('/', "") {
+                    self.search_key(&pattern);
+                }
+                return true;
+            },
+            (None, Key::Ctrl('g')) => {
+                let info = self.file_info();
+                self.message(info);
+                return true;
+            },
+            (Some('g'), Key::Ctrl('g')) => {
+                let info = self.cursor_info();
+                self.message(info);
+                return true;
+            },
+            (Some('!'), key) => return self.filter_motion(key),
+            _ => return false,
+        };
+        self.scroll(scroll);
+        true
     }
 
-    fn execute_write(&mut self, range: (u64, Option<u64>), filename: Option<&str>) -> Result<bool, String> {
+    /// Read a line at `prompt`, `:` or `/`, starting with `initial`.  The
+    /// line is added to the prompt's history.  Returns `None` if it was
+    /// cancelled.
+    pub fn read_cmdline(&mut self, prompt: char, initial: &str) -> Option<String> {
+        let mut editor = LineEditor::new(initial);
+        let mut menu_shown = false;
+        loop {
+            self.io.show_cmdline(prompt, &editor.text, editor.column());
+            let key = self.next_key(mapping::CMDLINE, None)?;
+            let trigger = match key {
+                Key::Char(c) => Some(Some(c)),
+                Key::Enter => Some(None),
+                _ => None,
+            };
+            if let (Some(trigger), false) = (trigger, editor.quoting()) {
+                if let Some((start, rhs)) = self.abbreviations.expand(&editor.text[.. editor.cursor], mapping::CMDLINE, trigger) {
+                    editor.replace_word(start, &rhs);
+                }
+            }
+            let outcome = editor.key(key, self.history(prompt));
+            if menu_shown && editor.completion.is_none() {
+                self.redraw();
+                menu_shown = false;
+            }
+            match outcome {
+                Outcome::Continue => {},
+                Outcome::Complete => {
+                    if editor.completion.is_none() {
+                        editor.completion = Some(self.start_completion(prompt, &editor));
+                    }
+                    let stages = complete::parse_wildmode(&self.option_str("wildmode"));
+                    let wildmenu = self.option_bool("wildmenu");
+                    let completion = editor.completion.as_mut().unwrap();
+                    let (word, menu) = completion.tab(&stages, wildmenu);
+                    let start = completion.start;
+                    editor.replace_word(start, &word);
+                    menu_shown = self.show_completions(&editor, menu, menu_shown);
+                },
+                Outcome::Cycle(forward) => {
+                    let completion = editor.completion.as_mut().unwrap();
+                    let (word, menu) = completion.cycle(forward);
+                    let start = completion.start;
+                    editor.replace_word(start, &word);
+                    menu_shown = self.show_completions(&editor, menu, menu_shown);
+                },
+                Outcome::Accept => {
+                    self.remember(prompt, &editor.text);
+                    return Some(editor.text);
+                },
+                Outcome::Cancel => return None,
+                Outcome::Register(name) => {
+                    if let Some(text) = self.register(name) {
+                        editor.insert_str(&text);
+                    }
+                },
+                Outcome::Window => return self.cmdline_window(prompt, Some(editor.text)),
+            }
+        }
+    }
 
+    /// Find what the word before the cursor can be completed to.  Only
+    /// ex command lines are completed.
+    fn start_completion(&self, prompt: char, editor: &LineEditor) -> Completion {
+        let typed = &editor.text[.. editor.cursor];
+        let (start, kind) = if prompt == ':' { parser::completion_context(typed) } else { (typed.len(), ArgumentKind::None) };
+        let word = &typed[start ..];
+        Completion::new(start, word, self.completions(&kind, word))
+    }
 
-        let filename = match filename {
-            Some(filename) => filename,
-            None => match self.buffer.filename {
-                Some(ref filename) => filename,
-                None => return Err("No file specified".to_string()),
-            }
-        };
-        match File::create(filename) {
-            Ok(mut fh) => {
-                for line in range.0 .. (range.1.unwrap_or((self.buffer.content.len()+1) as u64)) {
-                    match fh.write_all(&self.buffer.content[line as usize].as_bytes()).and(fh.write_all(b"\n")) {
-                        Ok(_) => continue,
-                        Err(_) => return Err(format!("Error writing file, {}", filename).to_string()),
+    /// The words that complete `word`, an argument of the given kind.
+    fn completions(&self, kind: &ArgumentKind, word: &str) -> Vec<String> {
+        match *kind {
+            ArgumentKind::Command => complete::matching(parser::command_names().into_iter(), word),
+            ArgumentKind::Option => {
+                let mut names = vec![];
+                for def in options::OPTIONS.iter() {
+                    names.push(def.name.to_string());
+                    if def.kind == options::Kind::Bool {
+                        names.push(format!("no{}", def.name));
+                        names.push(format!("inv{}", def.name));
                     }
                 }
-                Ok(true)
+                complete::matching(names.iter().map(String::as_str), word)
+            },
+            ArgumentKind::OptionValue(ref name) => match options::lookup(name) {
+                Some(def) if def.kind != options::Kind::Bool => {
+                    let mut values = complete::matching(def.choices.iter().cloned(), word);
+                    let current = self.option(def.name).to_string();
+                    if current.starts_with(word) && !values.contains(&current) {
+                        values.insert(0, current);
+                    }
+                    values
+                },
+                _ => vec![],
             },
-            Err(_) => Err(format!("Could not write to file: {}", filename))
+            ArgumentKind::File => complete::files(word),
+            ArgumentKind::None => vec![],
         }
     }
 
-    fn execute_go(&mut self, range: (u64, Option<u64>)) -> Result<bool, String> {
-        let line = match range.1 {
-            Some(x) => x,
-            None => range.0,
+    /// Draw the completion matches as `menu` says.  Returns whether a menu
+    /// is on screen; one that was, `shown`, is cleared if no longer wanted.
+    fn show_completions(&mut self, editor: &LineEditor, menu: Menu, shown: bool) -> bool {
+        let matches = editor.completion.as_ref().map_or(&[][..], |completion| { &completion.matches[..] });
+        match menu {
+            Menu::Hidden => {
+                if shown {
+                    self.redraw();
+                }
+                false
+            },
+            Menu::Wild(selected) => {
+                self.io.show_wildmenu(matches, selected);
+                true
+            },
+            Menu::List(selected) => {
+                self.io.show_completion_list(matches, selected);
+                true
+            },
+        }
+    }
+
+    fn history(&self, prompt: char) -> &History {
+        if prompt == '/' { &self.search_history } else { &self.command_history }
+    }
+
+    fn history_mut(&mut self, prompt: char) -> &mut History {
+        if prompt == '/' { &mut self.search_history } else { &mut self.command_history }
+    }
+
+    fn remember(&mut self, prompt: char, line: &str) {
+        let max = self.option_number("history").max(0) as usize;
+        self.history_mut(prompt).add(line, max);
+    }
+
+    /// The contents of register `name`, for `Ctrl-R` on the command line
+    /// and in Insert mode.
+    fn register(&self, name: char) -> Option<String> {
+        match name {
+            '"' | '0' => Some(self.clipboard.trim_end_matches('\n').to_string()),
+            ':' => self.command_history.entries().last().cloned(),
+            '/' => self.last_search.clone(),
+            '%' => self.buffer.filename.clone(),
+            '#' => self.alternate.clone(),
+            _ => None,
+        }
+    }
+
+    /// `q:`, `q/` and `Ctrl-F`: show the history of `prompt` in a window,
+    /// with `current` on the last line.  `j` and `k` move, Enter picks the
+    /// line under the cursor, `dd` deletes a history entry, `i`, `a` and
+    /// `A` edit the line at the prompt, and Escape or `q` close the window
+    /// without picking anything.
+    pub fn cmdline_window(&mut self, prompt: char, current: Option<String>) -> Option<String> {
+        let mut window = buffer::Buffer::new();
+        window.content = self.history(prompt).entries().to_vec();
+        window.content.push(current.unwrap_or_default());
+        let cursor = CursorLocator { line: window.content.len() as u64, col: 1 };
+        let buffer = mem::replace(&mut self.buffer, window);
+        let cursor = mem::replace(&mut self.cursor, cursor);
+        let picked = self.cmdline_window_keys(prompt);
+        self.buffer = buffer;
+        self.cursor = cursor;
+        self.redraw();
+        match picked {
+            Some((line, false)) => {
+                self.remember(prompt, &line);
+                Some(line)
+            },
+            Some((line, true)) => self.read_cmdline(prompt, &line),
+            None => None,
+        }
+    }
+
+    /// Handle keys in the command-line window until a line is picked, and
+    /// whether it is to be edited first.
+    fn cmdline_window_keys(&mut self, prompt: char) -> Option<(String, bool)> {
+        let title = if prompt == '/' { "[Search History]" } else { "[Command Line]" };
+        let mut delete = false;
+        loop {
+            self.redraw();
+            self.io.set_status(title);
+            let key = self.next_key(mapping::NORMAL, None)?;
+            let last = self.buffer.content.len() as u64;
+            let line = self.cursor.line;
+            let text = self.buffer.content[line as usize - 1].clone();
+            match key {
+                Key::Char('j') | Key::Down if line < last => self.cursor.line += 1,
+                Key::Char('k') | Key::Up if line > 1 => self.cursor.line -= 1,
+                Key::Char('G') => self.cursor.line = last,
+                Key::Enter => return Some((text, false)),
+                Key::Char('i') | Key::Char('a') | Key::Char('A') => return Some((text, true)),
+                Key::Char('d') if delete && line < last => {
+                    self.buffer.content.remove(line as usize - 1);
+                    self.history_mut(prompt).remove(line as usize - 1);
+                },
+                Key::Char('d') => {
+                    delete = true;
+                    continue;
+                },
+                Key::Esc | Key::Char('q') | Key::Ctrl('c') => return None,
+                _ => {},
+            }
+            delete = false;
+        }
+    }
+
+    /// Search for `pattern` from Normal mode, reporting failure in the
+    /// message area.
+    fn search_key(&mut self, pattern: &str) {
+        if let Err(err) = self.search(pattern) {
+            self.message(err);
+        }
+    }
+
+    /// `/pattern`: move to the next line containing `pattern`, or the last
+    /// pattern searched for if it is empty.
+    pub fn search(&mut self, pattern: &str) -> Result<(), String> {
+        let pattern = match (pattern, self.last_search.clone()) {
+            ("", Some(last)) => last,
+            ("", None) => return Err("E35: No previous regular expression".to_string()),
+            (pattern, _) => pattern.to_string(),
         };
+        self.last_search = Some(pattern.clone());
+        let line = self.search_forward(&pattern)?;
         self.cursor = CursorLocator { line: line, col: 1 };
-        Ok(true)
+        Ok(())
     }
 
-    fn execute_yank(&mut self, range: (u64, Option<u64>)) -> Result<bool, String> {
-        let mut yanked = String::new();
-        let end = match range.1 {
-            Some(x) => x,
-            None => range.0,
+    /// The `!{motion}` operator: start an ex command line that filters the
+    /// lines the motion covers, e.g. `:.,.+1!` for `!j`.  A count before
+    /// the operator or the motion repeats the motion, so `3!!` and `!2j`
+    /// both give `:.,.+2!`.  Returns false if the motion cannot move.
+    fn filter_motion(&mut self, key: Key) -> bool {
+        let typed = match (self.operator_count.take(), self.count) {
+            (None, None) => None,
+            (before, after) => Some((before.unwrap_or(1) as u64).saturating_mul(after.unwrap_or(1).max(1) as u64)),
         };
-        for line in range.0 .. (end + 1) {
-            let offset = (line - 1) as usize;
-            yanked.push_str(&self.buffer.content[offset]);
-            yanked.push('\n');
+        let count = typed.unwrap_or(1);
+        let line = self.cursor.line;
+        let last = self.buffer.content.len() as u64;
+        let range = match key {
+            Key::Char('!') if count == 1 => ".".to_string(),
+            Key::Char('!') => format!(".,.+{}", count - 1),
+            Key::Char('j') | Key::Down if line.saturating_add(count) <= last => format!(".,.+{}", count),
+            Key::Char('k') | Key::Up if line > count => format!(".-{},.", count),
+            Key::Char('j') | Key::Down | Key::Char('k') | Key::Up => return false,
+            Key::Char('G') => match typed {
+                Some(target) => {
+                    let target = target.clamp(1, last.max(1));
+                    if target < line { format!("{},.", target) } else { format!(".,{}", target) }
+                },
+                None => ".,$".to_string(),
+            },
+            Key::Char('}') => {
+                let blank = self.buffer.content.iter().enumerate().skip(line as usize)
+                    .filter(|&(_, text)| { text.is_empty() })
+                    .nth(count as usize - 1)
+                    .map(|(i, _)| { i as u64 + 1 });
+                match blank {
+                    Some(blank) => format!(".,{}", blank),
+                    None => ".,$".to_string(),
+                }
+            },
+            Key::Esc => return true,
+            key => {
+                self.message(format!("Not a motion for !: {}", mapping::key_notation(&[key])));
+                return true;
+            },
+        };
+        self.cmdline = Some(format!("{}!", range));
+        true
+    }
+
+    /// The index of the cursor's line, counted from 0.
+    fn line_index(&self) -> usize {
+        self.cursor.line.max(1) as usize - 1
+    }
+
+    /// The index of the character under the cursor, counted from 0.
+    fn col(&self) -> usize {
+        self.cursor.col.max(1) as usize - 1
+    }
+
+    fn set_col(&mut self, col: usize) {
+        self.cursor.col = col as u64 + 1;
+    }
+
+    /// Keep the cursor on a line of the buffer and a character of that
+    /// line, as Normal mode needs.
+    fn clamp_cursor(&mut self) {
+        let len = self.buffer.content.len();
+        if len == 0 {
+            self.cursor = CursorLocator::new();
+            return;
         }
-        self.clipboard = yanked;
-        Ok(true)
+        let line = self.line_index().min(len - 1);
+        let last = edit::char_count(&self.buffer.content[line]).saturating_sub(1);
+        self.cursor = CursorLocator { line: line as u64 + 1, col: self.col().min(last) as u64 + 1 };
     }
 
-    fn execute_print(&self, range: (u64, Option<u64>)) -> Result<bool, String> {
-        let end = match range.1 {
-            Some(x) => x,
-            None => range.0,
+    /// Make sure there is a line to type into.
+    fn ensure_line(&mut self) {
+        if self.buffer.content.is_empty() {
+            self.buffer.content.push(String::new());
+            self.cursor = CursorLocator::new();
+        }
+    }
+
+    /// Insert mode, or Replace mode if `replace`: type text at the cursor
+    /// until Escape.  In Replace mode typed characters overwrite those
+    /// under the cursor, and Backspace brings back what they overwrote.
+    fn text_entry(&mut self, replace: bool) {
+        self.mode = if replace { Mode::Replace } else { Mode::Insert };
+        self.ensure_line();
+        let mut replaced = vec![];
+        let mut completion: Option<Completion> = None;
+        let mut previous = None;
+        loop {
+            if let Err(string) = self.sync_swap() {
+                self.messages.push(string);
+            }
+            self.redraw();
+            if !self.messages.is_empty() {
+                self.flush_messages();
+            } else if let Some(ref completion) = completion {
+                let status = match completion.selected {
+                    Some(i) => format!("-- Keyword completion (^N^P) match {} of {}", i + 1, completion.matches.len()),
+                    None => "-- Keyword completion (^N^P) Back at original".to_string(),
+                };
+                self.io.set_status(&status);
+                self.show_keyword_menu(completion);
+            } else {
+                self.io.set_status(if replace { "-- REPLACE --" } else { "-- INSERT --" });
+            }
+            let key = match self.next_key(mapping::INSERT, Some(IDLE)) {
+                Some(key) => key,
+                // Without a screen no key will ever come, e.g. after `Ctrl-O
+                // :!cmd` could not take the terminal back.
+                None if self.io.rustbox().is_err() => break,
+                None => {
+                    self.idle();
+                    continue;
+                },
+            };
+            if let Some(mut active) = completion.take() {
+                match key {
+                    Key::Ctrl('n') | Key::Ctrl('p') => {
+                        let (word, _) = active.cycle(key == Key::Ctrl('n'));
+                        self.replace_keyword(active.start, &word);
+                        completion = Some(active);
+                        continue;
+                    },
+                    Key::Ctrl('y') => continue,
+                    Key::Ctrl('e') => {
+                        self.replace_keyword(active.start, active.typed());
+                        continue;
+                    },
+                    _ => {},
+                }
+            }
+            let trigger = match key {
+                Key::Char(c) => Some(Some(c)),
+                Key::Tab => Some(Some('\t')),
+                Key::Enter | Key::Esc => Some(None),
+                _ => None,
+            };
+            if let Some(trigger) = trigger {
+                if self.expand_abbreviation(trigger) {
+                    replaced.clear();
+                }
+            }
+            match key {
+                Key::Esc | Key::Ctrl('c') => break,
+                Key::Char(c) => self.type_char(c, replace, &mut replaced),
+                Key::Tab => self.type_char('\t', replace, &mut replaced),
+                Key::Enter => {
+                    self.break_line(!replace);
+                    if replace {
+                        replaced.push(Replaced::LineBreak);
+                    }
+                },
+                Key::Backspace | Key::Ctrl('h') if replace => self.replace_backspace(&mut replaced),
+                Key::Backspace | Key::Ctrl('h') => self.insert_backspace(),
+                Key::Ctrl('w') | Key::Ctrl('u') => self.delete_before(key == Key::Ctrl('w'), replace, &mut replaced),
+                Key::Ctrl('t') => {
+                    replaced.clear();
+                    self.shift_indent(true);
+                },
+                Key::Ctrl('d') => {
+                    replaced.clear();
+                    // `0 Ctrl-D` deletes all indent.
+                    if previous == Some(Key::Char('0')) {
+                        self.insert_backspace();
+                        self.shift_indent_to(0);
+                    } else {
+                        self.shift_indent(false);
+                    }
+                },
+                Key::Ctrl('r') => {
+                    if let Some(Key::Char(name)) = self.next_key(0, None) {
+                        if let Some(text) = self.register(name) {
+                            for c in text.chars() {
+                                match c {
+                                    '\n' => {
+                                        self.break_line(false);
+                                        if replace {
+                                            replaced.push(Replaced::LineBreak);
+                                        }
+                                    },
+                                    c => self.type_char(c, replace, &mut replaced),
+                                }
+                            }
+                        }
+                    }
+                },
+                Key::Ctrl('v') | Key::Ctrl('q') => {
+                    if let Some(c) = self.literal_key() {
+                        self.type_char(c, replace, &mut replaced);
+                    }
+                },
+                Key::Ctrl('k') => {
+                    if let Some(c) = self.digraph_key() {
+                        self.type_char(c, replace, &mut replaced);
+                    }
+                },
+                Key::Ctrl('o') => {
+                    replaced.clear();
+                    self.insert_command(replace);
+                    if self.command.is_some() {
+                        break;
+                    }
+                },
+                Key::Ctrl('n') | Key::Ctrl('p') => {
+                    replaced.clear();
+                    completion = self.start_keyword_completion(key == Key::Ctrl('n'));
+                },
+                Key::Left | Key::Right | Key::Up | Key::Down | Key::Home | Key::End => {
+                    replaced.clear();
+                    self.entry_motion(key);
+                },
+                _ => {},
+            }
+            previous = Some(key);
+        }
+        self.mode = Mode::Normal;
+        let col = self.col().saturating_sub(1);
+        self.set_col(col);
+        self.clamp_cursor();
+    }
+
+    /// `Ctrl-W` and `Ctrl-U` while typing: delete the word before the
+    /// cursor, or everything before it back to the indent and then the
+    /// indent.  At the start of a line, join it to the line before.
+    fn delete_before(&mut self, word: bool, replace: bool, replaced: &mut Vec<Replaced>) {
+        let col = self.col();
+        let text = &self.buffer.content[self.line_index()];
+        let target = if col == 0 {
+            0
+        } else if word {
+            let start = edit::word_start(&text[.. edit::byte_offset(text, col)]);
+            edit::char_count(&text[.. start])
+        } else {
+            let indent = edit::first_non_blank(text);
+            if col > indent { indent } else { 0 }
         };
-        for line in range.0 .. (end + 1) {
-            let offset = (line - 1) as usize;
-            let output = format!("{} {}\n", line, &self.buffer.content[offset]);
-            io::stdout().write(output.as_bytes()).ok();
+        for _ in 0 .. (col - target).max(1) {
+            if replace {
+                self.replace_backspace(replaced);
+            } else {
+                self.insert_backspace();
+            }
+        }
+    }
+
+    /// `Ctrl-T` and `Ctrl-D` while typing: indent the line by one more or
+    /// one less `shiftwidth`, rounded to a multiple of it.
+    fn shift_indent(&mut self, more: bool) {
+        let shiftwidth = self.shiftwidth();
+        let tabstop = self.option_number("tabstop").max(1) as usize;
+        let width = indent_width(&self.buffer.content[self.line_index()], tabstop);
+        let width = if more {
+            (width / shiftwidth + 1) * shiftwidth
+        } else if !width.is_multiple_of(shiftwidth) {
+            width - width % shiftwidth
+        } else {
+            width.saturating_sub(shiftwidth)
         };
-        Ok(true)
+        self.shift_indent_to(width);
     }
 
-    fn execute_quit(&self) -> Result<bool, String> {
-        Ok(false)
+    /// Change the indent of the cursor's line to `width` columns, keeping
+    /// the cursor on the same text.
+    fn shift_indent_to(&mut self, width: usize) {
+        let tabstop = self.option_number("tabstop").max(1) as usize;
+        let indent = make_indent(width, tabstop, self.option_bool("expandtab"));
+        let line = self.line_index();
+        let text = &self.buffer.content[line];
+        let old = edit::first_non_blank(text);
+        let shifted = indent.clone() + &text[edit::byte_offset(text, old) ..];
+        if shifted != *text {
+            self.buffer.content[line] = shifted;
+            self.buffer.changed();
+        }
+        let new = edit::char_count(&indent);
+        let col = self.col();
+        self.set_col(if col > old { col - old + new } else { new });
     }
 
-    fn execute_unknown(&self, command: &ex::Command) -> Result<bool, String> {
-        Err(format!("Unknown command {:?}", command))
+    /// `Ctrl-V` while typing: the next key taken literally, or a character
+    /// typed as its code, see `edit::code_radix`.  A key that does not
+    /// continue the code is used as typed after it.
+    fn literal_key(&mut self) -> Option<char> {
+        let first = match self.next_key(0, None)? {
+            Key::Char(c) => c,
+            key => return edit::literal_char(key),
+        };
+        let (radix, max) = match edit::code_radix(first) {
+            Some(code) => code,
+            None => return Some(first),
+        };
+        let mut digits = if radix == 10 { first.to_string() } else { String::new() };
+        while digits.len() < max {
+            match self.next_key(0, None) {
+                Some(Key::Char(c)) if c.is_digit(radix) => digits.push(c),
+                Some(key) => {
+                    self.keys.feed(&[key], true);
+                    break;
+                },
+                None => break,
+            }
+        }
+        if digits.is_empty() {
+            return Some(first);
+        }
+        edit::code_char(&digits, radix)
     }
-}
 
-impl CursorLocator {
-    pub fn new() -> Self {
-        CursorLocator { line: 1, col: 1 }
+    /// `Ctrl-K {char1} {char2}` while typing: the digraph the two
+    /// characters stand for, or the second one if there is none.
+    fn digraph_key(&mut self) -> Option<char> {
+        let first = match self.next_key(0, None)? {
+            Key::Char(c) => c,
+            _ => return None,
+        };
+        match self.next_key(0, None)? {
+            Key::Char(second) => Some(digraph::lookup(first, second).unwrap_or(second)),
+            _ => None,
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use ex;
-    use super::*;
-    use display;
+    /// `Ctrl-O` while typing: run one Normal mode command, or an ex command
+    /// after `:`, and go back to typing.  The cursor stays past the end of
+    /// the line if it was there and the command did not move it.
+    fn insert_command(&mut self, replace: bool) {
+        let col = self.col();
+        let at_end = col > 0 && col >= edit::char_count(&self.buffer.content[self.line_index()]);
+        self.mode = Mode::Normal;
+        self.clamp_cursor();
+        let before = (self.cursor.line, self.cursor.col);
+        self.redraw();
+        self.io.set_status(if replace { "-- (replace) --" } else { "-- (insert) --" });
+        let command = loop {
+            let key = match self.next_key(self.key_mode(), None) {
+                Some(key) => key,
+                None => break None,
+            };
+            if key == Key::Char(':') && self.pending.is_none() {
+                self.count = None;
+                break self.read_cmdline(':', "");
+            }
+            self.normal_key(key);
+            if self.pending.is_none() && self.count.is_none() {
+                break match (self.command.take(), self.cmdline.take()) {
+                    (Some(command), _) => Some(command),
+                    (None, Some(initial)) => self.read_cmdline(':', &initial),
+                    (None, None) => None,
+                };
+            }
+        };
+        if let Some(command) = command {
+            match self.run(&command) {
+                Ok(true) => {},
+                Ok(false) => self.command = Some(command),
+                Err(err) => self.message(err),
+            }
+        }
+        self.mode = if replace { Mode::Replace } else { Mode::Insert };
+        self.ensure_line();
+        self.clamp_cursor();
+        if at_end && (self.cursor.line, self.cursor.col) == before {
+            self.set_col(col);
+        }
+    }
 
-    #[test]
-    fn execute_yank() {
-        let mut io = display::IO::new().unwrap();
-        let mut engine = Engine::new(&mut io);
-        engine.buffer.content.push("First line.".to_string());
-        let cmd = ex::Command {
-            string: "1y".to_string(),
-            action: ex::Action::Yank,
-            selector: ex::Selector { start: ex::Locator::Line(1), end: None },
+    /// `Ctrl-N` and `Ctrl-P` while typing: complete the keyword before the
+    /// cursor with the next or previous word in the buffer that starts
+    /// with it, searching from the cursor.
+    fn start_keyword_completion(&mut self, forward: bool) -> Option<Completion> {
+        let line = self.line_index();
+        let text = &self.buffer.content[line];
+        let cursor = edit::byte_offset(text, self.col());
+        let start = text[.. cursor].char_indices().rev()
+            .take_while(|&(_, c)| { abbrev::is_keyword(c) })
+            .last()
+            .map(|(i, _)| { i })
+            .unwrap_or(cursor);
+        let typed = text[start .. cursor].to_string();
+        let matches = complete::keywords(&self.buffer.content, line, start, &typed);
+        if matches.is_empty() {
+            self.message("-- Keyword completion (^N^P) Pattern not found".to_string());
+            return None;
+        }
+        let mut completion = Completion::new(start, &typed, matches);
+        let (word, _) = completion.cycle(forward);
+        self.replace_keyword(start, &word);
+        Some(completion)
+    }
+
+    /// Replace the text from byte `start` of the cursor's line up to the
+    /// cursor with `word`.
+    fn replace_keyword(&mut self, start: usize, word: &str) {
+        let line = self.line_index();
+        let cursor = edit::byte_offset(&self.buffer.content[line], self.col());
+        self.buffer.content[line].replace_range(start .. cursor, word);
+        self.buffer.changed();
+        let col = edit::char_count(&self.buffer.content[line][.. start]) + edit::char_count(word);
+        self.set_col(col);
+    }
+
+    /// Show the keyword completion matches in a popup menu at the word
+    /// being completed.
+    fn show_keyword_menu(&self, completion: &Completion) {
+        let text_x = 1 + Gutter::new(self, self.buffer.content.len()).width;
+        let text = &self.buffer.content[self.line_index()];
+        let row = (self.cursor.line as usize).saturating_sub(self.buffer.top_visible);
+        let col = text_x + edit::char_count(&text[.. completion.start]) - 1;
+        self.io.show_popup_menu(&completion.matches, completion.selected, row, col);
+    }
+
+    /// Expand an Insert mode abbreviation before the cursor if `trigger`
+    /// ends it.  Returns true if one was expanded.
+    fn expand_abbreviation(&mut self, trigger: Option<char>) -> bool {
+        let line = self.line_index();
+        let text = &self.buffer.content[line];
+        let cursor = edit::byte_offset(text, self.col());
+        let (start, rhs) = match self.abbreviations.expand(&text[.. cursor], mapping::INSERT, trigger) {
+            Some(found) => found,
+            None => return false,
         };
-        match engine.execute(&cmd) {
-            Ok(_) => assert_eq!(&engine.clipboard, "First line.\n"),
-            Err(e) => panic!(e),
+        let col = edit::char_count(&text[.. start]) + edit::char_count(&rhs);
+        self.buffer.content[line].replace_range(start .. cursor, &rhs);
+        self.buffer.changed();
+        self.set_col(col);
+        true
+    }
+
+    fn type_char(&mut self, c: char, replace: bool, replaced: &mut Vec<Replaced>) {
+        let col = self.col();
+        let line = self.line_index();
+        let text = &mut self.buffer.content[line];
+        let at = edit::byte_offset(text, col);
+        match text[at ..].chars().next() {
+            Some(old) if replace => {
+                text.replace_range(at .. at + old.len_utf8(), c.encode_utf8(&mut [0; 4]));
+                replaced.push(Replaced::Char(old));
+            },
+            _ => {
+                text.insert(at, c);
+                if replace {
+                    replaced.push(Replaced::Appended);
+                }
+            },
+        }
+        self.buffer.changed();
+        self.set_col(col + 1);
+    }
+
+    /// Split the line at the cursor, indenting the new line like the old
+    /// one with `autoindent` if `indent`.
+    fn break_line(&mut self, indent: bool) {
+        let line = self.line_index();
+        let at = edit::byte_offset(&self.buffer.content[line], self.col());
+        let rest = self.buffer.content[line].split_off(at);
+        let indent = if indent && self.option_bool("autoindent") {
+            let text = &self.buffer.content[line];
+            text[.. edit::byte_offset(text, edit::first_non_blank(text))].to_string()
+        } else {
+            String::new()
         };
+        let col = edit::char_count(&indent);
+        self.buffer.content.insert(line + 1, indent + &rest);
+        self.buffer.changed();
+        self.cursor.line += 1;
+        self.set_col(col);
+    }
+
+    /// Join the cursor's line onto the one before, leaving the cursor
+    /// where they meet.
+    fn join_previous(&mut self) {
+        let line = self.line_index();
+        if line == 0 {
+            return;
+        }
+        let text = self.buffer.content.remove(line);
+        let col = edit::char_count(&self.buffer.content[line - 1]);
+        self.buffer.content[line - 1].push_str(&text);
+        self.buffer.changed();
+        self.cursor.line -= 1;
+        self.set_col(col);
+    }
+
+    fn insert_backspace(&mut self) {
+        let col = self.col();
+        if col == 0 {
+            self.join_previous();
+            return;
+        }
+        let line = self.line_index();
+        let text = &mut self.buffer.content[line];
+        text.remove(edit::byte_offset(text, col - 1));
+        self.buffer.changed();
+        self.set_col(col - 1);
+    }
+
+    /// Backspace in Replace mode: undo the last key typed, or just move
+    /// left over text that was there before.
+    fn replace_backspace(&mut self, replaced: &mut Vec<Replaced>) {
+        let col = self.col();
+        let line = self.line_index();
+        match replaced.pop() {
+            Some(Replaced::LineBreak) => self.join_previous(),
+            // Nothing typed on this line is left to take back.
+            Some(_) if col == 0 => {},
+            Some(Replaced::Char(old)) => {
+                let text = &mut self.buffer.content[line];
+                let at = edit::byte_offset(text, col - 1);
+                let typed = text[at ..].chars().next().map(char::len_utf8).unwrap_or(0);
+                text.replace_range(at .. at + typed, old.encode_utf8(&mut [0; 4]));
+                self.buffer.changed();
+                self.set_col(col - 1);
+            },
+            Some(Replaced::Appended) => {
+                let text = &mut self.buffer.content[line];
+                text.remove(edit::byte_offset(text, col - 1));
+                self.buffer.changed();
+                self.set_col(col - 1);
+            },
+            None => self.set_col(col.saturating_sub(1)),
+        }
+    }
+
+    /// Move the cursor with the arrow, Home and End keys while typing,
+    /// which may put it just past the end of a line.
+    fn entry_motion(&mut self, key: Key) {
+        let len = self.buffer.content.len() as u64;
+        match key {
+            Key::Left => {
+                let col = self.col().saturating_sub(1);
+                self.set_col(col);
+            },
+            Key::Right => {
+                let col = self.col() + 1;
+                self.set_col(col);
+            },
+            Key::Up if self.cursor.line > 1 => self.cursor.line -= 1,
+            Key::Down if self.cursor.line < len => self.cursor.line += 1,
+            Key::Home => self.set_col(0),
+            Key::End => self.set_col(usize::MAX - 1),
+            _ => {},
+        }
+        let end = edit::char_count(&self.buffer.content[self.line_index()]);
+        let col = self.col().min(end);
+        self.set_col(col);
+    }
+
+    /// `r{char}`: replace `count` characters with `key`, or with a line
+    /// break for Enter.  Nothing changes if there are too few characters.
+    fn replace_chars(&mut self, key: Key, count: usize) {
+        let line = self.line_index();
+        let col = self.col();
+        let text = match self.buffer.content.get(line) {
+            Some(text) if col.saturating_add(count) <= edit::char_count(text) => text.clone(),
+            _ => return,
+        };
+        let (start, end) = (edit::byte_offset(&text, col), edit::byte_offset(&text, col + count));
+        match key {
+            Key::Char(c) => {
+                let replacement: String = (0 .. count).map(|_| { c }).collect();
+                self.buffer.content[line].replace_range(start .. end, &replacement);
+                self.set_col(col + count - 1);
+            },
+            Key::Tab => {
+                self.buffer.content[line].replace_range(start .. end, &"\t".repeat(count));
+                self.set_col(col + count - 1);
+            },
+            Key::Enter => {
+                self.buffer.content[line].replace_range(start .. end, "");
+                self.break_line(true);
+            },
+            _ => return,
+        }
+        self.buffer.changed();
+    }
+
+    /// `x` and `X`: delete `count` characters from the cursor, or before
+    /// it if `before`, into the clipboard.
+    fn delete_chars(&mut self, count: usize, before: bool) {
+        let line = self.line_index();
+        let col = self.col();
+        let text = match self.buffer.content.get_mut(line) {
+            Some(text) => text,
+            None => return,
+        };
+        let len = edit::char_count(text);
+        let (from, to) = if before {
+            (col.saturating_sub(count), col.min(len))
+        } else {
+            (col, col.saturating_add(count).min(len))
+        };
+        if from >= to {
+            return;
+        }
+        let (start, end) = (edit::byte_offset(text, from), edit::byte_offset(text, to));
+        self.clipboard = text[start .. end].to_string();
+        text.replace_range(start .. end, "");
+        self.buffer.changed();
+        self.set_col(from);
+    }
+
+    /// `D`: delete from the cursor to the end of the line and `count` - 1
+    /// more lines into the clipboard.
+    fn delete_to_end(&mut self, count: usize) {
+        let line = self.line_index();
+        if line >= self.buffer.content.len() {
+            return;
+        }
+        let start = edit::byte_offset(&self.buffer.content[line], self.col());
+        let last = line.saturating_add(count - 1).min(self.buffer.content.len() - 1);
+        let mut deleted = self.buffer.content[line].split_off(start);
+        for text in self.buffer.content.drain(line + 1 ..= last) {
+            deleted.push('\n');
+            deleted.push_str(&text);
+        }
+        if !deleted.is_empty() {
+            self.clipboard = deleted;
+            self.buffer.changed();
+        }
+    }
+
+    /// `S`: replace `count` lines with an empty one, keeping the indent
+    /// with `autoindent`, and start Insert mode.
+    fn substitute_lines(&mut self, count: usize) {
+        self.ensure_line();
+        let line = self.line_index();
+        let last = line.saturating_add(count).min(self.buffer.content.len());
+        let mut lines: Vec<String> = self.buffer.content.drain(line + 1 .. last).collect();
+        let first = mem::take(&mut self.buffer.content[line]);
+        let indent = if self.option_bool("autoindent") {
+            first[.. edit::byte_offset(&first, edit::first_non_blank(&first))].to_string()
+        } else {
+            String::new()
+        };
+        lines.insert(0, first);
+        self.clipboard = lines.join("\n") + "\n";
+        self.set_col(edit::char_count(&indent));
+        self.buffer.content[line] = indent;
+        self.buffer.changed();
+        self.text_entry(false);
+    }
+
+    /// `~`: switch the case of `count` characters and move past them.
+    fn toggle_case(&mut self, count: usize) {
+        let line = self.line_index();
+        let (text, end) = match self.buffer.content.get(line) {
+            Some(text) => edit::toggle_case(text, self.col(), count),
+            None => return,
+        };
+        if text != self.buffer.content[line] {
+            self.buffer.content[line] = text;
+            self.buffer.changed();
+        }
+        self.set_col(end);
+    }
+
+    /// `J` and `gJ`: join `count` lines, at least two, with the cursor
+    /// where the last two met.
+    fn join_lines(&mut self, count: usize, raw: bool) {
+        let line = self.line_index();
+        let mut col = None;
+        for _ in 1 .. count.max(2) {
+            if line + 1 >= self.buffer.content.len() {
+                break;
+            }
+            col = Some(edit::char_count(&self.buffer.content[line]));
+            let number = line as u64 + 1;
+            if self.execute_join((number, Some(number + 1)), raw, None).is_err() {
+                break;
+            }
+        }
+        if let Some(col) = col {
+            self.cursor.line = line as u64 + 1;
+            self.set_col(col);
+        }
+    }
+
+    /// `p`, `P`, `gp` and `gP`: put the clipboard `count` times after or
+    /// before the cursor.  Text ending in a line break is put as lines
+    /// below or above the cursor's line.  With `after_text` the cursor
+    /// ends just after the new text, otherwise on its start or, for text
+    /// within a line, its last character.
+    fn put(&mut self, count: usize, after: bool, after_text: bool) {
+        if self.clipboard.len().checked_mul(count).is_none() {
+            return;
+        }
+        let text = self.clipboard.repeat(count);
+        if text.is_empty() {
+            return;
+        }
+        if text.ends_with('\n') {
+            let lines: Vec<String> = text[.. text.len() - 1].split('\n').map(String::from).collect();
+            let at = match self.buffer.content.len() {
+                0 => 0,
+                _ if after => self.line_index() + 1,
+                _ => self.line_index(),
+            };
+            let added = lines.len();
+            self.buffer.content.splice(at .. at, lines);
+            self.buffer.changed();
+            if after_text {
+                self.cursor = CursorLocator { line: (at + added + 1) as u64, col: 1 };
+            } else {
+                let col = edit::first_non_blank(&self.buffer.content[at]);
+                self.cursor.line = at as u64 + 1;
+                self.set_col(col);
+            }
+            return;
+        }
+        self.ensure_line();
+        let line = self.line_index();
+        let current = self.buffer.content[line].clone();
+        let col = if after && !current.is_empty() { self.col() + 1 } else { self.col() };
+        let at = edit::byte_offset(&current, col);
+        let combined = format!("{}{}{}", &current[.. at], text, &current[at ..]);
+        let lines: Vec<String> = combined.split('\n').map(String::from).collect();
+        self.buffer.content.splice(line .. line + 1, lines);
+        self.buffer.changed();
+        let end = match text.rfind('\n') {
+            Some(i) => {
+                self.cursor.line += text.matches('\n').count() as u64;
+                edit::char_count(&text[i + 1 ..])
+            },
+            None => col + edit::char_count(&text),
+        };
+        match (after_text, text.contains('\n')) {
+            (true, _) => self.set_col(end),
+            (false, false) => self.set_col(end - 1),
+            (false, true) => self.cursor.line = line as u64 + 1,
+        }
+    }
+
+    /// `Ctrl-A` and `Ctrl-X`: add `delta` to the number under or after
+    /// the cursor.
+    fn increment(&mut self, delta: i64) {
+        let line = self.line_index();
+        let found = self.buffer.content.get(line).and_then(|text| { edit::increment(text, self.col(), delta) });
+        if let Some((text, last)) = found {
+            self.buffer.content[line] = text;
+            self.buffer.changed();
+            self.set_col(last);
+        }
+    }
+
+    /// Parse and execute an ex command line, which may hold several
+    /// commands separated by `|`.  Stops at the first command that fails.
+    pub fn run(&mut self, command_string: &str) -> Result<bool, String> {
+        let commands = parser::parse_line(command_string).map_err(|err| { err.to_string() })?;
+        for command in commands.iter() {
+            if !self.execute(command)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Run each line of `text` as an ex command, skipping blank lines and
+    /// `"` comments.  Errors are collected and reported with `name` and the
+    /// line number they came from.
+    pub fn run_lines(&mut self, name: &str, text: &str) -> Result<bool, String> {
+        if self.sourcing >= MAX_SOURCING {
+            return Err("E169: Command too recursive".to_string());
+        }
+        self.sourcing += 1;
+        let result = self.run_each_line(name, text);
+        self.sourcing -= 1;
+        result
+    }
+
+    fn run_each_line(&mut self, name: &str, text: &str) -> Result<bool, String> {
+        let mut errors = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_start().trim_start_matches(':');
+            if line.is_empty() || line.starts_with('"') {
+                continue;
+            }
+            match self.run(line) {
+                Ok(true) => {},
+                Ok(false) => return Ok(false),
+                Err(err) => errors.push(format!("{} line {}: {}", name, number + 1, err)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(true)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// Run the commands from a startup source.
+    pub fn run_source(&mut self, source: &config::Source) -> Result<bool, String> {
+        match *source {
+            config::Source::Exinit(ref commands) => self.run_lines("EXINIT", commands),
+            config::Source::File(ref path) => self.execute_source(&path.to_string_lossy()),
+        }
+    }
+
+    /// The effective value of an option, preferring a buffer- or
+    /// window-local value over the global one.
+    pub fn option(&self, name: &str) -> Value {
+        let def = options::lookup(name).expect("unknown option");
+        let local = match def.scope {
+            options::Scope::Buffer => self.buffer.options.get(def.name),
+            options::Scope::Window => self.window_options.get(def.name),
+            options::Scope::Global => None,
+        };
+        match local.or_else(|| { self.options.get(def.name) }) {
+            Some(value) => value.clone(),
+            None => def.default_value(),
+        }
+    }
+
+    pub fn option_bool(&self, name: &str) -> bool {
+        self.option(name) == Value::Bool(true)
+    }
+
+    pub fn option_number(&self, name: &str) -> i64 {
+        match self.option(name) {
+            Value::Number(n) => n,
+            _ => 0,
+        }
+    }
+
+    pub fn option_str(&self, name: &str) -> String {
+        match self.option(name) {
+            Value::Str(s) => s,
+            _ => String::new(),
+        }
+    }
+
+    /// Set an option.  `:set` changes both the global and the local value,
+    /// `:setlocal` only the local one.
+    pub fn set_option(&mut self, def: &options::OptionDef, value: Value, local: bool) {
+        if !local || def.scope == options::Scope::Global {
+            self.options.set(def.name, value.clone());
+        }
+        match def.scope {
+            options::Scope::Buffer => self.buffer.options.set(def.name, value),
+            options::Scope::Window => self.window_options.set(def.name, value),
+            options::Scope::Global => {},
+        }
+    }
+
+    pub fn execute(&mut self, command: &ex::Command) -> Result<bool, String> {
+        if self.restricted && command.action.is_restricted() {
+            return Err("E523: Not allowed here".to_string());
+        }
+        let range = self.get_selection(&command.selector)?;
+        match command.action {
+            ex::Action::Edit(bang, ref filename) => self.execute_edit(bang, filename),
+            ex::Action::Write(bang, ref target) => self.execute_write(range, bang, target),
+            ex::Action::Update(bang, ref target) => self.execute_update(range, bang, target),
+            ex::Action::WriteNext(bang, ref target) => self.execute_write_next(range, bang, target, 1),
+            ex::Action::WritePrevious(bang, ref target) => self.execute_write_next(range, bang, target, -1),
+            ex::Action::SaveAs(bang, ref arg) => self.execute_saveas(bang, arg),
+            ex::Action::Read(ref source) => self.execute_read(range, source),
+            ex::Action::Shell(ref command) => self.execute_shell(command),
+            ex::Action::Filter(ref command) => self.execute_filter(range, command),
+            ex::Action::CheckTime => self.checktime(),
+            ex::Action::Move(ref address) => self.execute_move(range, address),
+            ex::Action::Copy(ref address) => self.execute_copy(range, address),
+            ex::Action::Join(bang, count) => self.execute_join(range, bang, count),
+            ex::Action::Shift(amount, count) => self.execute_shift(range, amount, count),
+            ex::Action::Sort(reverse, ref args) => self.execute_sort(range, reverse, args),
+            ex::Action::Go => self.execute_go(range),
+            ex::Action::Yank => self.execute_yank(range),
+            ex::Action::Print => self.execute_print(range, false, false),
+            ex::Action::Number => self.execute_print(range, true, false),
+            ex::Action::List => self.execute_print(range, false, true),
+            ex::Action::LineNumber => self.execute_line_number(range),
+            ex::Action::Z(kind, count) => self.execute_z(range, kind, count),
+            ex::Action::Quit(bang) => self.execute_quit(bang),
+            ex::Action::Set(ref args) => self.execute_set(args, false),
+            ex::Action::SetLocal(ref args) => self.execute_set(args, true),
+            ex::Action::Source(ref filename) => self.execute_source(filename),
+            ex::Action::Abbreviate(modes, ref args) => self.execute_abbreviate(modes, args),
+            ex::Action::Unabbreviate(modes, ref lhs) => {
+                if !self.abbreviations.remove(lhs, mapping::modes(modes)) {
+                    return Err("E24: No such abbreviation".to_string());
+                }
+                Ok(true)
+            },
+            ex::Action::AbbreviateClear(modes) => {
+                self.abbreviations.clear(mapping::modes(modes));
+                Ok(true)
+            },
+            ex::Action::Map(modes, noremap, ref args) => self.execute_map(modes, noremap, args),
+            ex::Action::Unmap(modes, ref lhs) => self.execute_unmap(modes, lhs),
+            ex::Action::MapClear(modes) => {
+                self.mappings.clear(mapping::modes(modes));
+                Ok(true)
+            },
+            _ => self.execute_unknown(command)
+        }
+    }
+
+    fn get_selection(&self, selector: &ex::Selector) -> Result<(u64, Option<u64>), String> {
+        let start = match selector.start {
+            ex::Locator::All => 1,
+            ref locator => self.address(locator)?,
+        };
+        let end = match selector.end {
+            Some(ref locator) => Some(self.address(locator)?),
+            None => match selector.start {
+                ex::Locator::All => Some(self.buffer.content.len() as u64),
+                _ => None,
+            }
+        };
+        Ok((start, end))
+    }
+
+    /// The line an address stands for; `%` stands for the last line.
+    fn address(&self, locator: &ex::Locator) -> Result<u64, String> {
+        match *locator {
+            ex::Locator::Here => Ok(self.cursor.line),
+            ex::Locator::All | ex::Locator::Last => Ok(self.buffer.content.len() as u64),
+            ex::Locator::Line(x) => Ok(x),
+            ex::Locator::Ahead(offset) => self.ahead(offset),
+            ex::Locator::Back(offset) => self.back(offset),
+            ex::Locator::Search(ref pattern) => self.search_forward(pattern),
+            ex::Locator::Offset(ref base, offset) => {
+                let line = self.address(base)?;
+                let line = if offset < 0 { line.checked_sub(offset.unsigned_abs()) } else { line.checked_add(offset as u64) };
+                line.ok_or_else(|| { "E16: Invalid range".to_string() })
+            },
+        }
+    }
+
+    /// The line `offset` lines below the cursor.
+    fn ahead(&self, offset: u64) -> Result<u64, String> {
+        self.cursor.line.checked_add(offset).ok_or_else(|| { "E16: Invalid range".to_string() })
+    }
+
+    /// The line `offset` lines above the cursor.
+    fn back(&self, offset: u64) -> Result<u64, String> {
+        self.cursor.line.checked_sub(offset).ok_or_else(|| { "E16: Invalid range".to_string() })
+    }
+
+    /// The line number of a single address, such as the target of `:m`.
+    fn locate(&self, locator: &ex::Locator) -> Result<u64, String> {
+        let line = self.address(locator)?;
+        if line > self.buffer.content.len() as u64 {
+            return Err("E16: Invalid range".to_string());
+        }
+        Ok(line)
+    }
+
+    /// The lines a command with a trailing count acts on: `count` lines
+    /// starting with the last line of `range`.
+    fn counted_range(range: (u64, Option<u64>), count: Option<u64>) -> (u64, Option<u64>) {
+        match count {
+            Some(count) => {
+                let last = range.1.unwrap_or(range.0);
+                (last, Some(last.saturating_add(count.saturating_sub(1))))
+            },
+            None => range,
+        }
+    }
+
+    /// Find the next line after the cursor containing `pattern`, wrapping
+    /// around the end of the buffer.
+    fn search_forward(&self, pattern: &str) -> Result<u64, String> {
+        let len = self.buffer.content.len() as u64;
+        for step in 1 .. (len + 1) {
+            let line = (self.cursor.line - 1 + step) % len + 1;
+            if self.buffer.content[(line - 1) as usize].contains(pattern) {
+                return Ok(line);
+            }
+        }
+        Err(format!("Pattern not found: {}", pattern))
+    }
+
+
+    /// Replace the current buffer with `filename`, decoded with `encoding`
+    /// if given or the `fileencodings` list otherwise.  If another session
+    /// left a swap file for it the user is asked what to do, and may choose
+    /// to quit, in which case this returns `Ok(false)`.
+    pub fn open(&mut self, filename: &str, encoding: Option<&str>) -> Result<bool, String> {
+        self.load(filename, encoding)?;
+        self.attach_swap(None)
+    }
+
+    /// Open `filename` and restore its unsaved changes from a swap file.
+    pub fn recover(&mut self, filename: &str) -> Result<bool, String> {
+        self.load(filename, None)?;
+        self.attach_swap(Some(swap::Choice::Recover))
+    }
+
+    fn load(&mut self, filename: &str, encoding: Option<&str>) -> Result<(), String> {
+        let fileencodings = match encoding {
+            Some(encoding) => encoding.to_string(),
+            None => self.option_str("fileencodings"),
+        };
+        match buffer::Buffer::open_with(filename, &fileencodings) {
+            Ok(buffer) => {
+                self.close_swap();
+                if buffer.filename != self.buffer.filename {
+                    self.alternate = self.buffer.filename.take();
+                }
+                self.buffer = buffer;
+                self.cursor = CursorLocator::new();
+                self.watch_buffer();
+                Ok(())
+            },
+            Err(err) => Err(format!("Could not open {}: {}", filename, err))
+        }
+    }
+
+    fn attach_swap(&mut self, choice: Option<swap::Choice>) -> Result<bool, String> {
+        let filename = match self.buffer.filename {
+            Some(ref filename) if self.option_bool("swapfile") && !self.option_bool("readonly") => filename.clone(),
+            _ => return Ok(true),
+        };
+        match swap::find_existing(Path::new(&filename), &self.option_str("directory")) {
+            Some(journal) => {
+                let choice = match choice {
+                    Some(choice) => choice,
+                    None => self.prompt(&swap::attention(&journal), "oerdq")
+                        .and_then(swap::Choice::from_key)
+                        .unwrap_or(swap::Choice::OpenReadOnly),
+                };
+                self.apply_swap_choice(choice, journal)
+            },
+            None if choice == Some(swap::Choice::Recover) => Err(format!("E305: No swap file found for {}", filename)),
+            None => self.create_swap(),
+        }
+    }
+
+    /// Act on the user's answer to finding `journal` for the current buffer.
+    pub fn apply_swap_choice(&mut self, choice: swap::Choice, journal: swap::Journal) -> Result<bool, String> {
+        match choice {
+            swap::Choice::OpenReadOnly => {
+                self.buffer.options.set("readonly", Value::Bool(true));
+                Ok(true)
+            },
+            swap::Choice::EditAnyway => self.create_swap(),
+            swap::Choice::Recover | swap::Choice::Delete if journal.swap.in_use() => {
+                self.buffer.options.set("readonly", Value::Bool(true));
+                Err(format!("E325: Swap file \"{}\" is in use by process {}, opening read-only",
+                            journal.swap.path.display(), journal.swap.pid))
+            },
+            swap::Choice::Recover => {
+                self.buffer.content = journal.content;
+                self.buffer.changed();
+                journal.swap.remove().ok();
+                self.create_swap()
+            },
+            swap::Choice::Delete => {
+                journal.swap.remove().map_err(|err| { format!("Could not delete {}: {}", journal.swap.path.display(), err) })?;
+                self.create_swap()
+            },
+            swap::Choice::Quit => Ok(false),
+        }
+    }
+
+    fn create_swap(&mut self) -> Result<bool, String> {
+        if let Some(ref filename) = self.buffer.filename {
+            let directory = self.option_str("directory");
+            match swap::create(Path::new(filename), &directory, &self.buffer.content, self.buffer.changedtick) {
+                Ok(swap) => self.swap = Some(swap),
+                Err(err) => return Err(format!("E303: Unable to open swap file for {}, recovery impossible: {}", filename, err)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Journal the buffer to its swap file once `updatecount` changes were
+    /// made since the last write, or once no key was typed for
+    /// `updatetime` milliseconds.  An `updatecount` of zero leaves only the
+    /// latter.  Called whenever the editor is about to wait for input.
+    pub fn sync_swap(&mut self) -> Result<(), String> {
+        let unsaved = match self.swap {
+            Some(ref swap) => self.buffer.changedtick.saturating_sub(swap.tick),
+            None => return Ok(()),
+        };
+        let count = self.option_number("updatecount");
+        let idle = self.typed_at.elapsed() >= Duration::from_millis(self.option_number("updatetime").max(0) as u64);
+        if unsaved == 0 || !(idle || (count > 0 && unsaved >= count as u64)) {
+            return Ok(());
+        }
+        self.flush_swap()
+    }
+
+    /// Journal the buffer to its swap file now if it changed since the
+    /// last write.
+    pub fn flush_swap(&mut self) -> Result<(), String> {
+        let tick = self.buffer.changedtick;
+        match self.swap {
+            Some(ref mut swap) if swap.tick != tick => {
+                swap.write(&self.buffer.content, tick).map_err(|err| { format!("E297: Write error in swap file: {}", err) })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the swap file for the current buffer.
+    pub fn close_swap(&mut self) {
+        if let Some(swap) = self.swap.take() {
+            swap.remove().ok();
+        }
+    }
+
+    fn watch_buffer(&mut self) {
+        if let (Some(watcher), Some(filename)) = (self.watcher.as_mut(), self.buffer.filename.as_ref()) {
+            watcher.watch(Path::new(filename)).ok();
+        }
+    }
+
+    /// Whether the current file may have been changed by another program.
+    /// Without inotify every call checks the file itself.
+    pub fn file_events(&mut self) -> bool {
+        match self.watcher {
+            Some(ref mut watcher) => watcher.changed(),
+            None => self.buffer.changed_on_disk(),
+        }
+    }
+
+    /// Called when no key was typed for `IDLE`: reload the file or ask
+    /// about it if another program changed it.
+    pub fn idle(&mut self) {
+        if self.file_events() {
+            match self.checktime() {
+                Ok(_) => self.redraw(),
+                Err(string) => self.io.set_status(&string),
+            }
+        }
+    }
+
+    /// `:checktime`: if the file changed on disk, reload it when `autoread`
+    /// is set and the buffer has no changes of its own, or ask otherwise.
+    pub fn checktime(&mut self) -> Result<bool, String> {
+        if !self.buffer.changed_on_disk() {
+            return Ok(true);
+        }
+        let filename = self.buffer.filename.clone().unwrap_or_default();
+        let stat = match buffer::FileStat::of(Path::new(&filename)) {
+            Some(stat) => stat,
+            None => {
+                self.buffer.stat = None;
+                return Err(format!("E211: File \"{}\" no longer available", filename));
+            },
+        };
+        let reload = if self.buffer.modified() {
+            let message = format!("W12: Warning: File \"{}\" has changed and the buffer was changed in virs as well. [O]K, (L)oad File:", filename);
+            self.prompt(&message, "ol") == Some('l')
+        } else if self.option_bool("autoread") {
+            true
+        } else {
+            let message = format!("W11: Warning: File \"{}\" has changed since editing started. [O]K, (L)oad File:", filename);
+            self.prompt(&message, "ol") == Some('l')
+        };
+        if reload {
+            self.reload()?;
+            self.io.set_status(&format!("\"{}\" reloaded", filename));
+        } else {
+            self.buffer.stat = Some(stat);
+        }
+        Ok(true)
+    }
+
+    /// Read the current file again, keeping the cursor and swap file.
+    fn reload(&mut self) -> Result<(), String> {
+        let filename = self.buffer.filename.clone().unwrap_or_default();
+        let mut buffer = buffer::Buffer::open_with(&filename, &self.option_str("fileencodings"))
+            .map_err(|err| { format!("Could not open {}: {}", filename, err) })?;
+        buffer.top_visible = self.buffer.top_visible;
+        // Count the reload as a change so the swap file is rewritten.
+        buffer.changedtick = self.buffer.changedtick + 1;
+        buffer.savedtick = buffer.changedtick;
+        self.buffer = buffer;
+        let len = self.buffer.content.len() as u64;
+        if self.cursor.line > len {
+            self.cursor.line = if len > 0 { len } else { 1 };
+        }
+        Ok(())
+    }
+
+    /// `:e`: edit another file, or the current one again when no name is
+    /// given.  Unsaved changes are only discarded with `!`.
+    fn execute_edit(&mut self, bang: bool, arg: &str) -> Result<bool, String> {
+        let (file_options, filename) = parser::parse_file_options(arg)?;
+        if !bang && self.buffer.modified() {
+            return Err("E37: No write since last change (add ! to override)".to_string());
+        }
+        let filename = match (filename, self.buffer.filename.clone()) {
+            ("", Some(current)) => current,
+            ("", None) => return Err("E32: No file name".to_string()),
+            (filename, _) => filename.to_string(),
+        };
+        if !self.open(&filename, file_options.encoding.as_deref())? {
+            return Ok(false);
+        }
+        self.redraw();
+        self.io.set_status(&self.file_message());
+        Ok(true)
+    }
+
+    /// Describe the current file the way it is announced after `:e`.
+    pub fn file_message(&self) -> String {
+        let name = self.buffer.filename.as_deref().unwrap_or("[No Name]");
+        if self.buffer.new_file {
+            format!("\"{}\" [New File]", name)
+        } else {
+            let readonly = if self.option_bool("readonly") { " [readonly]" } else { "" };
+            format!("\"{}\"{} {}L", name, readonly, self.buffer.content.len())
+        }
+    }
+
+    /// The `Ctrl-G` message: the file name and state, and where the cursor
+    /// is in the file.
+    pub fn file_info(&self) -> String {
+        let name = self.buffer.filename.as_deref().unwrap_or("[No Name]");
+        let mut flags = String::new();
+        if self.buffer.modified() {
+            flags.push_str(" [Modified]");
+        }
+        if self.option_bool("readonly") {
+            flags.push_str(" [readonly]");
+        }
+        if self.buffer.new_file {
+            flags.push_str(" [New]");
+        }
+        let len = self.buffer.content.len() as u64;
+        if len == 0 {
+            return format!("\"{}\"{} --No lines in buffer--", name, flags);
+        }
+        let line = self.cursor.line.max(1).min(len);
+        format!("\"{}\"{} line {} of {} --{}%-- col {}", name, flags, line, len, line * 100 / len, self.cursor.col)
+    }
+
+    /// The `g Ctrl-G` message: the cursor position counted in columns,
+    /// lines, words and bytes.
+    pub fn cursor_info(&self) -> String {
+        let content = &self.buffer.content;
+        if content.is_empty() {
+            return "--No lines in buffer--".to_string();
+        }
+        let cursor_line = (self.cursor.line.max(1) as usize - 1).min(content.len() - 1);
+        let col = self.cursor.col.max(1) as usize;
+        let (mut words, mut word_at, mut bytes, mut byte_at) = (0, 0, 0, 0);
+        for (i, line) in content.iter().enumerate() {
+            if i == cursor_line {
+                let before = word_starts(line).filter(|&start| { start < col }).count();
+                word_at = words + before;
+                byte_at = bytes + col.min(line.len().max(1));
+            }
+            words += word_starts(line).count();
+            bytes += line.len() + 1;
+        }
+        format!(
+            "Col {} of {}; Line {} of {}; Word {} of {}; Byte {} of {}",
+            col, content[cursor_line].len(), cursor_line + 1, content.len(), word_at, words, byte_at, bytes
+        )
+    }
+
+    /// Lines `start .. end`, counted from 0, addressed by `range`.
+    fn line_span(&self, range: (u64, Option<u64>)) -> Result<(usize, usize), String> {
+        let len = self.buffer.content.len() as u64;
+        let (start, end) = (range.0, range.1.unwrap_or(range.0));
+        if len > 0 && (start == 0 || start > end || end > len) {
+            return Err("E16: Invalid range".to_string());
+        }
+        Ok((start.saturating_sub(1) as usize, if end < len { end as usize } else { len as usize }))
+    }
+
+    fn execute_write(&mut self, range: (u64, Option<u64>), bang: bool, target: &ex::WriteTarget) -> Result<bool, String> {
+        let (start, end) = self.line_span(range)?;
+        match *target {
+            ex::WriteTarget::File(ref arg) => self.write_file(start, end, bang, arg, false),
+            ex::WriteTarget::Append(ref arg) => self.write_file(start, end, bang, arg, true),
+            ex::WriteTarget::Command(ref command) => self.write_command(start, end, command),
+        }
+    }
+
+    fn write_file(&mut self, start: usize, end: usize, bang: bool, arg: &str, append: bool) -> Result<bool, String> {
+        if self.option_bool("readonly") && !bang {
+            return Err("E45: 'readonly' option is set (add ! to override)".to_string());
+        }
+        let (file_options, filename) = parser::parse_file_options(arg)?;
+        let mut format = self.write_format()?;
+        if let Some(ref name) = file_options.encoding {
+            format.encoding = match Encoding::from_name(name) {
+                Some(encoding) => encoding,
+                None => return Err(format!("E474: Invalid argument: ++enc={}", name)),
+            };
+        }
+        let filename = match filename {
+            "" => match self.buffer.filename {
+                Some(ref filename) => filename.clone(),
+                None => return Err("E32: No file name".to_string()),
+            },
+            filename => filename.to_string(),
+        };
+        let own = Some(&filename) == self.buffer.filename.as_ref();
+        let whole = start == 0 && end == self.buffer.content.len();
+        let metadata = fs::metadata(&filename).ok();
+        if append {
+            if metadata.is_none() && !bang {
+                return Err(format!("E212: Can't open file for writing: {}", filename));
+            }
+            return match self.buffer.append_lines(&filename, start, end, &format, bang) {
+                Ok(_) => {
+                    self.io.set_status(&format!("\"{}\" {}L appended", filename, end - start));
+                    Ok(true)
+                },
+                Err(err) => Err(format!("Could not append to file {}: {}", filename, err)),
+            };
+        }
+        if let Some(ref metadata) = metadata {
+            if metadata.is_dir() {
+                return Err(format!("E502: \"{}\" is a directory", filename));
+            }
+            if !bang && own && !whole {
+                return Err("E140: Use ! to write partial buffer".to_string());
+            }
+            if !bang && !own && metadata.is_file() {
+                return Err("E13: File exists (add ! to override)".to_string());
+            }
+            if metadata.is_file() && !buffer::writable(Path::new(&filename), metadata) && !bang {
+                return Err(format!("E505: \"{}\" is read-only (add ! to override)", filename));
+            }
+        }
+        if own && !bang && self.buffer.changed_on_disk() {
+            let message = "WARNING: The file has been changed since reading it!!! Do you really want to write to it (y/n)?";
+            if self.prompt(message, "ny") != Some('y') {
+                return Err(format!("Not written: \"{}\" was changed since reading it", filename));
+            }
+        }
+        let backup = buffer::Backup::from_options(|name| { self.option(name) });
+        match self.buffer.write_lines(Some(&filename), start, end, &format, &backup, bang) {
+            Ok(_) => {
+                if own {
+                    self.buffer.stat = buffer::FileStat::of(Path::new(&filename));
+                }
+                if own && whole {
+                    self.buffer.saved();
+                    self.buffer.options.unset("readonly");
+                }
+                self.io.set_status(&format!("\"{}\" {}L written", filename, end - start));
+                Ok(true)
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                Err(format!("E212: Can't open file for writing: {}", filename))
+            },
+            Err(err) => Err(format!("Could not write to file {}: {}", filename, err)),
+        }
+    }
+
+    /// `:w !cmd`: pipe the lines to `command` and show what it prints.
+    fn write_command(&mut self, start: usize, end: usize, command: &str) -> Result<bool, String> {
+        let bytes = self.write_format()?.encode(&self.buffer.content[start .. end])?;
+        let output = self.run_shell(command, &bytes)?;
+        let mut message: Vec<String> = String::from_utf8_lossy(&output.stdout).lines()
+            .chain(String::from_utf8_lossy(&output.stderr).lines())
+            .map(|line| { line.to_string() })
+            .collect();
+        if let Some(code) = output.status.code().filter(|&code| { code != 0 }) {
+            message.push(format!("shell returned {}", code));
+        }
+        self.io.set_status(&message.join(" | "));
+        Ok(true)
+    }
+
+    fn execute_update(&mut self, range: (u64, Option<u64>), bang: bool, target: &ex::WriteTarget) -> Result<bool, String> {
+        if self.buffer.modified() {
+            self.execute_write(range, bang, target)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// `:wn` and `:wN`: write, then edit the file `step` places along the
+    /// argument list.
+    fn execute_write_next(&mut self, range: (u64, Option<u64>), bang: bool, target: &ex::WriteTarget, step: isize) -> Result<bool, String> {
+        let next = self.argidx as isize + step;
+        if next < 0 {
+            return Err("E164: Cannot go before first file".to_string());
+        }
+        if next as usize >= self.arglist.len() {
+            return Err("E165: Cannot go beyond last file".to_string());
+        }
+        self.execute_write(range, bang, target)?;
+        self.argidx = next as usize;
+        let filename = self.arglist[self.argidx].clone();
+        if !self.open(&filename, None)? {
+            return Ok(false);
+        }
+        self.redraw();
+        self.io.set_status(&self.file_message());
+        Ok(true)
+    }
+
+    /// `:saveas`: write the buffer to a new file and keep editing it there.
+    fn execute_saveas(&mut self, bang: bool, arg: &str) -> Result<bool, String> {
+        let (_, filename) = parser::parse_file_options(arg)?;
+        if filename.is_empty() {
+            return Err("E471: Argument required".to_string());
+        }
+        if Path::new(filename).exists() && !bang {
+            return Err("E13: File exists (add ! to override)".to_string());
+        }
+        let previous = self.buffer.filename.replace(filename.to_string());
+        let len = self.buffer.content.len();
+        if let Err(err) = self.write_file(0, len, true, arg, false) {
+            self.buffer.filename = previous;
+            return Err(err);
+        }
+        if previous.is_some() {
+            self.alternate = previous;
+        }
+        self.watch_buffer();
+        if self.swap.is_some() {
+            self.close_swap();
+            self.create_swap()?;
+        }
+        Ok(true)
+    }
+
+    /// The line endings and encoding to write with, from the effective
+    /// option values.
+    pub fn write_format(&self) -> Result<buffer::WriteFormat, String> {
+        buffer::WriteFormat::from_options(|name| { self.option(name) })
+    }
+
+    /// Replace `%`, `#` and `!` in a shell command with the current file,
+    /// the alternate file and the previous command.  A backslash keeps the
+    /// character as it is.
+    pub fn expand_command(&self, command: &str) -> Result<String, String> {
+        let mut expanded = String::new();
+        let mut chars = command.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(next) if next == '%' || next == '#' || next == '!' => expanded.push(next),
+                    Some(next) => {
+                        expanded.push(c);
+                        expanded.push(next);
+                    },
+                    None => expanded.push(c),
+                },
+                '%' => match self.buffer.filename {
+                    Some(ref filename) => expanded.push_str(filename),
+                    None => return Err("E499: Empty file name for '%' or '#', only works with \":p:h\"".to_string()),
+                },
+                '#' => match self.alternate {
+                    Some(ref filename) => expanded.push_str(filename),
+                    None => return Err("E194: No alternate file name to substitute for '#'".to_string()),
+                },
+                '!' => match self.last_shell {
+                    Some(ref previous) => expanded.push_str(previous),
+                    None => return Err("E34: No previous command".to_string()),
+                },
+                c => expanded.push(c),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Expand `command` and remember it for `!`, then build the process
+    /// that runs it with the `shell` and `shellcmdflag` options.
+    fn shell_command(&mut self, command: &str) -> Result<(String, process::Command), String> {
+        let command = self.expand_command(command)?;
+        self.last_shell = Some(command.clone());
+        let mut process = process::Command::new(self.option_str("shell"));
+        process.args(self.option_str("shellcmdflag").split_whitespace()).arg(&command);
+        Ok((command, process))
+    }
+
+    /// Run `command` with `input` on its standard input and collect what it
+    /// prints.
+    fn run_shell(&mut self, command: &str, input: &[u8]) -> Result<process::Output, String> {
+        let (command, process) = self.shell_command(command)?;
+        pipe::run_piped(process, input).map_err(|err| { format!("E482: Can't run {}: {}", command, err) })
+    }
+
+    /// `:!cmd`: run the command on the terminal and wait for Enter before
+    /// going back to the editor.
+    fn execute_shell(&mut self, command: &str) -> Result<bool, String> {
+        let (command, mut process) = self.shell_command(command)?;
+        self.io.suspend();
+        println!(":!{}", command);
+        let status = process.status();
+        match status {
+            Ok(ref status) if !status.success() => {
+                println!("\nshell returned {}", status.code().unwrap_or(-1));
+            },
+            Ok(_) => {},
+            Err(ref err) => println!("E482: Can't run {}: {}", command, err),
+        }
+        print!("\nPress ENTER to continue");
+        io::stdout().flush().ok();
+        io::stdin().read_line(&mut String::new()).ok();
+        self.io.resume().map_err(|err| { format!("Could not restore the screen: {}", err) })?;
+        self.redraw();
+        self.checktime()
+    }
+
+    /// `:[range]!cmd`: replace the lines with the command's output.
+    fn execute_filter(&mut self, range: (u64, Option<u64>), command: &str) -> Result<bool, String> {
+        let (start, end) = self.line_span(range)?;
+        let bytes = self.write_format()?.encode(&self.buffer.content[start .. end])?;
+        let output = self.run_shell(command, &bytes)?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("shell returned {}: {}", output.status.code().unwrap_or(-1), stderr.trim_end()));
+        }
+        let lines = match buffer::Buffer::read_with(&output.stdout[..], &self.option_str("fileencodings")) {
+            Ok(read) => read.content,
+            Err(err) => return Err(format!("Could not read output of {}: {}", command, err)),
+        };
+        self.buffer.content.splice(start .. end, lines);
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: start as u64 + 1, col: 1 };
+        self.io.set_status(&format!("{} lines filtered", end - start));
+        Ok(true)
+    }
+
+    /// `:r`: insert a file or a command's output below the addressed line,
+    /// decoded the same way as when opening a file.
+    fn execute_read(&mut self, range: (u64, Option<u64>), source: &ex::ReadSource) -> Result<bool, String> {
+        let line = range.1.unwrap_or(range.0) as usize;
+        let len = self.buffer.content.len();
+        if line > len && len > 0 {
+            return Err("E16: Invalid range".to_string());
+        }
+        let lines = match *source {
+            ex::ReadSource::File(ref arg) => {
+                let (file_options, filename) = parser::parse_file_options(arg)?;
+                let filename = match filename {
+                    "" => self.buffer.filename.clone().ok_or_else(|| { "E32: No file name".to_string() })?,
+                    filename => filename.to_string(),
+                };
+                let fileencodings = file_options.encoding.unwrap_or_else(|| { self.option_str("fileencodings") });
+                match buffer::Buffer::open_with(&filename, &fileencodings) {
+                    Ok(ref read) if read.new_file => return Err(format!("E484: Can't open file {}", filename)),
+                    Ok(read) => read.content,
+                    Err(err) => return Err(format!("Could not read {}: {}", filename, err)),
+                }
+            },
+            ex::ReadSource::Command(ref command) => {
+                let output = self.run_shell(command, b"")?;
+                match buffer::Buffer::read_with(&output.stdout[..], &self.option_str("fileencodings")) {
+                    Ok(read) => read.content,
+                    Err(err) => return Err(format!("Could not read output of {}: {}", command, err)),
+                }
+            },
+        };
+        let at = if line < len { line } else { len };
+        let count = lines.len();
+        self.buffer.content.splice(at .. at, lines);
+        self.buffer.changed();
+        if count > 0 {
+            self.cursor = CursorLocator { line: at as u64 + 1, col: 1 };
+        }
+        Ok(true)
+    }
+
+    /// `:m`: move the lines below `address`.
+    fn execute_move(&mut self, range: (u64, Option<u64>), address: &ex::Locator) -> Result<bool, String> {
+        let (start, end) = self.line_span(range)?;
+        let target = self.locate(address)? as usize;
+        if target > start && target < end {
+            return Err("E134: Cannot move a range of lines into itself".to_string());
+        }
+        let lines: Vec<String> = self.buffer.content.drain(start .. end).collect();
+        let count = lines.len();
+        let at = if target >= end { target - count } else { target };
+        self.buffer.content.splice(at .. at, lines);
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: (at + count) as u64, col: 1 };
+        Ok(true)
+    }
+
+    /// `:t` and `:co`: copy the lines below `address`.
+    fn execute_copy(&mut self, range: (u64, Option<u64>), address: &ex::Locator) -> Result<bool, String> {
+        let (start, end) = self.line_span(range)?;
+        let at = self.locate(address)? as usize;
+        let lines = self.buffer.content[start .. end].to_vec();
+        let count = lines.len();
+        self.buffer.content.splice(at .. at, lines);
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: (at + count) as u64, col: 1 };
+        Ok(true)
+    }
+
+    /// `:j`: join the lines into one.  A single line is joined with the
+    /// next.  Without `!` leading white space is removed from the joined
+    /// lines and a space put between them, as `J` does.
+    fn execute_join(&mut self, range: (u64, Option<u64>), bang: bool, count: Option<u64>) -> Result<bool, String> {
+        let range = match Engine::counted_range(range, count) {
+            (start, None) => (start, Some(start + 1)),
+            range => range,
+        };
+        let len = self.buffer.content.len() as u64;
+        let range = (range.0, range.1.map(|end| { if end > len { len } else { end } }));
+        let (start, end) = self.line_span(range)?;
+        if end - start < 2 {
+            return Ok(true);
+        }
+        let mut joined = self.buffer.content[start].clone();
+        for line in self.buffer.content.drain(start + 1 .. end) {
+            if bang {
+                joined.push_str(&line);
+                continue;
+            }
+            let line = line.trim_start();
+            if !line.is_empty() && !line.starts_with(')') && !joined.is_empty() && !joined.ends_with(char::is_whitespace) {
+                joined.push(' ');
+            }
+            joined.push_str(line);
+        }
+        self.buffer.content[start] = joined;
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: start as u64 + 1, col: 1 };
+        Ok(true)
+    }
+
+    /// The width of a level of indent: `shiftwidth`, or `tabstop` when it
+    /// is zero.
+    fn shiftwidth(&self) -> usize {
+        match self.option_number("shiftwidth") {
+            sw if sw <= 0 => self.option_number("tabstop").max(1) as usize,
+            sw => sw as usize,
+        }
+    }
+
+    /// `:>` and `:<`: change the indent of the lines by `amount`
+    /// `shiftwidth`s.  Empty lines are left alone.
+    fn execute_shift(&mut self, range: (u64, Option<u64>), amount: i64, count: Option<u64>) -> Result<bool, String> {
+        let (start, end) = self.line_span(Engine::counted_range(range, count))?;
+        let tabstop = self.option_number("tabstop").max(1) as usize;
+        let shiftwidth = self.shiftwidth();
+        let expandtab = self.option_bool("expandtab");
+        for line in self.buffer.content[start .. end].iter_mut() {
+            if line.is_empty() {
+                continue;
+            }
+            let text = line.trim_start().to_string();
+            let width = indent_width(line, tabstop) as i64 + amount * shiftwidth as i64;
+            let width = if width > 0 { width as usize } else { 0 };
+            *line = make_indent(width, tabstop, expandtab) + &text;
+        }
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: end as u64, col: 1 };
+        Ok(true)
+    }
+
+    /// `:sort`: sort the lines in place, see `sort::sort_lines`.
+    fn execute_sort(&mut self, range: (u64, Option<u64>), reverse: bool, args: &str) -> Result<bool, String> {
+        let options = parser::parse_sort_options(args, reverse)?;
+        let (start, end) = self.line_span(range)?;
+        let sorted = sort::sort_lines(&self.buffer.content[start .. end], &options);
+        if sorted.len() < end - start {
+            self.io.set_status(&format!("{} fewer lines", end - start - sorted.len()));
+        }
+        self.buffer.content.splice(start .. end, sorted);
+        self.buffer.changed();
+        self.cursor = CursorLocator { line: start as u64 + 1, col: 1 };
+        Ok(true)
+    }
+
+    fn execute_go(&mut self, range: (u64, Option<u64>)) -> Result<bool, String> {
+        let line = match range.1 {
+            Some(x) => x,
+            None => range.0,
+        };
+        self.cursor = CursorLocator { line: line, col: 1 };
+        Ok(true)
+    }
+
+    fn execute_yank(&mut self, range: (u64, Option<u64>)) -> Result<bool, String> {
+        let mut yanked = String::new();
+        let end = match range.1 {
+            Some(x) => x,
+            None => range.0,
+        };
+        for line in range.0 .. (end + 1) {
+            let offset = (line - 1) as usize;
+            yanked.push_str(&self.buffer.content[offset]);
+            yanked.push('\n');
+        }
+        self.clipboard = yanked;
+        Ok(true)
+    }
+
+    /// `:p`, `:nu` and `:l`: show the lines in the message area and move
+    /// the cursor to the last of them.
+    fn execute_print(&mut self, range: (u64, Option<u64>), number: bool, list: bool) -> Result<bool, String> {
+        let (start, end) = self.line_span(range)?;
+        for line in start .. end {
+            let text = self.format_line(line, number, list);
+            self.message(text);
+        }
+        if end > start {
+            self.cursor = CursorLocator { line: end as u64, col: 1 };
+        }
+        Ok(true)
+    }
+
+    /// Line `line`, counted from 0, as `:p` shows it: with its number when
+    /// asked for or `number` is set, and with tabs and the end of the line
+    /// made visible for `:l` or when `list` is set.
+    fn format_line(&self, line: usize, number: bool, list: bool) -> String {
+        let text = &self.buffer.content[line];
+        let text = if list || self.option_bool("list") { list_line(text) } else { text.clone() };
+        if number || self.option_bool("number") {
+            format!("{:>3} {}", line + 1, text)
+        } else {
+            text
+        }
+    }
+
+    /// `:z`: show a page of lines around the addressed one.  The page is
+    /// `count` lines long, or as high as the text area.
+    fn execute_z(&mut self, range: (u64, Option<u64>), kind: ex::ZKind, count: Option<u64>) -> Result<bool, String> {
+        let len = self.buffer.content.len() as i64;
+        if len == 0 {
+            return Ok(true);
+        }
+        let line = range.1.unwrap_or(range.0) as i64;
+        if line < 1 || line > len {
+            return Err("E16: Invalid range".to_string());
+        }
+        let size = count.map(|count| { count as i64 }).unwrap_or(self.io.text_height() as i64).max(1);
+        let half = (size + 1) / 2;
+        let (start, end, cursor) = match kind {
+            ex::ZKind::Start => (line, line + size - 1, line + size - 1),
+            ex::ZKind::Plus => (line + 1, line + size, line + size),
+            ex::ZKind::Minus => (line - size + 1, line, line),
+            ex::ZKind::Caret => (line - 2 * size, line - size, line - size),
+            ex::ZKind::Dot => (line - half + 1, line + half - 1, line + half - 1),
+            ex::ZKind::Equal => (line - half + 1, line + half - 1, line),
+        };
+        let start = start.max(1).min(len);
+        let end = end.max(start).min(len);
+        let dashes = "-".repeat(self.io.width().saturating_sub(1));
+        for n in start ..= end {
+            let marked = kind == ex::ZKind::Equal && n == line;
+            if marked {
+                self.message(dashes.clone());
+            }
+            let text = self.format_line(n as usize - 1, false, false);
+            self.message(text);
+            if marked {
+                self.message(dashes.clone());
+            }
+        }
+        self.cursor = CursorLocator { line: cursor.max(start).min(end) as u64, col: 1 };
+        Ok(true)
+    }
+
+    /// `:=`: show the addressed line number, or the number of lines.
+    fn execute_line_number(&mut self, range: (u64, Option<u64>)) -> Result<bool, String> {
+        self.message(format!("{}", range.1.unwrap_or(range.0)));
+        Ok(true)
+    }
+
+    fn execute_set(&mut self, args: &str, local: bool) -> Result<bool, String> {
+        let mut shown = vec![];
+        let args = options::split_set_args(args);
+        let set_args = if args.is_empty() {
+            vec![SetArg::ShowChanged]
+        } else {
+            args.iter().map(|arg| { options::parse_set_arg(arg) }).collect::<Result<Vec<_>, _>>()?
+        };
+        for arg in set_args {
+            match arg {
+                SetArg::Enable(def) | SetArg::Disable(def) | SetArg::Toggle(def) | SetArg::Reset(def) | SetArg::Modify(def, ..)
+                    if self.restricted && options::is_secure(def) => {
+                    return Err(format!("E523: Not allowed here: {}", def.name));
+                },
+                SetArg::ShowAll | SetArg::ShowChanged => {
+                    for def in options::OPTIONS.iter() {
+                        let value = self.option(def.name);
+                        if arg == SetArg::ShowAll || value != def.default_value() {
+                            shown.push(options::format_option(def, &value));
+                        }
+                    }
+                },
+                SetArg::Show(def) => shown.push(options::format_option(def, &self.option(def.name))),
+                SetArg::Enable(def) => self.set_option(def, Value::Bool(true), local),
+                SetArg::Disable(def) => self.set_option(def, Value::Bool(false), local),
+                SetArg::Toggle(def) => {
+                    let value = !self.option_bool(def.name);
+                    self.set_option(def, Value::Bool(value), local);
+                },
+                SetArg::Reset(def) => self.set_option(def, def.default_value(), local),
+                SetArg::Modify(def, operator, ref text) => {
+                    let value = options::apply(def, &self.option(def.name), operator, text)?;
+                    self.set_option(def, value, local);
+                },
+            }
+        }
+        if !shown.is_empty() {
+            self.io.set_status(&shown.join("  "));
+        }
+        Ok(true)
+    }
+
+    fn execute_source(&mut self, filename: &str) -> Result<bool, String> {
+        let path = config::expand_home(filename);
+        let mut text = String::new();
+        match File::open(&path).and_then(|mut fh| { fh.read_to_string(&mut text) }) {
+            Ok(_) => self.run_lines(filename, &text),
+            Err(_) => Err(format!("Can't open file {}", filename)),
+        }
+    }
+
+    /// `:map {lhs} {rhs}` and its relatives.  Without `{rhs}`, list the
+    /// mappings starting with `{lhs}`, or all of them without either.
+    fn execute_map(&mut self, modes: &str, noremap: bool, args: &str) -> Result<bool, String> {
+        let modes = mapping::modes(modes);
+        let leader = self.option_str("mapleader");
+        let args = args.trim_start();
+        let (lhs, rhs) = match args.find(char::is_whitespace) {
+            Some(i) => (&args[.. i], args[i ..].trim_start()),
+            None => (args, ""),
+        };
+        let lhs = mapping::parse_keys(lhs, &leader);
+        if rhs.is_empty() {
+            let found: Vec<String> = self.mappings.list(modes, &lhs).iter().map(|mapping| { mapping.describe() }).collect();
+            if found.is_empty() {
+                self.message("No mapping found".to_string());
+            }
+            for line in found {
+                self.message(line);
+            }
+            return Ok(true);
+        }
+        if lhs.is_empty() {
+            return Err(format!("E474: Invalid argument: {}", args));
+        }
+        let rhs = mapping::parse_keys(rhs, &leader);
+        self.mappings.add(Mapping { lhs, rhs, noremap, modes });
+        Ok(true)
+    }
+
+    /// `:abbreviate {lhs} {rhs}` and its relatives.  Without `{rhs}`,
+    /// list the abbreviations starting with `{lhs}`, or all of them without
+    /// either.
+    fn execute_abbreviate(&mut self, modes: &str, args: &str) -> Result<bool, String> {
+        let modes = mapping::modes(modes);
+        let args = args.trim_start();
+        let (lhs, rhs) = match args.find(char::is_whitespace) {
+            Some(i) => (&args[.. i], args[i ..].trim_start()),
+            None => (args, ""),
+        };
+        if rhs.is_empty() {
+            let found: Vec<String> = self.abbreviations.list(modes, lhs).iter().map(|abbreviation| { abbreviation.describe() }).collect();
+            if found.is_empty() {
+                self.message("No abbreviation found".to_string());
+            }
+            for line in found {
+                self.message(line);
+            }
+            return Ok(true);
+        }
+        if !abbrev::valid_lhs(lhs) {
+            return Err(format!("E474: Invalid argument: {}", lhs));
+        }
+        self.abbreviations.add(Abbreviation { lhs: lhs.to_string(), rhs: rhs.to_string(), modes });
+        Ok(true)
+    }
+
+    /// `:unmap {lhs}` and its relatives.
+    fn execute_unmap(&mut self, modes: &str, lhs: &str) -> Result<bool, String> {
+        if lhs.is_empty() {
+            return Err("E474: Invalid argument".to_string());
+        }
+        let keys = mapping::parse_keys(lhs, &self.option_str("mapleader"));
+        if keys.is_empty() {
+            return Err(format!("E474: Invalid argument: {}", lhs));
+        }
+        if !self.mappings.remove(&keys, mapping::modes(modes)) {
+            return Err("E31: No such mapping".to_string());
+        }
+        Ok(true)
+    }
+
+    fn execute_quit(&self, bang: bool) -> Result<bool, String> {
+        if !bang && self.buffer.modified() {
+            return Err("E37: No write since last change (add ! to override)".to_string());
+        }
+        Ok(false)
+    }
+
+    fn execute_unknown(&self, command: &ex::Command) -> Result<bool, String> {
+        Err(format!("Unknown command {:?}", command))
+    }
+}
+
+/// The byte offsets at which the white space separated words of `line`
+/// start.
+fn word_starts<'a>(line: &'a str) -> impl Iterator<Item = usize> + 'a {
+    line.char_indices()
+        .filter(move |&(i, c)| { !c.is_whitespace() && line[.. i].chars().next_back().is_none_or(char::is_whitespace) })
+        .map(|(i, _)| { i })
+}
+
+/// `line` as `:l` shows it: control characters as `^I` and the like, and
+/// a `$` at the end.
+fn list_line(line: &str) -> String {
+    let mut listed = String::new();
+    for c in line.chars() {
+        match c {
+            '\x7f' => listed.push_str("^?"),
+            c if (c as u32) < 0x20 => {
+                listed.push('^');
+                listed.push((c as u8 + b'@') as char);
+            },
+            c => listed.push(c),
+        }
+    }
+    listed.push('$');
+    listed
+}
+
+/// The screen width of the white space `line` starts with.
+fn indent_width(line: &str, tabstop: usize) -> usize {
+    line.chars().take_while(|c| { c.is_whitespace() }).fold(0, |width, c| {
+        if c == '\t' { width + tabstop - width % tabstop } else { width + 1 }
+    })
+}
+
+/// White space `width` columns wide, using tabs unless `expandtab` is set.
+fn make_indent(width: usize, tabstop: usize, expandtab: bool) -> String {
+    if expandtab {
+        " ".repeat(width)
+    } else {
+        "\t".repeat(width / tabstop) + &" ".repeat(width % tabstop)
+    }
+}
+
+impl <'a> Drop for Engine<'a> {
+    fn drop(&mut self) {
+        self.close_swap();
+    }
+}
+
+impl CursorLocator {
+    pub fn new() -> Self {
+        CursorLocator { line: 1, col: 1 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ex;
+    use super::*;
+    use display;
+
+    #[test]
+    fn execute_yank() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.content.push("First line.".to_string());
+        let cmd = ex::Command {
+            string: "1y".to_string(),
+            action: ex::Action::Yank,
+            selector: ex::Selector { start: ex::Locator::Line(1), end: None },
+        };
+        match engine.execute(&cmd) {
+            Ok(_) => assert_eq!(&engine.clipboard, "First line.\n"),
+            Err(e) => panic!(e),
+        };
+    }
+
+    #[test]
+    fn run_search() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.content.push("fn helper() {}".to_string());
+        engine.buffer.content.push("fn main() {}".to_string());
+        engine.cursor.line = 2;
+        assert_eq!(engine.run("/helper"), Ok(true));
+        assert_eq!(engine.cursor.line, 1);
+        assert!(engine.run("/nothing").is_err());
+    }
+
+    #[test]
+    fn restricted_commands() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.content.push("text".to_string());
+        engine.restricted = true;
+        for command in ["w /tmp/virs-restricted", "!true", "1!cat", "r !echo", "set sh=/bin/bash", "set secure!", "map Q :!rm x", "noremap Q gq", "ab teh the"].iter() {
+            assert!(engine.run(command).unwrap_err().starts_with("E523"), "{}", command);
+        }
+        assert_eq!(engine.run("set ts=4 | 1p"), Ok(true));
+        assert!(!Path::new("/tmp/virs-restricted").exists());
+        engine.restricted = false;
+        assert_eq!(engine.run("set sh=/bin/bash"), Ok(true));
+    }
+
+    #[test]
+    fn source_reports_line_numbers() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.content.push("First line.".to_string());
+        engine.buffer.content.push("Second line.".to_string());
+        File::create("/tmp/virs-sourced").unwrap().write_all(b"\" comment\n\n:2y\nbogus\n").unwrap();
+        assert_eq!(
+            engine.run("so /tmp/virs-sourced"),
+            Err("/tmp/virs-sourced line 4: E492: Not an editor command: bogus".to_string())
+        );
+        assert_eq!(&engine.clipboard, "Second line.\n");
+        assert!(engine.run("so /tmp/virs-no-such-file").is_err());
+        File::create("/tmp/virs-sourced").unwrap().write_all(b"so /tmp/virs-sourced\n").unwrap();
+        assert!(engine.run("so /tmp/virs-sourced").unwrap_err().ends_with("line 1: E169: Command too recursive"));
+        assert_eq!(engine.sourcing, 0);
+    }
+
+    #[test]
+    fn set_options() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert_eq!(engine.run("set ts=4 et nowrapscan"), Ok(true));
+        assert_eq!(engine.option_number("tabstop"), 4);
+        assert!(engine.option_bool("expandtab"));
+        assert!(!engine.option_bool("wrapscan"));
+        assert_eq!(engine.run("set ts+=2 ws! et&"), Ok(true));
+        assert_eq!(engine.option_number("ts"), 6);
+        assert!(engine.option_bool("ws"));
+        assert!(!engine.option_bool("et"));
+        assert!(engine.run("set frobnicate").is_err());
+    }
+
+    #[test]
+    fn setlocal_only_changes_local_value() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert_eq!(engine.run("setlocal sw=2 nu"), Ok(true));
+        assert_eq!(engine.option_number("sw"), 2);
+        assert!(engine.option_bool("number"));
+        assert_eq!(engine.options.get("sw"), Some(&Value::Number(8)));
+        assert_eq!(engine.options.get("nu"), Some(&Value::Bool(false)));
+        engine.buffer = buffer::Buffer::new();
+        assert_eq!(engine.option_number("sw"), 8);
+    }
+
+    #[test]
+    fn scroll_keys() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        // A page forward keeps two lines of the old screen in view.
+        let height = engine.io.text_height();
+        let page = if height > 2 { height - 2 } else { 1 };
+        for i in 0 .. 3 * height {
+            engine.buffer.content.push(format!("Line {}", i + 1));
+        }
+        assert!(engine.normal_key(Key::Ctrl('f')));
+        assert_eq!(engine.buffer.top_visible, page);
+        assert_eq!(engine.cursor.line, page as u64 + 1);
+        assert!(engine.normal_key(Key::Ctrl('y')));
+        assert_eq!(engine.buffer.top_visible, page - 1);
+        assert!(engine.normal_key(Key::Char('z')));
+        assert!(engine.normal_key(Key::Char('t')));
+        assert_eq!(engine.buffer.top_visible, page);
+        assert!(!engine.normal_key(Key::Char('Q')));
+    }
+
+    #[test]
+    fn redraw_short_buffer() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.content.push("Only line.".to_string());
+        engine.redraw();
+        assert_eq!(engine.buffer.top_visible, 0);
+    }
+
+    #[test]
+    fn write_with_fileformat() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\nthree".as_bytes()).unwrap();
+        fs::remove_file("/tmp/virs-fileformat").ok();
+        assert_eq!(engine.run("%w /tmp/virs-fileformat"), Ok(true));
+        let mut written = String::new();
+        File::open("/tmp/virs-fileformat").unwrap().read_to_string(&mut written).unwrap();
+        assert_eq!(&written, "one\ntwo\nthree");
+        assert_eq!(engine.run("set ff=dos fixeol"), Ok(true));
+        assert!(engine.run("%w /tmp/virs-fileformat").unwrap_err().starts_with("E13"));
+        assert_eq!(engine.run("%w! /tmp/virs-fileformat"), Ok(true));
+        let mut written = String::new();
+        File::open("/tmp/virs-fileformat").unwrap().read_to_string(&mut written).unwrap();
+        assert_eq!(&written, "one\r\ntwo\r\nthree\r\n");
+    }
+
+    #[test]
+    fn write_defaults_to_whole_buffer() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\nthree\n".as_bytes()).unwrap();
+        engine.cursor.line = 3;
+        fs::remove_file("/tmp/virs-whole").ok();
+        assert_eq!(engine.run("w /tmp/virs-whole"), Ok(true));
+        let mut written = String::new();
+        File::open("/tmp/virs-whole").unwrap().read_to_string(&mut written).unwrap();
+        assert_eq!(&written, "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn edit_and_write_with_encoding() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        File::create("/tmp/virs-latin1").unwrap().write_all(b"caf\xe9\n").unwrap();
+        assert_eq!(engine.run("e ++enc=latin1 /tmp/virs-latin1"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["caf\u{e9}".to_string()]);
+        assert_eq!(engine.option_str("fileencoding"), "latin1");
+        assert_eq!(engine.run("w! ++enc=utf-8 /tmp/virs-utf8"), Ok(true));
+        let mut written = vec![];
+        File::open("/tmp/virs-utf8").unwrap().read_to_end(&mut written).unwrap();
+        assert_eq!(written, "caf\u{e9}\n".as_bytes().to_vec());
+        assert!(engine.run("e ++enc=utf-8 /tmp/virs-latin1").is_err());
+    }
+
+    fn read_file(path: &str) -> String {
+        let mut text = String::new();
+        File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn partial_and_appending_writes() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\nthree\n".as_bytes()).unwrap();
+        engine.cursor.line = 3;
+        assert_eq!(engine.run("2,3w! /tmp/virs-partial"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-partial"), "two\nthree\n");
+        assert_eq!(engine.run("1w >> /tmp/virs-partial"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-partial"), "two\nthree\none\n");
+        assert_eq!(engine.run("w! /tmp/virs-partial"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-partial"), "one\ntwo\nthree\n");
+        assert!(engine.run("2,4w! /tmp/virs-partial").unwrap_err().starts_with("E16"));
+        assert_eq!(engine.run("2w /dev/null"), Ok(true));
+        fs::remove_file("/tmp/virs-appended").ok();
+        assert!(engine.run("w >> /tmp/virs-appended").unwrap_err().starts_with("E212"));
+        assert_eq!(engine.run("w! >> /tmp/virs-appended"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-appended"), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn read_file_and_command() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\n".as_bytes()).unwrap();
+        File::create("/tmp/virs-snippet").unwrap().write_all(b"caf\xe9\r\nsnippet\r\n").unwrap();
+        assert_eq!(engine.run("1r /tmp/virs-snippet"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["one", "caf\u{e9}", "snippet", "two"]);
+        assert_eq!(engine.cursor.line, 2);
+        assert_eq!(engine.run("0r !echo top"), Ok(true));
+        assert_eq!(engine.buffer.content[0], "top");
+        assert_eq!(engine.run("$r !printf 'a\\nb'"), Ok(true));
+        assert_eq!(&engine.buffer.content[5 ..], &["a", "b"]);
+        assert!(engine.buffer.modified());
+        assert!(engine.run("r /tmp/virs-no-such-snippet").unwrap_err().starts_with("E484"));
+        assert!(engine.run("9r /tmp/virs-snippet").unwrap_err().starts_with("E16"));
+    }
+
+    #[test]
+    fn expand_shell_commands() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert!(engine.expand_command("ls %").unwrap_err().starts_with("E499"));
+        engine.buffer.filename = Some("notes.txt".to_string());
+        engine.alternate = Some("old.txt".to_string());
+        assert_eq!(engine.expand_command("diff # %"), Ok("diff old.txt notes.txt".to_string()));
+        assert_eq!(engine.expand_command("printf '\\%d' 5"), Ok("printf '%d' 5".to_string()));
+        assert!(engine.expand_command("!").unwrap_err().starts_with("E34"));
+        engine.last_shell = Some("make".to_string());
+        assert_eq!(engine.expand_command("! test"), Ok("make test".to_string()));
+    }
+
+    #[test]
+    fn filter_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("b\nc\na\nend\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("1,3!sort"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["a", "b", "c", "end"]);
+        assert_eq!(engine.last_shell, Some("sort".to_string()));
+        assert_eq!(engine.run("$!tr a-z A-Z"), Ok(true));
+        assert_eq!(engine.buffer.content[3], "END");
+        assert_eq!(engine.cursor.line, 4);
+        assert_eq!(engine.run("1!!"), Ok(true));
+        assert_eq!(engine.buffer.content[0], "A");
+        assert!(engine.run("1!exit 3").is_err());
+        assert_eq!(engine.buffer.content[0], "A");
+        assert_eq!(engine.run("set shell=/bin/sh shellcmdflag=-c"), Ok(true));
+        assert_eq!(engine.run("2,3!echo x"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["A", "x", "END"]);
+    }
+
+    #[test]
+    fn filter_motion() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("a\nb\n\nc\n".as_bytes()).unwrap();
+        assert!(engine.normal_key(Key::Char('!')));
+        assert!(engine.normal_key(Key::Char('j')));
+        assert_eq!(engine.cmdline.take(), Some(".,.+1!".to_string()));
+        engine.normal_key(Key::Char('!'));
+        engine.normal_key(Key::Char('}'));
+        assert_eq!(engine.cmdline.take(), Some(".,3!".to_string()));
+        engine.normal_key(Key::Char('!'));
+        assert!(!engine.normal_key(Key::Char('k')));
+        assert_eq!(engine.cmdline, None);
+
+        for (keys, range) in [("3!!", ".,.+2!"), ("!2j", ".,.+2!"), ("2!!", ".,.+1!"), ("!G", ".,$!"), ("!3G", ".,3!"), ("3!G", ".,3!")].iter() {
+            for c in keys.chars() {
+                engine.normal_key(Key::Char(c));
+            }
+            assert_eq!(engine.cmdline.take(), Some(range.to_string()));
+            assert_eq!((engine.pending, engine.count), (None, None));
+        }
+        engine.cursor.line = 3;
+        for c in "2!j".chars() {
+            engine.normal_key(Key::Char(c));
+        }
+        assert_eq!(engine.cmdline, None);
+        engine.normal_key(Key::Char('!'));
+        engine.normal_key(Key::Char('1'));
+        assert!(engine.normal_key(Key::Char('G')));
+        assert_eq!(engine.cmdline.take(), Some("1,.!".to_string()));
+        engine.normal_key(Key::Char('!'));
+        assert!(engine.normal_key(Key::Char('x')));
+        assert_eq!(engine.cmdline, None);
+        assert_eq!(engine.messages, vec!["Not a motion for !: x"]);
+    }
+
+    #[test]
+    fn write_to_command() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\nthree\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("2,$w !cat > /tmp/virs-piped"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-piped"), "two\nthree\n");
+    }
+
+    #[test]
+    fn saveas_update_and_wnext() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("set noswapfile").unwrap();
+        for path in ["/tmp/virs-args-1", "/tmp/virs-args-2", "/tmp/virs-saved-as"].iter() {
+            fs::remove_file(path).ok();
+        }
+        engine.arglist = vec!["/tmp/virs-args-1".to_string(), "/tmp/virs-args-2".to_string()];
+        engine.open("/tmp/virs-args-1", None).unwrap();
+        engine.buffer.content.push("first".to_string());
+        engine.buffer.changed();
+        assert!(engine.run("wN").unwrap_err().starts_with("E164"));
+        assert_eq!(engine.run("wn"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-args-1"), "first\n");
+        assert_eq!(engine.buffer.filename, Some("/tmp/virs-args-2".to_string()));
+        assert!(engine.run("wn").unwrap_err().starts_with("E165"));
+
+        assert_eq!(engine.run("update"), Ok(true));
+        assert!(!Path::new("/tmp/virs-args-2").exists());
+        engine.buffer.content.push("second".to_string());
+        engine.buffer.changed();
+        assert_eq!(engine.run("up"), Ok(true));
+        assert_eq!(read_file("/tmp/virs-args-2"), "second\n");
+
+        assert!(engine.run("sav /tmp/virs-args-1").unwrap_err().starts_with("E13"));
+        assert_eq!(engine.run("saveas /tmp/virs-saved-as"), Ok(true));
+        assert_eq!(engine.buffer.filename, Some("/tmp/virs-saved-as".to_string()));
+        assert_eq!(read_file("/tmp/virs-saved-as"), "second\n");
+        assert!(!engine.buffer.modified());
+    }
+
+    #[test]
+    fn external_changes() {
+        let path = "/tmp/virs-external";
+        File::create(path).unwrap().write_all(b"mine\n").unwrap();
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("set noswapfile").unwrap();
+        engine.open(path, None).unwrap();
+        assert_eq!(engine.run("w"), Ok(true));
+        // Our own write is seen by the watcher but is not a change.
+        engine.file_events();
+        assert!(!engine.buffer.changed_on_disk());
+
+        File::create(path).unwrap().write_all(b"theirs\nmore\n").unwrap();
+        assert!(engine.file_events());
+        engine.keys.feed(&[Key::Char('n')], false);
+        assert!(engine.run("w").unwrap_err().starts_with("Not written"));
+        assert_eq!(read_file(path), "theirs\nmore\n");
+
+        engine.keys.feed(&[Key::Enter], false);
+        assert_eq!(engine.run("checktime"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["mine"]);
+        assert!(!engine.buffer.changed_on_disk());
+
+        File::create(path).unwrap().write_all(b"loaded\n").unwrap();
+        engine.keys.feed(&[Key::Char('L')], false);
+        assert_eq!(engine.run("checktime"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["loaded"]);
+        assert!(engine.keys.is_empty());
+
+        File::create(path).unwrap().write_all(b"again\n").unwrap();
+        engine.run("set autoread").unwrap();
+        assert_eq!(engine.run("checkt"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["again"]);
+        assert!(!engine.buffer.modified());
+
+        File::create(path).unwrap().write_all(b"while idle\n").unwrap();
+        engine.idle();
+        assert_eq!(engine.buffer.content, vec!["while idle"]);
+
+        fs::remove_file(path).unwrap();
+        assert!(engine.run("checktime").unwrap_err().starts_with("E211"));
+        assert_eq!(engine.run("checktime"), Ok(true));
+    }
+
+    fn lines<'a>(engine: &'a Engine) -> Vec<&'a str> {
+        engine.buffer.content.iter().map(|line| { line.as_str() }).collect()
+    }
+
+    #[test]
+    fn print_number_and_list() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\n\ttwo\nthree\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("1,2p"), Ok(true));
+        assert_eq!(engine.cursor.line, 2);
+        assert_eq!(engine.run("2#"), Ok(true));
+        assert_eq!(engine.run("%l"), Ok(true));
+        assert_eq!(engine.run("="), Ok(true));
+        assert_eq!(engine.run("1="), Ok(true));
+        assert_eq!(engine.messages, vec!["one", "\ttwo", "  2 \ttwo", "one$", "^Itwo$", "three$", "3", "1"]);
+        assert_eq!(engine.cursor.line, 3);
+    }
+
+    #[test]
+    fn command_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("one\ntwo\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("2p | 1p \" both"), Ok(true));
+        assert_eq!(engine.messages, vec!["two", "one"]);
+        assert_eq!(engine.run("1p | bogus | 2p"), Err("E492: Not an editor command: bogus | 2p".to_string()));
+        assert_eq!(engine.messages.len(), 2);
+        assert_eq!(engine.run("-5"), Err("E16: Invalid range".to_string()));
+        assert_eq!(engine.run("+18446744073709551615"), Err("E16: Invalid range".to_string()));
+        assert_eq!(engine.run(".,+18446744073709551615d"), Err("E16: Invalid range".to_string()));
+        assert_eq!(engine.run("m +18446744073709551615"), Err("E16: Invalid range".to_string()));
+        assert_eq!(engine.run("1"), Ok(true));
+        assert_eq!(engine.run(".+1p"), Ok(true));
+        assert_eq!(engine.run("$-1p"), Ok(true));
+        assert_eq!(engine.run("1+1-1p"), Ok(true));
+        assert_eq!(engine.messages[2 ..], ["two", "one", "one"]);
+        assert_eq!(engine.run(".-2p"), Err("E16: Invalid range".to_string()));
+        assert_eq!(engine.run("$+1p"), Err("E16: Invalid range".to_string()));
+        engine.buffer.changed();
+        assert!(engine.run("q").unwrap_err().starts_with("E37"));
+        assert!(engine.run("e").unwrap_err().starts_with("E37"));
+        assert_eq!(engine.run("q!"), Ok(false));
+    }
+
+    #[test]
+    fn search_history_and_registers() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("alpha\nbeta\nalphabet\n".as_bytes()).unwrap();
+        assert!(engine.search("").unwrap_err().starts_with("E35"));
+        assert_eq!(engine.search("alpha"), Ok(()));
+        assert_eq!(engine.cursor.line, 3);
+        assert_eq!(engine.search(""), Ok(()));
+        assert_eq!(engine.cursor.line, 1);
+        assert!(engine.search("gamma").is_err());
+        assert_eq!(engine.register('/'), Some("gamma".to_string()));
+
+        engine.run("2y").unwrap();
+        assert_eq!(engine.register('"'), Some("beta".to_string()));
+        engine.run("set hi=2").unwrap();
+        for line in ["1p", "2p", "3p"].iter() {
+            engine.remember(':', line);
+        }
+        assert_eq!(engine.command_history.entries(), &["2p", "3p"]);
+        assert_eq!(engine.register(':'), Some("3p".to_string()));
+
+        // The window holds the history and an empty line for a new command.
+        engine.keys.feed(&[Key::Char('k'), Key::Enter], false);
+        assert_eq!(engine.cmdline_window(':', None), Some("3p".to_string()));
+        assert_eq!(lines(&engine), vec!["alpha", "beta", "alphabet"]);
+        engine.keys.feed(&[Key::Char('k'), Key::Char('k'), Key::Char('d'), Key::Char('d'), Key::Esc], false);
+        assert_eq!(engine.cmdline_window(':', None), None);
+        assert_eq!(engine.command_history.entries(), &["3p"]);
+        assert_eq!(lines(&engine), vec!["alpha", "beta", "alphabet"]);
+        assert!(engine.keys.is_empty());
+    }
+
+    #[test]
+    fn completions() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        let complete = |engine: &Engine, line: &str| {
+            let editor = LineEditor::new(line);
+            let completion = engine.start_completion(':', &editor);
+            (completion.start, completion.matches)
+        };
+        assert_eq!(complete(&engine, "2,3se"), (3, vec!["set".to_string(), "setlocal".to_string()]));
+        assert_eq!(complete(&engine, "set noli"), (4, vec!["nolist".to_string()]));
+        engine.run("set ff=dos").unwrap();
+        assert_eq!(complete(&engine, "set ff="), (7, vec!["dos".to_string(), "mac".to_string(), "unix".to_string()]));
+        assert_eq!(complete(&engine, "se fileformat=u").1, vec!["unix".to_string()]);
+        assert_eq!(complete(&engine, "set ts=").1, vec!["8".to_string()]);
+        assert_eq!(complete(&engine, "set wim=list:").1, vec!["list:full".to_string(), "list:longest".to_string()]);
+        assert_eq!(complete(&engine, "sort n").1, Vec::<String>::new());
+        assert_eq!(engine.start_completion('/', &LineEditor::new("wr")).matches, Vec::<String>::new());
+    }
+
+    #[test]
+    fn map_commands() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("set mapleader=, | nnoremap <leader>w :w<CR>| map! jj <Esc>").unwrap();
+        engine.run("map <F5> :make\\|copen<CR>").unwrap();
+        engine.messages.clear();
+        engine.run("map").unwrap();
+        assert_eq!(engine.messages, vec!["n  ,w           * :w<CR>", "   <F5>           :make|copen<CR>"]);
+        engine.messages.clear();
+        engine.run("map ,").unwrap();
+        assert_eq!(engine.messages.len(), 1);
+        engine.run("nun ,w").unwrap();
+        assert_eq!(engine.run("nun ,w"), Err("E31: No such mapping".to_string()));
+        engine.messages.clear();
+        engine.run("nmap").unwrap();
+        assert_eq!(engine.messages, vec!["   <F5>           :make|copen<CR>"]);
+        engine.run("mapc | imapc").unwrap();
+        engine.messages.clear();
+        engine.run("map!").unwrap();
+        assert_eq!(engine.messages, vec!["c  jj             <Esc>"]);
+        assert_eq!(engine.run("map <Nop> x"), Err("E474: Invalid argument: <Nop> x".to_string()));
+        engine.run("set mapleader=").unwrap();
+        assert!(engine.run("map <leader> x").is_err());
+        assert!(engine.run("unmap <Nop>").is_err());
+        assert_eq!(engine.key_mode(), mapping::NORMAL);
+        engine.pending = Some('!');
+        assert_eq!(engine.key_mode(), mapping::OPERATOR);
+    }
+
+    #[test]
+    fn abbreviation_commands() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("ab teh the| iab #i #include <stdio.h>| cab W w").unwrap();
+        assert_eq!(engine.run("ab a.b x"), Err("E474: Invalid argument: a.b".to_string()));
+        engine.messages.clear();
+        engine.run("ab").unwrap();
+        assert_eq!(engine.messages, vec![
+            "i  #i             #include <stdio.h>",
+            "c  W              w",
+            "!  teh            the",
+        ]);
+        assert_eq!(engine.abbreviations.expand("W", mapping::CMDLINE, None), Some((0, "w".to_string())));
+        engine.run("cuna W | iabc").unwrap();
+        assert_eq!(engine.run("una W"), Err("E24: No such abbreviation".to_string()));
+        engine.messages.clear();
+        engine.run("ab").unwrap();
+        assert_eq!(engine.messages, vec!["c  teh            the"]);
+        engine.run("abc").unwrap();
+        engine.messages.clear();
+        engine.run("ab").unwrap();
+        assert_eq!(engine.messages, vec!["No abbreviation found"]);
+    }
+
+    #[test]
+    fn z_pages() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("1\n2\n3\n4\n5\n6\n7\n8\n9\n".as_bytes()).unwrap();
+        let z = |engine: &mut Engine, command: &str| {
+            engine.messages.clear();
+            assert_eq!(engine.run(command), Ok(true));
+            (engine.messages.join(" "), engine.cursor.line)
+        };
+        assert_eq!(z(&mut engine, "3z3"), ("3 4 5".to_string(), 5));
+        assert_eq!(z(&mut engine, "z2"), ("6 7".to_string(), 7));
+        assert_eq!(z(&mut engine, "z+5"), ("8 9".to_string(), 9));
+        assert_eq!(z(&mut engine, "5z-2"), ("4 5".to_string(), 5));
+        assert_eq!(z(&mut engine, "7z^2"), ("3 4 5".to_string(), 5));
+        assert_eq!(z(&mut engine, "5z.3"), ("4 5 6".to_string(), 6));
+        engine.messages.clear();
+        assert_eq!(engine.run("5z=3"), Ok(true));
+        assert_eq!(engine.messages.len(), 5);
+        assert_eq!(engine.messages[2], "5");
+        assert!(engine.messages[1].starts_with("--"));
+        assert_eq!(engine.cursor.line, 5);
+    }
+
+    #[test]
+    fn file_and_cursor_info() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert_eq!(engine.file_info(), "\"[No Name]\" --No lines in buffer--");
+        engine.buffer = buffer::Buffer::read("one two\n  three\nfour\n".as_bytes()).unwrap();
+        engine.buffer.filename = Some("notes.txt".to_string());
+        engine.buffer.changed();
+        engine.cursor = CursorLocator { line: 2, col: 4 };
+        assert_eq!(engine.file_info(), "\"notes.txt\" [Modified] line 2 of 3 --66%-- col 4");
+        assert_eq!(engine.cursor_info(), "Col 4 of 7; Line 2 of 3; Word 3 of 4; Byte 12 of 21");
+        assert!(engine.normal_key(Key::Char('g')));
+        assert!(engine.normal_key(Key::Ctrl('g')));
+        assert!(engine.normal_key(Key::Ctrl('g')));
+        assert_eq!(engine.messages.len(), 2);
+        assert!(engine.messages[1].starts_with("\"notes.txt\""));
+    }
+
+    #[test]
+    fn move_and_copy_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("1\n2\n3\n4\n5\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("2,3m$"), Ok(true));
+        assert_eq!(lines(&engine), vec!["1", "4", "5", "2", "3"]);
+        assert_eq!(engine.cursor.line, 5);
+        assert_eq!(engine.run("4,5m0"), Ok(true));
+        assert_eq!(lines(&engine), vec!["2", "3", "1", "4", "5"]);
+        assert!(engine.run("1,3m2").unwrap_err().starts_with("E134"));
+        assert_eq!(engine.run("1,2m2"), Ok(true));
+        assert_eq!(lines(&engine), vec!["2", "3", "1", "4", "5"]);
+        assert_eq!(engine.run("1t$"), Ok(true));
+        assert_eq!(lines(&engine), vec!["2", "3", "1", "4", "5", "2"]);
+        assert_eq!(engine.run("1,2co0"), Ok(true));
+        assert_eq!(lines(&engine), vec!["2", "3", "2", "3", "1", "4", "5", "2"]);
+        assert_eq!(engine.cursor.line, 2);
+        assert!(engine.run("1t9").is_err());
+    }
+
+    #[test]
+    fn join_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("call(\n    a,\n    b\n)\nx \n  y\nlast\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("1,4j"), Ok(true));
+        assert_eq!(lines(&engine), vec!["call( a, b)", "x ", "  y", "last"]);
+        assert_eq!(engine.run("2j"), Ok(true));
+        assert_eq!(lines(&engine), vec!["call( a, b)", "x y", "last"]);
+        assert_eq!(engine.run("2j!"), Ok(true));
+        assert_eq!(lines(&engine), vec!["call( a, b)", "x ylast"]);
+        assert_eq!(engine.run("$j"), Ok(true));
+        assert_eq!(engine.run("1j 2"), Ok(true));
+        assert_eq!(lines(&engine), vec!["call( a, b) x ylast"]);
+    }
+
+    #[test]
+    fn shift_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("a\n\n  b\n".as_bytes()).unwrap();
+        engine.run("set sw=4").unwrap();
+        assert_eq!(engine.run("%>"), Ok(true));
+        assert_eq!(lines(&engine), vec!["    a", "", "      b"]);
+        assert_eq!(engine.run("3>>"), Ok(true));
+        assert_eq!(lines(&engine), vec!["    a", "", "\t      b"]);
+        assert_eq!(engine.run("set et"), Ok(true));
+        assert_eq!(engine.run("1< 3"), Ok(true));
+        assert_eq!(lines(&engine), vec!["a", "", "          b"]);
+        assert_eq!(engine.run("3<<<<<"), Ok(true));
+        assert_eq!(lines(&engine), vec!["a", "", "b"]);
+        assert!(engine.run("2> 18446744073709551615").unwrap_err().starts_with("E16"));
+    }
+
+    #[test]
+    fn sort_lines() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("head\nitem 10\nitem 9\nitem 9\ntail\n".as_bytes()).unwrap();
+        assert_eq!(engine.run("2,4sort n u"), Ok(true));
+        assert_eq!(lines(&engine), vec!["head", "item 9", "item 10", "tail"]);
+        assert_eq!(engine.run("sort!"), Ok(true));
+        assert_eq!(lines(&engine), vec!["tail", "item 9", "item 10", "head"]);
+        assert_eq!(engine.run("sort /item /"), Ok(true));
+        assert_eq!(lines(&engine), vec!["tail", "head", "item 10", "item 9"]);
+        assert_eq!(engine.run("sort /\\d\\+/ r n"), Ok(true));
+        assert_eq!(lines(&engine), vec!["tail", "head", "item 9", "item 10"]);
+        assert!(engine.run("sort n x").is_err());
+        assert!(engine.run("sort q").is_err());
+    }
+
+    #[test]
+    fn swap_journal_and_recover() {
+        fs::create_dir_all("/tmp/virs-engine-swap").unwrap();
+        File::create("/tmp/virs-engine-swap/file").unwrap().write_all(b"saved\n").unwrap();
+        {
+            let mut io = display::IO::new().unwrap();
+            let mut engine = Engine::new(&mut io);
+            assert_eq!(engine.open("/tmp/virs-engine-swap/file", None), Ok(true));
+            engine.buffer.content[0] = "unsaved".to_string();
+            engine.buffer.changed();
+            engine.sync_swap().unwrap();
+            assert_eq!(swap::read(&engine.swap.as_ref().unwrap().path).unwrap().content, vec!["saved".to_string()]);
+            engine.run("set updatecount=1").unwrap();
+            engine.sync_swap().unwrap();
+            // Simulate a crash: leave the swap file behind.
+            engine.swap = None;
+        }
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert_eq!(engine.recover("/tmp/virs-engine-swap/file"), Ok(true));
+        assert_eq!(engine.buffer.content, vec!["unsaved".to_string()]);
+        assert!(engine.buffer.modified());
+        let swap_path = engine.swap.as_ref().unwrap().path.clone();
+        assert_eq!(swap_path, Path::new("/tmp/virs-engine-swap/.file.swp"));
+        engine.close_swap();
+        assert!(!swap_path.exists());
+        assert!(engine.recover("/tmp/virs-engine-swap/file").is_err());
+
+        // pid 1 is always running, so its swap file must be left alone.
+        let mut other = SwapFile { path: swap_path.clone(), file: Path::new("/tmp/virs-engine-swap/file").to_path_buf(), pid: 1, tick: 1 };
+        other.write(&["theirs".to_string()], 1).unwrap();
+        assert!(engine.recover("/tmp/virs-engine-swap/file").unwrap_err().starts_with("E325"));
+        assert!(swap_path.exists());
+        assert!(engine.option_bool("readonly"));
+        other.remove().unwrap();
+    }
+
+    #[test]
+    fn noswapfile() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        assert_eq!(engine.run("set noswapfile"), Ok(true));
+        assert_eq!(engine.open("/etc/hostname", None), Ok(true));
+        assert!(engine.swap.is_none());
+    }
+
+    #[test]
+    fn write_readonly() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer.options.set("readonly", Value::Bool(true));
+        assert!(engine.run("w /tmp/virs-readonly").is_err());
+        assert_eq!(engine.run("w! /tmp/virs-readonly"), Ok(true));
+    }
+
+    #[test]
+    fn write_through_readonly_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = "/tmp/virs-readonly-file";
+        fs::remove_file(path).ok();
+        File::create(path).unwrap().write_all(b"old\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o444)).unwrap();
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("set noswapfile").unwrap();
+        assert_eq!(engine.open(path, None), Ok(true));
+        assert!(engine.option_bool("readonly"));
+        engine.buffer.options.unset("readonly");
+        engine.buffer.content[0] = "new".to_string();
+        assert!(engine.run("w").unwrap_err().starts_with("E505"));
+        assert_eq!(engine.run("w!"), Ok(true));
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o444);
+        assert_eq!(buffer::Buffer::open(path).unwrap().content, vec!["new".to_string()]);
+    }
+
+    #[test]
+    fn edit_new_file_and_directory() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.run("set noswapfile").unwrap();
+        assert_eq!(engine.run("e /tmp/virs-no-such-file"), Ok(true));
+        assert!(engine.buffer.new_file);
+        assert_eq!(engine.file_message(), "\"/tmp/virs-no-such-file\" [New File]");
+        assert!(engine.run("e /tmp").unwrap_err().contains("E502"));
+        assert!(engine.run("e /dev/null").unwrap_err().contains("E503"));
+        assert!(engine.run("w /tmp").unwrap_err().contains("E502"));
+    }
+
+    fn typed(engine: &mut Engine, keys: &str) {
+        let keys: Vec<Key> = keys.chars().map(|c| {
+            match c {
+                '\x1b' => Key::Esc,
+                '\n' => Key::Enter,
+                '\x08' => Key::Backspace,
+                '\t' => Key::Tab,
+                c if c < ' ' => Key::Ctrl((c as u8 + b'a' - 1) as char),
+                c => Key::Char(c),
+            }
+        }).collect();
+        engine.keys.feed(&keys, true);
+        while !engine.keys.is_empty() {
+            let key = engine.next_key(engine.key_mode(), None).unwrap();
+            engine.normal_key(key);
+        }
+    }
+
+    #[test]
+    fn counts_and_motions() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("hello world\nab\n".as_bytes()).unwrap();
+        typed(&mut engine, "3l");
+        assert_eq!((engine.cursor.line, engine.cursor.col), (1, 4));
+        typed(&mut engine, "$");
+        assert_eq!(engine.cursor.col, 11);
+        typed(&mut engine, "j");
+        assert_eq!((engine.cursor.line, engine.cursor.col), (2, 2));
+        typed(&mut engine, "10h");
+        assert_eq!(engine.cursor.col, 1);
+        assert_eq!(engine.count, None);
+        typed(&mut engine, "123456789012");
+        assert_eq!(engine.count, Some(999_999_999));
+        typed(&mut engine, "~099999999999999999999x");
+        assert_eq!(engine.buffer.content, vec!["hello world", ""]);
+    }
+
+    #[test]
+    fn replace_mode() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("abc\n".as_bytes()).unwrap();
+        typed(&mut engine, "lRXYZW\x1b");
+        assert_eq!(engine.buffer.content, vec!["aXYZW"]);
+        assert_eq!(engine.mode, Mode::Normal);
+        assert_eq!(engine.cursor.col, 5);
+        typed(&mut engine, "0lRxy\nz\x08\x08\x08\x08\x08\x1b");
+        assert_eq!(engine.buffer.content, vec!["aXYZW"]);
+        assert_eq!(engine.cursor.col, 1);
+        typed(&mut engine, "3rq");
+        assert_eq!(engine.buffer.content, vec!["qqqZW"]);
+        assert_eq!(engine.cursor.col, 3);
+        typed(&mut engine, "5r-");
+        assert_eq!(engine.buffer.content, vec!["qqqZW"]);
+        typed(&mut engine, "r\n");
+        assert_eq!(engine.buffer.content, vec!["qq", "ZW"]);
+
+        // Replace mode ends rather than wait for keys that cannot come.
+        engine.io.suspend();
+        typed(&mut engine, "R..");
+        assert_eq!(engine.buffer.content, vec!["qq", ".."]);
+        assert_eq!(engine.mode, Mode::Normal);
+
+        engine.buffer = buffer::Buffer::read("abcdef\n".as_bytes()).unwrap();
+        engine.clipboard = "12\n34".to_string();
+        typed(&mut engine, "0R\x12\"\x1b");
+        assert_eq!(engine.buffer.content, vec!["12", "34ef"]);
+        typed(&mut engine, "0R\x12\"\x08\x08\x08\x08\x08\x08\x1b");
+        assert_eq!(engine.buffer.content, vec!["12", "34ef"]);
+    }
+
+    #[test]
+    fn delete_and_substitute() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("abcdef\n  one\ntwo\n".as_bytes()).unwrap();
+        typed(&mut engine, "l2x");
+        assert_eq!(engine.buffer.content[0], "adef");
+        assert_eq!(engine.clipboard, "bc");
+        typed(&mut engine, "$X");
+        assert_eq!(engine.buffer.content[0], "adf");
+        typed(&mut engine, "0sA\x1b");
+        assert_eq!(engine.buffer.content[0], "Adf");
+        typed(&mut engine, "lCxyz\x1b");
+        assert_eq!(engine.buffer.content[0], "Axyz");
+        typed(&mut engine, "hD");
+        assert_eq!(engine.buffer.content[0], "Ax");
+        assert_eq!((engine.clipboard.as_str(), engine.cursor.col), ("yz", 2));
+        engine.run("set autoindent").unwrap();
+        typed(&mut engine, "jS1\x1b");
+        assert_eq!(engine.buffer.content, vec!["Ax", "  1", "two"]);
+        assert_eq!(engine.clipboard, "  one\n");
+        typed(&mut engine, "02D");
+        assert_eq!(engine.buffer.content, vec!["Ax", ""]);
+        assert_eq!(engine.clipboard, "  1\ntwo");
+    }
+
+    #[test]
+    fn case_join_and_increment() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("abc\n  def\nghi\nx = 9\n".as_bytes()).unwrap();
+        typed(&mut engine, "2~");
+        assert_eq!(engine.buffer.content[0], "ABc");
+        assert_eq!(engine.cursor.col, 3);
+        typed(&mut engine, "3J");
+        assert_eq!(engine.buffer.content[0], "ABc def ghi");
+        assert_eq!(engine.cursor.col, 8);
+        typed(&mut engine, "gJ");
+        assert_eq!(engine.buffer.content, vec!["ABc def ghix = 9"]);
+        typed(&mut engine, "05");
+        engine.normal_key(Key::Ctrl('a'));
+        assert_eq!(engine.buffer.content, vec!["ABc def ghix = 14"]);
+        assert_eq!(engine.cursor.col, 17);
+        engine.normal_key(Key::Ctrl('x'));
+        assert_eq!(engine.buffer.content, vec!["ABc def ghix = 13"]);
+    }
+
+    #[test]
+    fn put_text() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("abc\nxyz\n".as_bytes()).unwrap();
+        engine.clipboard = "12".to_string();
+        typed(&mut engine, "p");
+        assert_eq!(engine.buffer.content[0], "a12bc");
+        assert_eq!(engine.cursor.col, 3);
+        typed(&mut engine, "0P");
+        assert_eq!(engine.buffer.content[0], "12a12bc");
+        typed(&mut engine, "0gp");
+        assert_eq!(engine.buffer.content[0], "1122a12bc");
+        assert_eq!(engine.cursor.col, 4);
+        engine.clipboard = "line\n".to_string();
+        typed(&mut engine, "2p");
+        assert_eq!(engine.buffer.content, vec!["1122a12bc", "line", "line", "xyz"]);
+        assert_eq!(engine.cursor.line, 2);
+        typed(&mut engine, "gP");
+        assert_eq!(engine.buffer.content, vec!["1122a12bc", "line", "line", "line", "xyz"]);
+        assert_eq!(engine.cursor.line, 3);
+    }
+
+    #[test]
+    fn insert_mode_keys() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("\n".as_bytes()).unwrap();
+        engine.run("set sw=4 et").unwrap();
+        typed(&mut engine, "ilet foo_bar = 1\x17\x17x\x1b");
+        assert_eq!(engine.buffer.content, vec!["let foo_bar x"]);
+        typed(&mut engine, "a\x14\x14\x04 \x15end\x1b");
+        assert_eq!(engine.buffer.content, vec!["    end"]);
+        typed(&mut engine, "a\x04\x1b");
+        assert_eq!(engine.buffer.content, vec!["end"]);
+        typed(&mut engine, "A0\x04\x1b");
+        assert_eq!(engine.buffer.content, vec!["end"]);
+        typed(&mut engine, "0i  \x1bA 0\x04\x1b");
+        assert_eq!(engine.buffer.content, vec!["end "]);
+        engine.clipboard = "two\nlines\n".to_string();
+        typed(&mut engine, "A\x12\"!\x1b");
+        assert_eq!(engine.buffer.content, vec!["end two", "lines!"]);
+
+        // Insert mode ends rather than wait for keys that cannot come.
+        engine.io.suspend();
+        typed(&mut engine, "A...");
+        assert_eq!(engine.buffer.content, vec!["end two", "lines!..."]);
+        assert_eq!(engine.mode, Mode::Normal);
+    }
+
+    #[test]
+    fn insert_literal_and_digraph() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        typed(&mut engine, "i\x16\t\x16065\x16u2014\x16x4g\x16xz\x0be'\x0bqq\x1b");
+        assert_eq!(engine.buffer.content, vec!["\tA—\u{4}gxzéq"]);
+    }
+
+    #[test]
+    fn insert_normal_command() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("abc\nxyz\n".as_bytes()).unwrap();
+        typed(&mut engine, "A\x0f~!\x1b");
+        assert_eq!(engine.buffer.content, vec!["abC!", "xyz"]);
+        typed(&mut engine, "A\x0fj.\x1b");
+        assert_eq!(engine.buffer.content, vec!["abC!", "xy.z"]);
+        typed(&mut engine, "i\x0f:1d\n\x1b");
+        assert_eq!(engine.mode, Mode::Normal);
+        assert_eq!(engine.messages.len(), 0);
+    }
+
+    #[test]
+    fn keyword_completion() {
+        let mut io = display::IO::new().unwrap();
+        let mut engine = Engine::new(&mut io);
+        engine.buffer = buffer::Buffer::read("total totals\n\ntotem\n".as_bytes()).unwrap();
+        typed(&mut engine, "jito\x0e\x1b");
+        assert_eq!(engine.buffer.content[1], "totem");
+        typed(&mut engine, "cc\x1b");
+        typed(&mut engine, "Sto\x10\x10 x\x0e\x1b");
+        assert_eq!(engine.buffer.content[1], "total x");
+        typed(&mut engine, "Sto\x0e\x0e\x0e\x0e\x1b");
+        assert_eq!(engine.buffer.content[1], "to");
+        typed(&mut engine, "Sto\x0e\x0e\x05\x1b");
+        assert_eq!(engine.buffer.content[1], "to");
+        typed(&mut engine, "Sto\x0e\x0e\x19s\x1b");
+        assert_eq!(engine.buffer.content[1], "totals");
     }
 }
@@ -1,5 +1,9 @@
 pub mod parser;
 
+use std::fmt;
+
+use pattern::Pattern;
+
 #[derive(Debug,PartialEq)]
 pub enum Locator {
     Last,
@@ -8,6 +12,10 @@ pub enum Locator {
     Ahead(u64),
     Back(u64),
     Line(u64),
+    Search(String),
+    /// An address followed by `+N` or `-N` offsets, e.g. `$-1`, and
+    /// their sum.
+    Offset(Box<Locator>, i64),
 }
 
 #[derive(Debug,PartialEq)]
@@ -19,16 +27,203 @@ pub struct Selector {
 
 #[derive(Debug,PartialEq)]
 pub enum Action {
+    /// `:ab[breviate] {lhs} {rhs}`, `:iab[brev]` and `:cab[brev]`: the
+    /// modes, in the letters `:map` uses, and the arguments.
+    Abbreviate(&'static str, String),
+    /// `:abc[lear]`, `:iabc[lear]` and `:cabc[lear]`.
+    AbbreviateClear(&'static str),
     Append,
+    /// `:checkt[ime]`
+    CheckTime,
     Delete,
-    Edit(String),
+    /// `:e[dit][!] [++opt] [file]`
+    Edit(bool, String),
+    /// `:[range]t {address}`, `:co[py]`
+    Copy(Locator),
+    /// `:[range]!cmd`: replace the lines with the command's output.
+    Filter(String),
     Global(Box<Action>),
     Go,
+    /// `:[range]j[oin][!] [count]`
+    Join(bool, Option<u64>),
+    /// `:[line]=`, the last line number without an address.
+    LineNumber,
+    /// `:[range]l[ist]`, showing tabs and the ends of lines.
+    List,
+    /// `:map {lhs} {rhs}` and its relatives: the modes, in the letters
+    /// `:map` lists them with, whether the right-hand side is mapped no
+    /// further, as for `:noremap`, and the arguments.
+    Map(&'static str, bool, String),
+    /// `:mapc[lear]` and its relatives, with the modes cleared.
+    MapClear(&'static str),
+    /// `:[range]m[ove] {address}`
+    Move(Locator),
+    /// `:[range]nu[mber]`, `:[range]#`
+    Number,
+    /// `:[range]p[rint]`
     Print,
     Put,
-    Quit,
-    Write(String),
+    /// `:q[uit][!]`
+    Quit(bool),
+    /// `:[line]r[ead]`, inserting below the addressed line.
+    Read(ReadSource),
+    /// `:sav[eas][!] file`
+    SaveAs(bool, String),
+    Set(String),
+    /// `:!cmd`
+    Shell(String),
+    /// `:[range]sor[t][!] [flags]`, with whether `!` was given.
+    Sort(bool, String),
+    /// `:[range]< [count]` and `:[range]> [count]`, by as many
+    /// `shiftwidth`s as there are `<` or `>`; negative shifts left.
+    Shift(i64, Option<u64>),
+    SetLocal(String),
+    Source(String),
+    /// `:una[bbreviate] {lhs}`, `:iuna[bbrev]` and `:cuna[bbrev]`.
+    Unabbreviate(&'static str, String),
+    /// `:unm[ap] {lhs}` and its relatives, with the modes unmapped.
+    Unmap(&'static str, String),
+    /// `:up[date][!]`, which writes only if the buffer was modified.
+    Update(bool, WriteTarget),
+    /// `:w[!]`, with whether `!` was given.
+    Write(bool, WriteTarget),
+    /// `:wn[ext][!]`, `:wN[ext][!]`: write, then edit the next or previous
+    /// file in the argument list.
+    WriteNext(bool, WriteTarget),
+    WritePrevious(bool, WriteTarget),
     Yank,
+    /// `:[line]z[+-^.=][count]`
+    Z(ZKind, Option<u64>),
+}
+
+/// Which lines `:z` shows, named after the mark that selects them.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ZKind {
+    /// No mark: a page starting at the addressed line.
+    Start,
+    /// `+`: the page after the line, the default without an address.
+    Plus,
+    /// `-`: a page ending at the line.
+    Minus,
+    /// `^`: the page before the one `-` shows.
+    Caret,
+    /// `.`: a page centered on the line.
+    Dot,
+    /// `=`: like `.`, with the line set off by rows of dashes.
+    Equal,
+}
+
+/// Where `:w` sends the lines.
+#[derive(Debug,PartialEq)]
+pub enum WriteTarget {
+    /// `[++opt] [file]`, the buffer's own file if empty.
+    File(String),
+    /// `>> [file]`
+    Append(String),
+    /// `!cmd`, piped to the command's standard input.
+    Command(String),
+}
+
+/// How `:sort` compares lines.
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub enum SortKey {
+    #[default]
+    Text,
+    /// `n`: the first decimal number in the line.
+    Decimal,
+    /// `x`: the first hexadecimal number, with or without `0x`.
+    Hex,
+    /// `o`: the first octal number.
+    Octal,
+    /// `b`: the first binary number.
+    Binary,
+    /// `f`: the first floating point number.
+    Float,
+}
+
+#[derive(Debug,Default,Clone,PartialEq)]
+pub struct SortOptions {
+    /// `!`
+    pub reverse: bool,
+    pub key: SortKey,
+    /// `u`: keep only the first of lines that compare equal.
+    pub unique: bool,
+    /// `i`
+    pub ignore_case: bool,
+    /// `/pattern/`: sort on what follows the first match.
+    pub pattern: Option<Pattern>,
+    /// `r`: sort on the match itself instead.
+    pub use_match: bool,
+}
+
+/// What `:r` inserts.
+#[derive(Debug,PartialEq)]
+pub enum ReadSource {
+    /// `[++opt] [file]`, the buffer's own file if empty.
+    File(String),
+    /// `!cmd`, the command's standard output.
+    Command(String),
+}
+
+impl Action {
+    /// The range used when a command is given without one, if it is not
+    /// the current line.
+    pub fn default_selector(&self) -> Option<Selector> {
+        match *self {
+            Action::Write(..) | Action::Update(..) | Action::WriteNext(..) | Action::WritePrevious(..) | Action::Sort(..) | Action::LineNumber => {
+                Some(Selector { start: Locator::All, end: None })
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the command runs a program, writes a file or defines a
+    /// mapping or abbreviation that could later do so, which a `.virsrc`
+    /// read with `secure` may not do.
+    pub fn is_restricted(&self) -> bool {
+        match *self {
+            Action::Write(..) | Action::Update(..) | Action::WriteNext(..) | Action::WritePrevious(..) | Action::SaveAs(..) => true,
+            Action::Shell(_) | Action::Filter(_) | Action::Read(ReadSource::Command(_)) => true,
+            Action::Map(..) | Action::Abbreviate(..) => true,
+            Action::Global(ref action) => action.is_restricted(),
+            _ => false,
+        }
+    }
+}
+
+/// Why a command line could not be parsed.
+#[derive(Debug,PartialEq)]
+pub struct ParseError {
+    /// The byte offset in the command line the problem was found at.
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(position: usize, message: String) -> Self {
+        ParseError { position, message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What the word being typed at the end of a command line is, for
+/// completion.
+#[derive(Debug,PartialEq)]
+pub enum ArgumentKind {
+    /// A command name.
+    Command,
+    /// An option name after `:set`.
+    Option,
+    /// The value of the named option, after `:set name=`.
+    OptionValue(String),
+    File,
+    /// Something that is not completed.
+    None,
 }
 
 #[derive(Debug,PartialEq)]
@@ -37,3 +232,10 @@ pub struct Command {
     pub selector: Selector,
     pub action: Action,
 }
+
+/// `++opt=val` arguments that can precede a file name, e.g.
+/// `:e ++enc=latin1 notes.txt`.
+#[derive(Debug,Default,PartialEq)]
+pub struct FileOptions {
+    pub encoding: Option<String>,
+}
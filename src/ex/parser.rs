@@ -1,8 +1,8 @@
-use nom;
-use nom::{IResult, ErrorKind, digit, eof};
-use nom::IResult::{Done, Error};
-use super::{Command, Selector, Locator, Action};
+use nom::{IResult, digit};
+use nom::IResult::Done;
+use super::{ArgumentKind, Command, Selector, Locator, Action, FileOptions, ParseError, ReadSource, SortKey, SortOptions, WriteTarget, ZKind};
 use self::utils::*;
+use pattern::Pattern;
 
 mod utils {
     use nom;
@@ -68,118 +68,546 @@ mod utils {
     }
 }
 
-pub fn parse_command<'a>(input: &'a str) -> IResult<&str, Command> {
-    tuple!(input, parse_selector , parse_action, eof).map(|(selector, action, _)| {
-        Command { string: input.to_string(), selector: selector, action: action }
-    })
+/// The command accepts a `!` right after its name.
+const BANG: u8 = 1;
+/// The command takes an argument.
+const EXTRA: u8 = 2;
+/// `|` belongs to the argument instead of starting the next command.
+const NOBAR: u8 = 4;
+/// `"` in the argument does not start a comment.
+const NOTRLCOM: u8 = 8;
+/// An argument starting with `!` is a shell command, which takes the rest
+/// of the line, `|` included.
+const PIPE: u8 = 16;
+/// The argument is a file name.
+const FILE: u8 = 32;
+/// The argument is a list of options.
+const OPTIONS: u8 = 64;
+
+/// An ex command: its full name, the length of its shortest accepted
+/// abbreviation, and how its argument is parsed.
+struct CommandDef {
+    name: &'static str,
+    abbrev: usize,
+    flags: u8,
+    parse: fn(bool, &str) -> Result<Action, String>,
 }
 
-fn parse_selector(input: &str) -> IResult<&str, Selector> {
-    let (input, start) = parse_locator(input).unwrap();
-    let (input, optend) = if input.chars().nth(0) == Some(',') {
-        let (input, end) = parse_locator(input.split_at(1).1).unwrap();
-        (input, Some(end))
-    } else {
-        (input, None)
+macro_rules! command {
+    ($name:expr, $abbrev:expr, $flags:expr, $parse:expr) => {
+        CommandDef { name: $name, abbrev: $abbrev, flags: $flags, parse: $parse }
     };
-    Done(input, Selector { start: start, end: optend })
 }
 
-fn action_quit(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "q").map(|_| { Action::Quit })
+/// The known commands.  When an abbreviation matches several, the first
+/// one listed wins.
+static COMMANDS: &[CommandDef] = &[
+    command!("abbreviate", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Abbreviate("ic", arg.to_string())) }),
+    command!("abclear", 3, 0, |_, _| { Ok(Action::AbbreviateClear("ic")) }),
+    command!("append", 1, 0, |_, _| { Ok(Action::Append) }),
+    command!("cabbrev", 3, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Abbreviate("c", arg.to_string())) }),
+    command!("cabclear", 4, 0, |_, _| { Ok(Action::AbbreviateClear("c")) }),
+    command!("checktime", 6, 0, |_, _| { Ok(Action::CheckTime) }),
+    command!("cmap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("c", false, arg.to_string())) }),
+    command!("cmapclear", 5, 0, |_, _| { Ok(Action::MapClear("c")) }),
+    command!("cnoremap", 3, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("c", true, arg.to_string())) }),
+    command!("cunabbrev", 4, EXTRA, |_, arg| { Ok(Action::Unabbreviate("c", arg.trim().to_string())) }),
+    command!("cunmap", 2, EXTRA, |_, arg| { Ok(Action::Unmap("c", arg.trim().to_string())) }),
+    command!("copy", 2, EXTRA, |_, arg| { parse_address(arg).map(Action::Copy) }),
+    command!("delete", 1, 0, |_, _| { Ok(Action::Delete) }),
+    command!("edit", 1, BANG | EXTRA | FILE, |bang, arg| { Ok(Action::Edit(bang, arg.trim().to_string())) }),
+    command!("iabbrev", 3, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Abbreviate("i", arg.to_string())) }),
+    command!("iabclear", 4, 0, |_, _| { Ok(Action::AbbreviateClear("i")) }),
+    command!("imap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("i", false, arg.to_string())) }),
+    command!("imapclear", 5, 0, |_, _| { Ok(Action::MapClear("i")) }),
+    command!("inoremap", 3, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("i", true, arg.to_string())) }),
+    command!("iunabbrev", 4, EXTRA, |_, arg| { Ok(Action::Unabbreviate("i", arg.trim().to_string())) }),
+    command!("iunmap", 2, EXTRA, |_, arg| { Ok(Action::Unmap("i", arg.trim().to_string())) }),
+    command!("join", 1, BANG | EXTRA, |bang, arg| { parse_count(arg).map(|count| { Action::Join(bang, count) }) }),
+    command!("list", 1, 0, |_, _| { Ok(Action::List) }),
+    command!("map", 3, BANG | EXTRA | NOTRLCOM, |bang, arg| { Ok(Action::Map(if bang { "ic" } else { "nvo" }, false, arg.to_string())) }),
+    command!("mapclear", 4, BANG, |bang, _| { Ok(Action::MapClear(if bang { "ic" } else { "nvo" })) }),
+    command!("move", 1, EXTRA, |_, arg| { parse_address(arg).map(Action::Move) }),
+    command!("nmap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("n", false, arg.to_string())) }),
+    command!("nmapclear", 5, 0, |_, _| { Ok(Action::MapClear("n")) }),
+    command!("nnoremap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("n", true, arg.to_string())) }),
+    command!("noremap", 2, BANG | EXTRA | NOTRLCOM, |bang, arg| { Ok(Action::Map(if bang { "ic" } else { "nvo" }, true, arg.to_string())) }),
+    command!("number", 2, 0, |_, _| { Ok(Action::Number) }),
+    command!("nunmap", 3, EXTRA, |_, arg| { Ok(Action::Unmap("n", arg.trim().to_string())) }),
+    command!("omap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("o", false, arg.to_string())) }),
+    command!("omapclear", 5, 0, |_, _| { Ok(Action::MapClear("o")) }),
+    command!("onoremap", 3, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("o", true, arg.to_string())) }),
+    command!("ounmap", 2, EXTRA, |_, arg| { Ok(Action::Unmap("o", arg.trim().to_string())) }),
+    command!("print", 1, 0, |_, _| { Ok(Action::Print) }),
+    command!("put", 2, 0, |_, _| { Ok(Action::Put) }),
+    command!("quit", 1, BANG, |bang, _| { Ok(Action::Quit(bang)) }),
+    command!("read", 1, EXTRA | FILE | PIPE, |_, arg| { Ok(Action::Read(parse_read_source(arg))) }),
+    command!("saveas", 3, BANG | EXTRA | FILE, |bang, arg| { Ok(Action::SaveAs(bang, arg.trim().to_string())) }),
+    command!("set", 2, EXTRA | OPTIONS, |_, arg| { Ok(Action::Set(arg.trim().to_string())) }),
+    command!("setlocal", 4, EXTRA | OPTIONS, |_, arg| { Ok(Action::SetLocal(arg.trim().to_string())) }),
+    command!("sort", 3, BANG | EXTRA | NOBAR | NOTRLCOM, |bang, arg| { Ok(Action::Sort(bang, arg.trim().to_string())) }),
+    command!("source", 2, EXTRA | FILE, |_, arg| { Ok(Action::Source(arg.trim().to_string())) }),
+    command!("t", 1, EXTRA, |_, arg| { parse_address(arg).map(Action::Copy) }),
+    command!("unabbreviate", 3, EXTRA, |_, arg| { Ok(Action::Unabbreviate("ic", arg.trim().to_string())) }),
+    command!("unmap", 3, BANG | EXTRA, |bang, arg| { Ok(Action::Unmap(if bang { "ic" } else { "nvo" }, arg.trim().to_string())) }),
+    command!("update", 2, BANG | EXTRA | FILE | PIPE, |bang, arg| { Ok(Action::Update(bang, parse_write_target(arg))) }),
+    command!("vmap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("v", false, arg.to_string())) }),
+    command!("vmapclear", 5, 0, |_, _| { Ok(Action::MapClear("v")) }),
+    command!("vnoremap", 2, EXTRA | NOTRLCOM, |_, arg| { Ok(Action::Map("v", true, arg.to_string())) }),
+    command!("vunmap", 2, EXTRA, |_, arg| { Ok(Action::Unmap("v", arg.trim().to_string())) }),
+    command!("write", 1, BANG | EXTRA | FILE | PIPE, |bang, arg| { Ok(Action::Write(bang, parse_write_target(arg))) }),
+    command!("wnext", 2, BANG | EXTRA | FILE | PIPE, |bang, arg| { Ok(Action::WriteNext(bang, parse_write_target(arg))) }),
+    command!("wNext", 2, BANG | EXTRA | FILE | PIPE, |bang, arg| { Ok(Action::WritePrevious(bang, parse_write_target(arg))) }),
+    command!("wprevious", 2, BANG | EXTRA | FILE | PIPE, |bang, arg| { Ok(Action::WritePrevious(bang, parse_write_target(arg))) }),
+    command!("yank", 1, 0, |_, _| { Ok(Action::Yank) }),
+    command!("z", 1, EXTRA, parse_z),
+    command!("!", 1, EXTRA | NOBAR, |_, arg| { Ok(Action::Shell(arg.to_string())) }),
+    command!("#", 1, 0, |_, _| { Ok(Action::Number) }),
+    command!("=", 1, 0, |_, _| { Ok(Action::LineNumber) }),
+    command!("<", 1, EXTRA, |_, arg| { parse_shift(-1, arg) }),
+    command!(">", 1, EXTRA, |_, arg| { parse_shift(1, arg) }),
+];
+
+/// Find the command `name` abbreviates.
+fn lookup(name: &str) -> Option<&'static CommandDef> {
+    COMMANDS.iter().find(|def| { name.len() >= def.abbrev && def.name.starts_with(name) })
+}
+
+/// The names of the commands, for completion.
+pub fn command_names() -> Vec<&'static str> {
+    COMMANDS.iter().map(|def| { def.name }).filter(|name| { name.starts_with(|c: char| { c.is_ascii_alphabetic() }) }).collect()
 }
-fn action_yank(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "y").map(|_| { Action::Yank })
+
+/// What the word at the end of `line` is and where it starts, for
+/// completing it.  Earlier commands separated by `|` are skipped.
+pub fn completion_context(line: &str) -> (usize, ArgumentKind) {
+    let mut start = 0;
+    loop {
+        let begin = skip(line, start, |c| { c.is_whitespace() || c == ':' });
+        if line[begin ..].starts_with('|') {
+            start = begin + 1;
+            continue;
+        }
+        let after_range = match parse_range(line, begin) {
+            Ok((_, end)) => end,
+            Err(_) => return (line.len(), ArgumentKind::None),
+        };
+        let name_start = skip(line, after_range, char::is_whitespace);
+        let rest = &line[name_start ..];
+        let name_len = match rest.find(|c: char| { !c.is_ascii_alphabetic() }) {
+            None => return (name_start, ArgumentKind::Command),
+            Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+            Some(len) => len,
+        };
+        let def = match lookup(&rest[.. name_len]) {
+            Some(def) => def,
+            None => return (line.len(), ArgumentKind::None),
+        };
+        let mut arg_start = name_start + name_len;
+        if def.flags & BANG != 0 && line[arg_start ..].starts_with('!') {
+            arg_start += 1;
+        }
+        let (_, end, next) = split_argument(line, arg_start, def.flags);
+        if let Some(next) = next {
+            start = next;
+            continue;
+        }
+        let arg = &line[arg_start ..];
+        if end < line.len() || def.flags & EXTRA == 0 || (def.flags & PIPE != 0 && arg.trim_start().starts_with('!')) {
+            return (line.len(), ArgumentKind::None);
+        }
+        let word_start = arg_start + arg.rfind(char::is_whitespace).map_or(0, |i| { i + 1 });
+        let word = &line[word_start ..];
+        return if def.flags & FILE != 0 {
+            if word.starts_with(">>") {
+                (word_start + 2, ArgumentKind::File)
+            } else if word.starts_with("++") {
+                (line.len(), ArgumentKind::None)
+            } else {
+                (word_start, ArgumentKind::File)
+            }
+        } else if def.flags & OPTIONS != 0 {
+            match word.find(|c| { c == '=' || c == ':' }) {
+                Some(i) => {
+                    let name = word[.. i].trim_end_matches(|c| { c == '+' || c == '-' || c == '^' });
+                    (word_start + i + 1, ArgumentKind::OptionValue(name.to_string()))
+                },
+                None => (word_start, ArgumentKind::Option),
+            }
+        } else {
+            (line.len(), ArgumentKind::None)
+        };
+    }
+}
+
+/// Parse a command line into its commands.  Commands are separated by
+/// `|`, and `"` starts a comment that runs to the end of the line.
+pub fn parse_line(line: &str) -> Result<Vec<Command>, ParseError> {
+    let mut commands = vec![];
+    let mut start = 0;
+    loop {
+        let (command, next) = parse_command(line, start)?;
+        commands.extend(command);
+        match next {
+            Some(next) => start = next,
+            None => return Ok(commands),
+        }
+    }
+}
+
+/// Parse the command starting at byte `start` of `line`.  Returns the
+/// command, if there is one rather than a comment or nothing, and where
+/// the next command starts.
+fn parse_command(line: &str, start: usize) -> Result<(Option<Command>, Option<usize>), ParseError> {
+    let begin = skip(line, start, |c| { c.is_whitespace() || c == ':' });
+    match line[begin ..].chars().next() {
+        None | Some('"') => return Ok((None, None)),
+        Some('|') => return Ok((None, Some(begin + 1))),
+        _ => {},
+    }
+    let (selector, after_range) = parse_range(line, begin)?;
+    let name_start = skip(line, after_range, char::is_whitespace);
+    let rest = &line[name_start ..];
+    let name_len = match rest.chars().next() {
+        None | Some('|') | Some('"') => {
+            // An address on its own goes to the line.
+            let command = Command {
+                string: line[begin .. name_start].trim_end().to_string(),
+                selector: selector.unwrap_or(Selector { start: Locator::Here, end: None }),
+                action: Action::Go,
+            };
+            let next = if rest.starts_with('|') { Some(name_start + 1) } else { None };
+            return Ok((Some(command), next));
+        },
+        Some(c) if c.is_ascii_alphabetic() => rest.find(|c: char| { !c.is_ascii_alphabetic() }).unwrap_or(rest.len()),
+        Some(c) => c.len_utf8(),
+    };
+    let def = lookup(&rest[.. name_len]).ok_or_else(|| {
+        ParseError::new(name_start, format!("E492: Not an editor command: {}", line[begin ..].trim_end()))
+    })?;
+    let mut arg_start = name_start + name_len;
+    let bang = def.flags & BANG != 0 && line[arg_start ..].starts_with('!');
+    if bang {
+        arg_start += 1;
+    }
+    let (arg, end, next) = split_argument(line, arg_start, def.flags);
+    if def.flags & EXTRA == 0 && !arg.trim().is_empty() {
+        let message = if arg.starts_with('!') {
+            "E477: No ! allowed".to_string()
+        } else {
+            format!("E488: Trailing characters: {}", arg.trim())
+        };
+        return Err(ParseError::new(arg_start, message));
+    }
+    let action = (def.parse)(bang, &arg).map_err(|message| { ParseError::new(arg_start, message) })?;
+    let selector = match (selector, action.default_selector()) {
+        (Some(selector), _) => Some(selector),
+        (None, default) => default,
+    };
+    let addressed = after_range > begin;
+    let action = match action {
+        Action::Shell(command) if addressed => Action::Filter(command),
+        Action::Z(ZKind::Start, count) if !addressed => Action::Z(ZKind::Plus, count),
+        action => action,
+    };
+    let command = Command {
+        string: line[begin .. end].trim_end().to_string(),
+        selector: selector.unwrap_or(Selector { start: Locator::Here, end: None }),
+        action,
+    };
+    Ok((Some(command), next))
+}
+
+/// The first byte offset from `start` on whose character is not `skipped`.
+fn skip<F: Fn(char) -> bool>(line: &str, start: usize, skipped: F) -> usize {
+    line[start ..].find(|c: char| { !skipped(c) }).map(|i| { start + i }).unwrap_or(line.len())
+}
+
+/// The argument of a command, starting at byte `start` of `line`, with
+/// `\|` turned into `|`.  Also returns where the argument ends and where
+/// the next command starts, if there is one.
+fn split_argument(line: &str, start: usize, flags: u8) -> (String, usize, Option<usize>) {
+    let rest = &line[start ..];
+    if flags & NOBAR != 0 || (flags & PIPE != 0 && rest.trim_start().starts_with('!')) {
+        return (rest.to_string(), line.len(), None);
+    }
+    let mut arg = String::new();
+    let mut chars = rest.char_indices().peekable();
+    let (mut end, mut next) = (line.len(), None);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if chars.peek().map(|&(_, c)| { c }) == Some('|') => {
+                arg.push('|');
+                chars.next();
+            },
+            '|' => {
+                end = start + i;
+                next = Some(end + 1);
+                break;
+            },
+            '"' if flags & NOTRLCOM == 0 => {
+                end = start + i;
+                break;
+            },
+            c => arg.push(c),
+        }
+    }
+    if flags & NOTRLCOM == 0 {
+        arg.truncate(arg.trim_end().len());
+    }
+    (arg, end, next)
 }
-fn action_print(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "p").map(|_| { Action::Print })
+
+/// Parse the addresses at byte `start` of `line`, e.g. `3,$` or `.+2,$-1`.
+/// Returns the selector, if there are any addresses, and where they end.
+fn parse_range(line: &str, start: usize) -> Result<(Option<Selector>, usize), ParseError> {
+    let (first, end) = parse_address_at(line, start)?;
+    let comma = skip(line, end, char::is_whitespace);
+    if !line[comma ..].starts_with(',') {
+        return Ok((first.map(|start| { Selector { start, end: None } }), end));
+    }
+    let (second, end) = parse_address_at(line, skip(line, comma + 1, char::is_whitespace))?;
+    let selector = Selector {
+        start: first.unwrap_or(Locator::Here),
+        end: Some(second.unwrap_or(Locator::Here)),
+    };
+    Ok((Some(selector), end))
 }
-fn action_put(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "put").map(|_| { Action::Put })
+
+/// Parse one address at byte `start` of `line`, if there is one.
+fn parse_address_at(line: &str, start: usize) -> Result<(Option<Locator>, usize), ParseError> {
+    let input = &line[start ..];
+    match parse_locator(input) {
+        Done(rest, _) if rest.len() == input.len() => Ok((None, start)),
+        Done(rest, locator) => Ok((Some(locator), line.len() - rest.len())),
+        _ => Err(ParseError::new(start, "E16: Invalid range".to_string())),
+    }
 }
-fn action_delete(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "d").map(|_| { Action::Delete })
+
+fn parse_read_source(arg: &str) -> ReadSource {
+    let arg = arg.trim_start();
+    match arg.strip_prefix('!') {
+        Some(command) => ReadSource::Command(command.to_string()),
+        None => ReadSource::File(arg.to_string()),
+    }
 }
-fn action_append(input: &str) -> IResult<&str, Action> {
-    tag_str(input, "a").map(|_| { Action::Append })
+
+fn parse_write_target(arg: &str) -> WriteTarget {
+    let arg = arg.trim_start();
+    if let Some(command) = arg.strip_prefix('!') {
+        WriteTarget::Command(command.to_string())
+    } else if let Done(filename, _) = tag_str(arg, ">>") {
+        WriteTarget::Append(filename.trim_start().to_string())
+    } else {
+        WriteTarget::File(arg.to_string())
+    }
 }
-fn action_edit(input: &str) -> IResult<&str, Action> {
-    match tag_str(input, "e") {
-        Done(input, _) => parse_filename(input).map(|filename| { Action::Edit(filename) }),
-        IResult::Incomplete(x) => IResult::Incomplete(x),
-        Error(x) => IResult::Error(x),
+
+/// An optional count after a command, e.g. the `3` in `:j 3`.
+fn parse_count(arg: &str) -> Result<Option<u64>, String> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Ok(None);
+    }
+    match parse_u64(arg) {
+        Done("", 0) => Err("E939: Positive count required".to_string()),
+        Done("", count) => Ok(Some(count)),
+        Done(rest, _) => Err(format!("E488: Trailing characters: {}", rest)),
+        _ => Err(format!("E488: Trailing characters: {}", arg)),
     }
 }
-fn action_write(input: &str) -> IResult<&str, Action> {
-    match tag_str(input, "w") {
-        Done(input, _) => parse_filename(input).map(|filename| { Action::Write(filename) }),
-        IResult::Incomplete(x) => IResult::Incomplete(x),
-        Error(x) => IResult::Error(x),
+
+/// The address argument of `:m` and `:t`.
+fn parse_address(arg: &str) -> Result<Locator, String> {
+    let arg = arg.trim();
+    match parse_locator(arg) {
+        Done("", _) if arg.is_empty() => Err("E14: Invalid address".to_string()),
+        Done("", locator) => Ok(locator),
+        Done(rest, _) => Err(format!("E488: Trailing characters: {}", rest)),
+        _ => Err("E14: Invalid address".to_string()),
     }
 }
-fn action_go(input: &str) -> IResult<&str, Action> {
-    eof(input).map(|_| { Action::Go })
+
+/// The argument of `:<` or `:>`: more of the same marks, each shifting
+/// one `shiftwidth` further, and a count.
+fn parse_shift(direction: i64, arg: &str) -> Result<Action, String> {
+    let marker = if direction > 0 { '>' } else { '<' };
+    let rest = arg.trim_start_matches(marker);
+    let depth = 1 + arg.len() - rest.len();
+    parse_count(rest).map(|count| { Action::Shift(direction * depth as i64, count) })
 }
 
-fn action_unknown(input: &str) -> IResult<&str, Action> {
-    Error(nom::Err::Position(ErrorKind::Tag, input))
+fn parse_z(_: bool, arg: &str) -> Result<Action, String> {
+    let kind = match arg.chars().next() {
+        Some('+') => ZKind::Plus,
+        Some('-') => ZKind::Minus,
+        Some('^') => ZKind::Caret,
+        Some('.') => ZKind::Dot,
+        Some('=') => ZKind::Equal,
+        _ => ZKind::Start,
+    };
+    let arg = if kind == ZKind::Start { arg } else { &arg[1 ..] };
+    parse_count(arg).map(|count| { Action::Z(kind, count) })
 }
 
-fn parse_action(input: &str) -> IResult<&str, Action> {
-    alt!(input, action_quit|action_yank|action_put|action_print|action_delete|action_append|action_edit|action_go|action_write|action_unknown)
+/// Parse the flags and pattern of `:sort`.
+pub fn parse_sort_options(input: &str, reverse: bool) -> Result<SortOptions, String> {
+    let mut options = SortOptions { reverse, ..SortOptions::default() };
+    let mut input = input;
+    while let Some(c) = input.chars().next() {
+        input = &input[c.len_utf8() ..];
+        let key = match c {
+            ' ' | '\t' => continue,
+            'n' => SortKey::Decimal,
+            'x' => SortKey::Hex,
+            'o' => SortKey::Octal,
+            'b' => SortKey::Binary,
+            'f' => SortKey::Float,
+            'u' => {
+                options.unique = true;
+                continue;
+            },
+            'i' => {
+                options.ignore_case = true;
+                continue;
+            },
+            'r' => {
+                options.use_match = true;
+                continue;
+            },
+            c if !c.is_alphanumeric() && c != '"' && c != '\\' => {
+                let (pattern, remainder) = parse_pattern(input, c);
+                if pattern.is_empty() {
+                    return Err("E35: No previous regular expression".to_string());
+                }
+                options.pattern = Some(Pattern::new(&pattern)?);
+                input = remainder;
+                continue;
+            },
+            c => return Err(format!("E474: Invalid argument: {}", c)),
+        };
+        if options.key != SortKey::Text {
+            return Err("E474: Invalid argument".to_string());
+        }
+        options.key = key;
+    }
+    Ok(options)
 }
 
 fn parse_locator(input: &str) -> IResult<&str, Locator> {
-    match input.chars().nth(0) {
+    let base = match input.chars().nth(0) {
         Some('.') => Done(input.split_at(1).1, Locator::Here),
-        Some('%') => Done(input.split_at(1).1, Locator::All),
+        Some('%') => return Done(input.split_at(1).1, Locator::All),
         Some('$') => Done(input.split_at(1).1, Locator::Last),
-        Some('+') => {
-            parse_u64(input.split_at(1).1).map(|distance| {
-                Locator::Ahead(distance)
+        Some(sign @ '+') | Some(sign @ '-') => {
+            parse_distance(input.split_at(1).1).map(|distance| {
+                if sign == '+' { Locator::Ahead(distance) } else { Locator::Back(distance) }
             })
         },
-        Some('-') => {
-            parse_u64(input.split_at(1).1).map(|distance| {
-                Locator::Back(distance)
-            })
+        Some('/') => {
+            let (pattern, remainder) = parse_pattern(input.split_at(1).1, '/');
+            Done(remainder, Locator::Search(pattern))
         },
         Some('0' ... '9') => {
             parse_u64(input).map(|lineno| {
                 Locator::Line(lineno)
             })
         },
-        _ => Done(input, Locator::Here),
+        _ => return Done(input, Locator::Here),
+    };
+    match base {
+        Done(rest, locator) => parse_offsets(rest, locator),
+        other => other,
     }
 }
 
-fn parse_u64(input: &str) -> IResult<&str, u64> {
-   // TODO: Handle too-large integers
-   map_result(digit(input), |o|{o.parse()})
+/// The distance after a `+` or `-`.  A sign on its own counts one line.
+fn parse_distance(input: &str) -> IResult<&str, u64> {
+    if input.starts_with(|c: char| { c.is_ascii_digit() }) { parse_u64(input) } else { Done(input, 1) }
 }
 
-fn parse_filename<'a>(input: &'a str) -> IResult<&'a str, String> {
+/// Apply the `+N` and `-N` offsets following an address, as in `.+2`.
+fn parse_offsets(input: &str, base: Locator) -> IResult<&str, Locator> {
     let mut input = input;
-    while input.chars().nth(0) == Some(' ') {
-        input = input.split_at(1).1;
+    let mut total: Option<i64> = None;
+    while let Some(sign) = input.chars().next().filter(|&c| { c == '+' || c == '-' }) {
+        let (rest, distance) = match parse_distance(&input[1 ..]) {
+            Done(rest, distance) => (rest, distance.min(i64::MAX as u64) as i64),
+            IResult::Error(err) => return IResult::Error(err),
+            IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+        };
+        let distance = if sign == '+' { distance } else { -distance };
+        total = Some(total.unwrap_or(0).saturating_add(distance));
+        input = rest;
+    }
+    match total {
+        Some(total) => Done(input, Locator::Offset(Box::new(base), total)),
+        None => Done(input, base),
+    }
+}
+
+/// Read a pattern up to an unescaped `delimiter` or the end of input.
+/// Returns the pattern and the input following the delimiter.
+fn parse_pattern(input: &str, delimiter: char) -> (String, &str) {
+    let mut pattern = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (pattern, input.split_at(i + c.len_utf8()).1);
+        } else if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => pattern.push(next),
+                Some((_, next)) => {
+                    pattern.push(c);
+                    pattern.push(next);
+                },
+                None => pattern.push(c),
+            }
+        } else {
+            pattern.push(c);
+        }
     }
-    Done("", input.to_string())
+    (pattern, "")
+}
+
+/// Split leading `++opt=val` arguments off a file name argument.
+pub fn parse_file_options(input: &str) -> Result<(FileOptions, &str), String> {
+    let mut options = FileOptions::default();
+    let mut input = input.trim_start();
+    while input.starts_with("++") {
+        let end = input.find(char::is_whitespace).unwrap_or(input.len());
+        let (arg, remainder) = input.split_at(end);
+        match arg[2..].find('=').map(|i| { arg[2..].split_at(i) }) {
+            Some(("enc", value)) | Some(("encoding", value)) => options.encoding = Some(value[1..].to_string()),
+            _ => return Err(format!("E474: Invalid argument: {}", arg)),
+        }
+        input = remainder.trim_start();
+    }
+    Ok((options, input))
+}
+
+/// Parse a decimal number, failing on ones too large for a `u64`.
+fn parse_u64(input: &str) -> IResult<&str, u64> {
+   map_result(digit(input), |o|{o.parse()})
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use super::super::{Command, Selector, Locator, Action};
-    use nom::IResult::Done;
+    use super::super::{ArgumentKind, Command, Selector, Locator, Action, FileOptions, ParseError, ReadSource, SortKey, SortOptions, WriteTarget, ZKind};
 
     fn assert_command_equal(cmd_string: &str, selector: Selector, action: Action) {
-        let cmd = parse_command(cmd_string);
+        let cmd = parse_line(cmd_string);
         let expected_result = Command {
             string: cmd_string.to_string(),
             selector: selector,
             action: action,
         };
-        assert_eq!(cmd, Done("", expected_result));
+        assert_eq!(cmd, Ok(vec![expected_result]));
+    }
+
+    fn assert_error(cmd_string: &str, position: usize, message: &str) {
+        assert_eq!(parse_line(cmd_string), Err(ParseError::new(position, message.to_string())));
     }
 
     #[test]
@@ -187,6 +615,12 @@ mod tests {
         assert_command_equal(".p", Selector {start: Locator::Here, end: None}, Action::Print);
     }
 
+    #[test]
+    fn write_whole_buffer() {
+        assert_command_equal("w out.txt", Selector {start: Locator::All, end: None}, Action::Write(false, WriteTarget::File("out.txt".to_string())));
+        assert_command_equal("2w out.txt", Selector {start: Locator::Line(2), end: None}, Action::Write(false, WriteTarget::File("out.txt".to_string())));
+    }
+
     #[test]
     fn all_delete() {
         assert_command_equal("%d", Selector {start: Locator::All, end: None}, Action::Delete);
@@ -215,17 +649,269 @@ mod tests {
         );
     }
 
-    // TODO: Handle this failure gracefully.
     #[test]
-    #[should_panic(expected="unwrap() called on an IResult that is Error")]
-    fn too_large_line() {
+    fn address_offsets() {
+        let offset = |base, offset| { Locator::Offset(Box::new(base), offset) };
         assert_command_equal(
-            "999999999999999999999999999999999999y",
-            Selector {
-                start: Locator::Line(3),
-                end: None,
-            },
+            ".+2,$-1d",
+            Selector {start: offset(Locator::Here, 2), end: Some(offset(Locator::Last, -1))},
+            Action::Delete,
+        );
+        assert_command_equal(
+            "/x/+-+p",
+            Selector {start: offset(Locator::Search("x".to_string()), 1), end: None},
+            Action::Print,
+        );
+        assert_command_equal("+2-3", Selector {start: offset(Locator::Ahead(2), -3), end: None}, Action::Go);
+        assert_error("3+99999999999999999999p", 0, "E16: Invalid range");
+    }
+
+    #[test]
+    fn source_file() {
+        assert_command_equal("so ~/.virsrc", Selector {start: Locator::Here, end: None}, Action::Source("~/.virsrc".to_string()));
+        assert_command_equal("source shared.vim", Selector {start: Locator::Here, end: None}, Action::Source("shared.vim".to_string()));
+    }
+
+    #[test]
+    fn set_options() {
+        assert_command_equal("set ts=4 noet", Selector {start: Locator::Here, end: None}, Action::Set("ts=4 noet".to_string()));
+        assert_command_equal("se", Selector {start: Locator::Here, end: None}, Action::Set("".to_string()));
+        assert_command_equal("setlocal sw=2", Selector {start: Locator::Here, end: None}, Action::SetLocal("sw=2".to_string()));
+        assert_command_equal("setl nu", Selector {start: Locator::Here, end: None}, Action::SetLocal("nu".to_string()));
+    }
+
+    #[test]
+    fn file_options() {
+        assert_eq!(
+            parse_file_options("++enc=latin1 notes.txt"),
+            Ok((FileOptions { encoding: Some("latin1".to_string()) }, "notes.txt"))
+        );
+        assert_eq!(parse_file_options("notes.txt"), Ok((FileOptions::default(), "notes.txt")));
+        assert_eq!(parse_file_options("++enc=utf-8"), Ok((FileOptions { encoding: Some("utf-8".to_string()) }, "")));
+        assert!(parse_file_options("++bin file").is_err());
+    }
+
+    #[test]
+    fn write_bang() {
+        assert_command_equal("w", Selector {start: Locator::All, end: None}, Action::Write(false, WriteTarget::File("".to_string())));
+        assert_command_equal("w! out.txt", Selector {start: Locator::All, end: None}, Action::Write(true, WriteTarget::File("out.txt".to_string())));
+    }
+
+    #[test]
+    fn partial_writes() {
+        assert_command_equal(
+            "2,5w part.txt",
+            Selector {start: Locator::Line(2), end: Some(Locator::Line(5))},
+            Action::Write(false, WriteTarget::File("part.txt".to_string())),
+        );
+        assert_command_equal(".w >> log", Selector {start: Locator::Here, end: None}, Action::Write(false, WriteTarget::Append("log".to_string())));
+        assert_command_equal("w !wc -l", Selector {start: Locator::All, end: None}, Action::Write(false, WriteTarget::Command("wc -l".to_string())));
+        assert_command_equal("w! >>log", Selector {start: Locator::All, end: None}, Action::Write(true, WriteTarget::Append("log".to_string())));
+    }
+
+    #[test]
+    fn other_write_commands() {
+        assert_command_equal("up", Selector {start: Locator::All, end: None}, Action::Update(false, WriteTarget::File("".to_string())));
+        assert_command_equal("wn", Selector {start: Locator::All, end: None}, Action::WriteNext(false, WriteTarget::File("".to_string())));
+        assert_command_equal("wN!", Selector {start: Locator::All, end: None}, Action::WritePrevious(true, WriteTarget::File("".to_string())));
+        assert_command_equal("sav! copy.txt", Selector {start: Locator::Here, end: None}, Action::SaveAs(true, "copy.txt".to_string()));
+    }
+
+    #[test]
+    fn read_file_and_command() {
+        assert_command_equal("0r header.txt", Selector {start: Locator::Line(0), end: None}, Action::Read(ReadSource::File("header.txt".to_string())));
+        assert_command_equal("r ++enc=latin1 x", Selector {start: Locator::Here, end: None}, Action::Read(ReadSource::File("++enc=latin1 x".to_string())));
+        assert_command_equal("$read !date", Selector {start: Locator::Last, end: None}, Action::Read(ReadSource::Command("date".to_string())));
+    }
+
+    #[test]
+    fn shell_and_filter() {
+        assert_command_equal("!ls %", Selector {start: Locator::Here, end: None}, Action::Shell("ls %".to_string()));
+        assert_command_equal("!!", Selector {start: Locator::Here, end: None}, Action::Shell("!".to_string()));
+        assert_command_equal(".!tr a-z A-Z", Selector {start: Locator::Here, end: None}, Action::Filter("tr a-z A-Z".to_string()));
+        assert_command_equal(
+            "2,$!sort",
+            Selector {start: Locator::Line(2), end: Some(Locator::Last)},
+            Action::Filter("sort".to_string()),
+        );
+    }
+
+    #[test]
+    fn checktime() {
+        assert_command_equal("checkt", Selector {start: Locator::Here, end: None}, Action::CheckTime);
+        assert_command_equal("checktime", Selector {start: Locator::Here, end: None}, Action::CheckTime);
+    }
+
+    #[test]
+    fn move_and_copy() {
+        assert_command_equal("2,4m0", Selector {start: Locator::Line(2), end: Some(Locator::Line(4))}, Action::Move(Locator::Line(0)));
+        assert_command_equal("m $", Selector {start: Locator::Here, end: None}, Action::Move(Locator::Last));
+        assert_command_equal("t.", Selector {start: Locator::Here, end: None}, Action::Copy(Locator::Here));
+        assert_command_equal("1,2co +3", Selector {start: Locator::Line(1), end: Some(Locator::Line(2))}, Action::Copy(Locator::Ahead(3)));
+        assert!(parse_line("m").is_err());
+    }
+
+    #[test]
+    fn join_and_shift() {
+        assert_command_equal("j", Selector {start: Locator::Here, end: None}, Action::Join(false, None));
+        assert_command_equal("1,3join!", Selector {start: Locator::Line(1), end: Some(Locator::Line(3))}, Action::Join(true, None));
+        assert_command_equal("j 4", Selector {start: Locator::Here, end: None}, Action::Join(false, Some(4)));
+        assert_command_equal("%>", Selector {start: Locator::All, end: None}, Action::Shift(1, None));
+        assert_command_equal(">> 3", Selector {start: Locator::Here, end: None}, Action::Shift(2, Some(3)));
+        assert_command_equal("2<", Selector {start: Locator::Line(2), end: None}, Action::Shift(-1, None));
+    }
+
+    #[test]
+    fn sort() {
+        assert_command_equal("sort", Selector {start: Locator::All, end: None}, Action::Sort(false, "".to_string()));
+        assert_command_equal(
+            "5,9sor! n u",
+            Selector {start: Locator::Line(5), end: Some(Locator::Line(9))},
+            Action::Sort(true, "n u".to_string()),
+        );
+        assert_eq!(
+            parse_sort_options("n u", true),
+            Ok(SortOptions { reverse: true, key: SortKey::Decimal, unique: true, ..SortOptions::default() })
+        );
+        assert_eq!(
+            parse_sort_options(" /id=/ ri", false),
+            Ok(SortOptions { pattern: Some(Pattern::new("id=").unwrap()), use_match: true, ignore_case: true, ..SortOptions::default() })
+        );
+        assert_eq!(parse_sort_options("x", false), Ok(SortOptions { key: SortKey::Hex, ..SortOptions::default() }));
+        assert!(parse_sort_options("n x", false).is_err());
+        assert!(parse_sort_options("q", false).is_err());
+        assert!(parse_sort_options("//", false).is_err());
+        assert_eq!(parse_sort_options("/^\\d\\+/", false).unwrap().pattern, Some(Pattern::new("^\\d\\+").unwrap()));
+        assert_eq!(parse_sort_options("/\\(a/", false), Err("E54: Unmatched \\(".to_string()));
+        assert_command_equal("so sorted.vim", Selector {start: Locator::Here, end: None}, Action::Source("sorted.vim".to_string()));
+    }
+
+    #[test]
+    fn informational() {
+        assert_command_equal("=", Selector {start: Locator::All, end: None}, Action::LineNumber);
+        assert_command_equal(".=", Selector {start: Locator::Here, end: None}, Action::LineNumber);
+        assert_command_equal("1,3#", Selector {start: Locator::Line(1), end: Some(Locator::Line(3))}, Action::Number);
+        assert_command_equal("number", Selector {start: Locator::Here, end: None}, Action::Number);
+        assert_command_equal("%l", Selector {start: Locator::All, end: None}, Action::List);
+        assert_command_equal("print", Selector {start: Locator::Here, end: None}, Action::Print);
+        assert_command_equal("z", Selector {start: Locator::Here, end: None}, Action::Z(ZKind::Plus, None));
+        assert_command_equal("5z", Selector {start: Locator::Line(5), end: None}, Action::Z(ZKind::Start, None));
+        assert_command_equal("z=3", Selector {start: Locator::Here, end: None}, Action::Z(ZKind::Equal, Some(3)));
+        assert_command_equal("$z- 4", Selector {start: Locator::Last, end: None}, Action::Z(ZKind::Minus, Some(4)));
+    }
+
+    #[test]
+    fn search_go() {
+        assert_command_equal("/fn main", Selector {start: Locator::Search("fn main".to_string()), end: None}, Action::Go);
+    }
+
+    #[test]
+    fn search_range_yank() {
+        assert_command_equal(
+            "/a\\/b/,$y",
+            Selector {start: Locator::Search("a/b".to_string()), end: Some(Locator::Last)},
             Action::Yank,
         );
     }
+
+    #[test]
+    fn too_large_line() {
+        assert_error("999999999999999999999999999999999999y", 0, "E16: Invalid range");
+        assert_error("1,99999999999999999999999y", 2, "E16: Invalid range");
+    }
+
+    #[test]
+    fn whitespace_and_abbreviations() {
+        assert_command_equal("3 d", Selector {start: Locator::Line(3), end: None}, Action::Delete);
+        assert_command_equal("1 , $ del", Selector {start: Locator::Line(1), end: Some(Locator::Last)}, Action::Delete);
+        assert_command_equal("wri out.txt", Selector {start: Locator::All, end: None}, Action::Write(false, WriteTarget::File("out.txt".to_string())));
+        assert_command_equal("-,+y", Selector {start: Locator::Back(1), end: Some(Locator::Ahead(1))}, Action::Yank);
+        assert_eq!(
+            parse_line("  :: 2p").unwrap()[0].selector,
+            Selector {start: Locator::Line(2), end: None}
+        );
+        assert_error("x", 0, "E492: Not an editor command: x");
+        assert_error("3del3", 4, "E488: Trailing characters: 3");
+        assert_error("wrt", 0, "E492: Not an editor command: wrt");
+    }
+
+    #[test]
+    fn bars_and_comments() {
+        let commands = parse_line("1,2d | w! out.txt|q").unwrap();
+        assert_eq!(commands.iter().map(|command| { &command.action }).collect::<Vec<_>>(), vec![
+            &Action::Delete,
+            &Action::Write(true, WriteTarget::File("out.txt".to_string())),
+            &Action::Quit(false),
+        ]);
+        assert_eq!(commands[1].string, "w! out.txt");
+        assert_eq!(parse_line("\" a comment | p"), Ok(vec![]));
+        assert_eq!(parse_line(""), Ok(vec![]));
+        assert_eq!(parse_line("set ts=4 \" tabs").unwrap()[0].action, Action::Set("ts=4".to_string()));
+        let commands = parse_line("e a\\|b.txt | !ls | wc").unwrap();
+        assert_eq!(commands[0].action, Action::Edit(false, "a|b.txt".to_string()));
+        assert_eq!(commands[1].action, Action::Shell("ls | wc".to_string()));
+        let commands = parse_line("w !sort | uniq").unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].action, Action::Write(false, WriteTarget::Command("sort | uniq".to_string())));
+        assert_eq!(parse_line("sort /\"/").unwrap()[0].action, Action::Sort(false, "/\"/".to_string()));
+        assert_eq!(parse_line("sort /a|b/").unwrap()[0].action, Action::Sort(false, "/a|b/".to_string()));
+        assert_eq!(parse_line("5 \" go").unwrap()[0].action, Action::Go);
+    }
+
+    #[test]
+    fn bang() {
+        assert_command_equal("q!", Selector {start: Locator::Here, end: None}, Action::Quit(true));
+        assert_command_equal("e! other", Selector {start: Locator::Here, end: None}, Action::Edit(true, "other".to_string()));
+        assert_command_equal("r!date", Selector {start: Locator::Here, end: None}, Action::Read(ReadSource::Command("date".to_string())));
+        assert_error("d!", 1, "E477: No ! allowed");
+        assert_error("j 0", 1, "E939: Positive count required");
+    }
+
+    #[test]
+    fn completion_contexts() {
+        assert_eq!(completion_context(""), (0, ArgumentKind::Command));
+        assert_eq!(completion_context("1,$wr"), (3, ArgumentKind::Command));
+        assert_eq!(completion_context("w | se"), (4, ArgumentKind::Command));
+        assert_eq!(completion_context("e src/ma"), (2, ArgumentKind::File));
+        assert_eq!(completion_context("w! >>lo"), (5, ArgumentKind::File));
+        assert_eq!(completion_context("set ts=4 ic"), (9, ArgumentKind::Option));
+        assert_eq!(completion_context("set ff="), (7, ArgumentKind::OptionValue("ff".to_string())));
+        assert_eq!(completion_context("se wim+=l"), (8, ArgumentKind::OptionValue("wim".to_string())));
+        assert_eq!(completion_context("w !so"), (5, ArgumentKind::None));
+        assert_eq!(completion_context("!ls | e "), (8, ArgumentKind::None));
+        assert_eq!(completion_context("d x"), (3, ArgumentKind::None));
+        assert!(command_names().contains(&"checktime"));
+        assert!(!command_names().contains(&"!"));
+    }
+
+    #[test]
+    fn map_commands() {
+        let here = || { Selector {start: Locator::Here, end: None} };
+        assert_command_equal("map <F5> :w<CR>", here(), Action::Map("nvo", false, " <F5> :w<CR>".to_string()));
+        assert_command_equal("no! jj <Esc>", here(), Action::Map("ic", true, " jj <Esc>".to_string()));
+        assert_command_equal("nn Q gq \"q", here(), Action::Map("n", true, " Q gq \"q".to_string()));
+        assert_command_equal("ino jk <Esc>", here(), Action::Map("i", true, " jk <Esc>".to_string()));
+        assert_command_equal("nun Q", here(), Action::Unmap("n", "Q".to_string()));
+        assert_command_equal("unm! jj", here(), Action::Unmap("ic", "jj".to_string()));
+        assert_command_equal("mapc", here(), Action::MapClear("nvo"));
+        assert_command_equal("cmapc", here(), Action::MapClear("c"));
+        assert_eq!(parse_line("map x a\\|b | nun x").unwrap().len(), 2);
+        assert_eq!(parse_line("map x a\\|b").unwrap()[0].action, Action::Map("nvo", false, " x a|b".to_string()));
+        assert_error("ma x y", 0, "E492: Not an editor command: ma x y");
+        assert_error("nmapc x", 5, "E488: Trailing characters: x");
+    }
+
+    #[test]
+    fn abbreviation_commands() {
+        let here = || { Selector {start: Locator::Here, end: None} };
+        assert_command_equal("ab teh the", here(), Action::Abbreviate("ic", " teh the".to_string()));
+        assert_command_equal("iab #i #include", here(), Action::Abbreviate("i", " #i #include".to_string()));
+        assert_command_equal("cabbrev W w", here(), Action::Abbreviate("c", " W w".to_string()));
+        assert_command_equal("una teh", here(), Action::Unabbreviate("ic", "teh".to_string()));
+        assert_command_equal("cuna W", here(), Action::Unabbreviate("c", "W".to_string()));
+        assert_command_equal("abc", here(), Action::AbbreviateClear("ic"));
+        assert_command_equal("iabc", here(), Action::AbbreviateClear("i"));
+        assert_command_equal("cu x", here(), Action::Unmap("c", "x".to_string()));
+        assert_command_equal("a", here(), Action::Append);
+    }
 }
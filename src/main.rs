@@ -1,86 +1,213 @@
+extern crate libc;
 extern crate rustbox;
 
-#[macro_use]
 extern crate nom;
 
+use std::env;
+use std::io::{self, Read};
+use std::process;
 use std::thread;
 use std::time;
 
-use nom::IResult::{Done, Incomplete, Error};
-
-use ex::{parser};
-
+pub mod abbrev;
+pub mod cli;
+pub mod cmdline;
+pub mod complete;
+pub mod compress;
+pub mod config;
+pub mod digraph;
 pub mod display;
+pub mod edit;
+pub mod encoding;
 pub mod engine;
 pub mod ex;
 pub mod buffer;
+pub mod mapping;
+pub mod options;
+pub mod pattern;
+pub mod pipe;
+pub mod sort;
+pub mod swap;
+pub mod viewport;
+pub mod watch;
 
 fn sleep(n: u64) {
     thread::sleep(time::Duration::from_secs(n));
 }
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match cli::parse(&args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("virs: {}\nMore info with: \"virs --help\"", err);
+            process::exit(1);
+        },
+    };
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if args.version {
+        println!("virs {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    if args.recover && args.files.is_empty() {
+        // The startup files may set `directory`; nothing they draw is shown.
+        let mut io = display::IO::detached();
+        let mut engine = engine::Engine::new(&mut io);
+        for source in config::global_sources(args.rcfile.as_deref()).iter() {
+            if let Err(string) = engine.run_source(source) {
+                eprintln!("virs: {}", string);
+            }
+        }
+        println!("Swap files found:");
+        let journals = swap::list(&engine.option_str("directory"));
+        if journals.is_empty() {
+            println!("   -- none --");
+        }
+        for (i, journal) in journals.iter().enumerate() {
+            println!("{}.    {}", i + 1, journal.swap.path.display());
+            println!("          file name: {}", journal.swap.file.display());
+            println!("         process ID: {}{}", journal.swap.pid,
+                     if journal.swap.owner_running() { " (STILL RUNNING)" } else { "" });
+        }
+        return;
+    }
+    let mut stdin = vec![];
+    if args.stdin {
+        if let Err(err) = io::stdin().read_to_end(&mut stdin) {
+            eprintln!("virs: {}", err);
+            process::exit(1);
+        }
+    }
+
     let mut io = match display::IO::new() {
         Ok(io) => io,
         Err(err) => panic!("{}", err),
     };
     let mut engine = engine::Engine::new(&mut io);
-    engine.buffer = match buffer::Buffer::open("src/main.rs") {
-        Ok(buffer) => buffer,
-        Err(error) => panic!(error),
-    };
+    engine.arglist = args.files.clone();
+    if args.noswap {
+        engine.options.set("swapfile", options::Value::Bool(false));
+    }
+    let sources = config::global_sources(args.rcfile.as_deref());
+    for source in sources.iter() {
+        if let Err(string) = engine.run_source(source) {
+            engine.io.set_status(&string);
+        }
+    }
+    if engine.option_bool("exrc") && args.rcfile.is_none() {
+        if let Some(local) = config::local_source(&sources) {
+            engine.restricted = engine.option_bool("secure") || !config::trusted(&local);
+            if let Err(string) = engine.run_source(&local) {
+                engine.io.set_status(&string);
+            }
+            engine.restricted = false;
+        }
+    }
+    if args.stdin {
+        match buffer::Buffer::read_with(&stdin[..], &engine.option_str("fileencodings")) {
+            Ok(buffer) => engine.buffer = buffer,
+            Err(err) => engine.io.set_status(&format!("Could not read stdin: {}", err)),
+        }
+    } else if let Some(filename) = args.files.first() {
+        let opened = if args.recover { engine.recover(filename) } else { engine.open(filename, None) };
+        match opened {
+            Ok(true) => engine.io.set_status(&engine.file_message()),
+            Ok(false) => return,
+            Err(string) => engine.io.set_status(&string),
+        }
+    }
+    if args.readonly {
+        engine.buffer.options.set("readonly", options::Value::Bool(true));
+    }
+    for command in args.commands.iter() {
+        if let Err(string) = engine.run(command) {
+            engine.io.set_status(&string);
+        }
+    }
+    let history_file = config::history_file();
+    let max_history = engine.option_number("history").max(0) as usize;
+    if let Some(Ok((commands, searches))) = history_file.as_ref().map(|path| { cmdline::load(path, max_history) }) {
+        engine.command_history = commands;
+        engine.search_history = searches;
+    }
+    engine.redraw();
+    engine.flush_messages();
     loop {
-        let input = match engine.io.rustbox.poll_event(false) {
-            Ok(rustbox::Event::KeyEvent(rustbox::Key::Char(':'))) => {
-                engine.io.set_status(":");
-                engine.io.rustbox.set_cursor(2, engine.io.status_line() as isize);
-                engine.io.rustbox.present();
+        // The screen is gone if it could not be taken back from a shell
+        // command; the swap file is brought up to date and left for
+        // recovery.
+        if let Err(err) = engine.io.rustbox() {
+            if let Err(string) = engine.flush_swap() {
+                eprintln!("virs: {}", string);
+            }
+            eprintln!("virs: {}", err);
+            process::exit(1);
+        }
+        if let Err(string) = engine.sync_swap() {
+            engine.io.set_status(&string);
+        }
+        let mode = engine.key_mode();
+        let key = match engine.next_key(mode, Some(engine::IDLE)) {
+            Some(key) => key,
+            None => {
+                engine.idle();
+                continue;
+            },
+        };
+        let input = match key {
+            rustbox::Key::Char(':') => {
                 engine.mode = engine::Mode::Ex;
-                Some(engine.io.readline())
+                engine.read_cmdline(':', "")
             },
-            Ok(rustbox::Event::KeyEvent(rustbox::Key::Char('q'))) => break,
-            Ok(rustbox::Event::KeyEvent(rustbox::Key::Char(x))) => {
-                engine.io.set_status(
-                    &format!("{}: Only ex mode implemented. Press ':' to enter commands or 'q' to quit", x)
-                );
+            key if engine.normal_key(key) => {
+                engine.redraw();
+                engine.flush_messages();
+                match (engine.command.take(), engine.cmdline.take()) {
+                    (Some(command), _) => Some(command),
+                    (None, Some(initial)) => {
+                        engine.mode = engine::Mode::Ex;
+                        engine.read_cmdline(':', &initial)
+                    },
+                    (None, None) => None,
+                }
+            },
+            rustbox::Key::Char(x) => {
+                engine.io.set_status(&format!("{}: not mapped and not a command", x));
                 None
             },
-            Ok(_) => {
-                engine.io.set_status("Only ex mode implemented. Press ':' to enter commands or 'q' to quit");
+            _ => {
+                engine.io.set_status("Key not mapped and not a command");
                 None
             },
-            _ => continue,
         };
         match input {
             Some(command_string) => {
-                match parser::parse_command(&command_string) {
-                    Done("", command) => {
-                        match engine.execute(&command) {
-                            Ok(continuable) => if continuable {
-                                continue;
-                            } else {
-                                engine.io.set_status(&format!("Received exit command: {:?}", command));
-                                sleep(3);
-                                break;
-                            },
-                            Err(string) => engine.io.set_status(&format!("{}", string)),
-                        }
+                match engine.run(&command_string) {
+                    Ok(continuable) => if continuable {
+                        engine.redraw();
+                        engine.flush_messages();
+                    } else {
+                        engine.io.set_status(&format!("Received exit command: {}", command_string));
+                        sleep(3);
+                        break;
+                    },
+                    Err(string) => {
+                        engine.flush_messages();
+                        engine.io.set_status(&string);
                     },
-                    Done(extra, command) => engine.io.set_status(
-                        &format!(
-                            "Invalid command: {}. Extra characters found at the end: {}",
-                            command.string,
-                            extra
-                        )
-                    ),
-                    Error(err) => engine.io.set_status(&format!("Error: {:?}", err)),
-                    Incomplete(_) => panic!("Should not receive incomplete"),
-                };
+                }
                 engine.mode = engine::Mode::Normal;
             },
             None => {
+                engine.mode = engine::Mode::Normal;
                 continue
             }
         }
     }
+    if let Some(path) = history_file {
+        cmdline::save(&path, &engine.command_history, &engine.search_history).ok();
+    }
 }
@@ -0,0 +1,383 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rustbox::Key;
+
+/// The modes a mapping applies in, as bits.
+pub const NORMAL: u8 = 1;
+pub const VISUAL: u8 = 2;
+/// Operator-pending, after an operator such as `!` waits for a motion.
+pub const OPERATOR: u8 = 4;
+pub const INSERT: u8 = 8;
+pub const CMDLINE: u8 = 16;
+
+/// The modes named by `letters`, the way the map commands name them:
+/// `:map` is `nvo`, `:map!` is `ic` and `:nmap` is `n`.
+pub fn modes(letters: &str) -> u8 {
+    letters.chars().fold(0, |modes, letter| {
+        modes | match letter {
+            'n' => NORMAL,
+            'v' => VISUAL,
+            'o' => OPERATOR,
+            'i' => INSERT,
+            'c' => CMDLINE,
+            _ => 0,
+        }
+    })
+}
+
+/// The letters `:map` lists `modes` with: blank for `:map`, `!` for
+/// `:map!`.
+fn mode_letters(modes: u8) -> String {
+    match modes {
+        m if m == NORMAL | VISUAL | OPERATOR => " ".to_string(),
+        m if m == INSERT | CMDLINE => "!".to_string(),
+        _ => [(NORMAL, 'n'), (VISUAL, 'v'), (OPERATOR, 'o'), (INSERT, 'i'), (CMDLINE, 'c')].iter()
+            .filter(|&&(bit, _)| { modes & bit != 0 })
+            .map(|&(_, letter)| { letter })
+            .collect(),
+    }
+}
+
+/// Names of special keys in `<>` notation, e.g. `<CR>`, compared without
+/// regard to case.
+static KEY_NAMES: &[(&str, Key)] = &[
+    ("cr", Key::Enter),
+    ("enter", Key::Enter),
+    ("return", Key::Enter),
+    ("nl", Key::Ctrl('j')),
+    ("esc", Key::Esc),
+    ("tab", Key::Tab),
+    ("bs", Key::Backspace),
+    ("del", Key::Delete),
+    ("insert", Key::Insert),
+    ("space", Key::Char(' ')),
+    ("lt", Key::Char('<')),
+    ("bar", Key::Char('|')),
+    ("bslash", Key::Char('\\')),
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+];
+
+/// The key `<name>` stands for, without the angle brackets.
+fn special_key(name: &str) -> Option<Key> {
+    let lower = name.to_ascii_lowercase();
+    if let Some(&(_, key)) = KEY_NAMES.iter().find(|&&(known, _)| { known == lower }) {
+        return Some(key);
+    }
+    if let Some(number) = lower.strip_prefix('f') {
+        return number.parse().ok().filter(|n| { (1 ..= 12).contains(n) }).map(Key::F);
+    }
+    let mut chars = lower.strip_prefix("c-")?.chars();
+    match (chars.next(), chars.next()) {
+        (Some('i'), None) => Some(Key::Tab),
+        (Some('m'), None) => Some(Key::Enter),
+        (Some('['), None) => Some(Key::Esc),
+        (Some(c), None) => Some(Key::Ctrl(c)),
+        _ => None,
+    }
+}
+
+/// Parse keys written in `<>` notation, e.g. `:w<CR>` or `<C-x>`, with
+/// `<Leader>` standing for `leader`.  A `<` that does not start a known
+/// key name is taken literally.
+pub fn parse_keys(notation: &str, leader: &str) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                let name = &rest[1 .. end];
+                if name.eq_ignore_ascii_case("leader") {
+                    keys.extend(leader.chars().map(Key::Char));
+                    rest = &rest[end + 1 ..];
+                    continue;
+                }
+                if name.eq_ignore_ascii_case("nop") {
+                    rest = &rest[end + 1 ..];
+                    continue;
+                }
+                if let Some(key) = special_key(name) {
+                    keys.push(key);
+                    rest = &rest[end + 1 ..];
+                    continue;
+                }
+            }
+        }
+        keys.push(Key::Char(c));
+        rest = &rest[c.len_utf8() ..];
+    }
+    keys
+}
+
+/// Write `keys` in `<>` notation, for listing mappings.
+pub fn key_notation(keys: &[Key]) -> String {
+    keys.iter().map(|key| {
+        match *key {
+            Key::Char(' ') => "<Space>".to_string(),
+            Key::Char('<') => "<lt>".to_string(),
+            Key::Char(c) => c.to_string(),
+            Key::Ctrl(c) => format!("<C-{}>", c.to_ascii_uppercase()),
+            Key::F(n) => format!("<F{}>", n),
+            Key::Enter => "<CR>".to_string(),
+            Key::Esc => "<Esc>".to_string(),
+            Key::Tab => "<Tab>".to_string(),
+            Key::Backspace => "<BS>".to_string(),
+            Key::Delete => "<Del>".to_string(),
+            Key::Insert => "<Insert>".to_string(),
+            Key::Up => "<Up>".to_string(),
+            Key::Down => "<Down>".to_string(),
+            Key::Left => "<Left>".to_string(),
+            Key::Right => "<Right>".to_string(),
+            Key::Home => "<Home>".to_string(),
+            Key::End => "<End>".to_string(),
+            Key::PageUp => "<PageUp>".to_string(),
+            Key::PageDown => "<PageDown>".to_string(),
+            Key::Unknown(code) => format!("<{}>", code),
+        }
+    }).collect()
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Mapping {
+    pub lhs: Vec<Key>,
+    pub rhs: Vec<Key>,
+    /// The keys of `rhs` are not mapped again.
+    pub noremap: bool,
+    pub modes: u8,
+}
+
+impl Mapping {
+    /// The line `:map` lists the mapping with, e.g. `n  <F5>  * :w<CR>`.
+    pub fn describe(&self) -> String {
+        format!("{:<3}{:<13}{}{}", mode_letters(self.modes), key_notation(&self.lhs),
+                if self.noremap { "* " } else { "  " }, key_notation(&self.rhs))
+    }
+}
+
+/// The mappings defined with `:map` and its relatives.
+#[derive(Debug,Default)]
+pub struct Mappings {
+    entries: Vec<Mapping>,
+}
+
+impl Mappings {
+    pub fn new() -> Self {
+        Mappings { entries: vec![] }
+    }
+
+    /// Add a mapping, replacing what `lhs` was mapped to in its modes.
+    pub fn add(&mut self, mapping: Mapping) {
+        self.remove_modes(&mapping.lhs, mapping.modes);
+        self.entries.push(mapping);
+    }
+
+    /// Remove the mappings of `lhs` in `modes`.  Returns false if there
+    /// were none.
+    pub fn remove(&mut self, lhs: &[Key], modes: u8) -> bool {
+        let found = self.entries.iter().any(|mapping| { mapping.lhs == lhs && mapping.modes & modes != 0 });
+        self.remove_modes(lhs, modes);
+        found
+    }
+
+    fn remove_modes(&mut self, lhs: &[Key], modes: u8) {
+        for mapping in self.entries.iter_mut().filter(|mapping| { mapping.lhs == lhs }) {
+            mapping.modes &= !modes;
+        }
+        self.entries.retain(|mapping| { mapping.modes != 0 });
+    }
+
+    /// Remove every mapping in `modes`.
+    pub fn clear(&mut self, modes: u8) {
+        for mapping in self.entries.iter_mut() {
+            mapping.modes &= !modes;
+        }
+        self.entries.retain(|mapping| { mapping.modes != 0 });
+    }
+
+    /// The mappings in any of `modes` whose left-hand side starts with
+    /// `prefix`, ordered by left-hand side.
+    pub fn list(&self, modes: u8, prefix: &[Key]) -> Vec<&Mapping> {
+        let mut found: Vec<&Mapping> = self.entries.iter()
+            .filter(|mapping| { mapping.modes & modes != 0 && mapping.lhs.starts_with(prefix) })
+            .collect();
+        found.sort_by_key(|mapping| { key_notation(&mapping.lhs) });
+        found
+    }
+
+    /// Look up `keys` in `mode`: the mapping with the longest left-hand
+    /// side `keys` starts with, and whether a longer left-hand side starts
+    /// with all of `keys`, so that more keys are needed to decide.
+    fn lookup(&self, keys: &[Key], mode: u8) -> (Option<&Mapping>, bool) {
+        let mut longest: Option<&Mapping> = None;
+        let mut more = false;
+        for mapping in self.entries.iter().filter(|mapping| { mapping.modes & mode != 0 }) {
+            if mapping.lhs.len() > keys.len() {
+                more |= mapping.lhs.starts_with(keys);
+            } else if keys.starts_with(&mapping.lhs) && longest.is_none_or(|found| { found.lhs.len() < mapping.lhs.len() }) {
+                longest = Some(mapping);
+            }
+        }
+        (longest, more)
+    }
+}
+
+/// Turns typed keys into the keys commands see, by replacing the
+/// left-hand sides of mappings with their right-hand sides.
+#[derive(Debug,Default)]
+pub struct Resolver {
+    /// Keys waiting to be used, and whether each may still be mapped.
+    typeahead: VecDeque<(Key, bool)>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver { typeahead: VecDeque::new() }
+    }
+
+    /// Whether no keys are waiting to be used.
+    pub fn is_empty(&self) -> bool {
+        self.typeahead.is_empty()
+    }
+
+    /// Add keys to be used before any typed ones, e.g. the right-hand side
+    /// of a mapping.
+    pub fn feed(&mut self, keys: &[Key], remap: bool) {
+        for key in keys.iter().rev() {
+            self.typeahead.push_front((*key, remap));
+        }
+    }
+
+    /// The next waiting key as it is, without mapping it.
+    pub fn take(&mut self) -> Option<Key> {
+        self.typeahead.pop_front().map(|(key, _)| { key })
+    }
+
+    /// The next key for `mode`.  `read` fetches a typed key, waiting at
+    /// most the given time or forever; it returns `None` if none came.
+    /// The first key is waited for for `idle`; while the keys typed could
+    /// still become a longer mapping, the next is waited for for
+    /// `timeout`.  Returns `Ok(None)` if no key was typed, and an error
+    /// when mappings expand more than `max_depth` times in a row.
+    pub fn next<F>(&mut self, mappings: &Mappings, mode: u8, idle: Option<Duration>, timeout: Option<Duration>,
+                   max_depth: usize, mut read: F) -> Result<Option<Key>, String>
+        where F: FnMut(Option<Duration>) -> Option<Key> {
+        let mut depth = 0;
+        let mut timed_out = false;
+        loop {
+            if self.typeahead.is_empty() {
+                match read(idle) {
+                    Some(key) => self.typeahead.push_back((key, true)),
+                    None => return Ok(None),
+                }
+            }
+            let keys: Vec<Key> = self.typeahead.iter()
+                .take_while(|&&(_, remap)| { remap })
+                .map(|&(key, _)| { key })
+                .collect();
+            let (found, more) = if keys.is_empty() { (None, false) } else { mappings.lookup(&keys, mode) };
+            if more && !timed_out {
+                match read(timeout) {
+                    Some(key) => self.typeahead.push_back((key, true)),
+                    None => timed_out = true,
+                }
+                continue;
+            }
+            timed_out = false;
+            let mapping = match found {
+                Some(mapping) => mapping,
+                None => return Ok(self.typeahead.pop_front().map(|(key, _)| { key })),
+            };
+            depth += 1;
+            if depth > max_depth {
+                self.typeahead.clear();
+                return Err("E223: Recursive mapping".to_string());
+            }
+            self.typeahead.drain(.. mapping.lhs.len());
+            self.feed(&mapping.rhs, !mapping.noremap);
+            // A right-hand side that starts with its left-hand side, as in
+            // `:map x xx`, would otherwise expand forever.
+            if !mapping.noremap && mapping.rhs.starts_with(&mapping.lhs) {
+                self.typeahead[0].1 = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(lhs: &str, rhs: &str, noremap: bool, letters: &str) -> Mapping {
+        Mapping { lhs: parse_keys(lhs, "\\"), rhs: parse_keys(rhs, "\\"), noremap, modes: modes(letters) }
+    }
+
+    /// Resolve all of `typed` in Normal mode, with `timeout` as the wait
+    /// for ambiguous keys and a timeout after the last typed key.
+    fn resolve(mappings: &Mappings, typed: &str) -> Result<String, String> {
+        let mut typed: VecDeque<Key> = parse_keys(typed, "\\").into_iter().collect();
+        let mut resolver = Resolver::new();
+        let mut keys = vec![];
+        while let Some(key) = resolver.next(mappings, NORMAL, None, Some(Duration::from_millis(1)), 20, |_| { typed.pop_front() })? {
+            keys.push(key);
+        }
+        Ok(key_notation(&keys))
+    }
+
+    #[test]
+    fn notation() {
+        assert_eq!(parse_keys(":w<CR>", "\\"), vec![Key::Char(':'), Key::Char('w'), Key::Enter]);
+        assert_eq!(parse_keys("<c-X><F5><lt><Space><leader>q", ","), vec![
+            Key::Ctrl('x'), Key::F(5), Key::Char('<'), Key::Char(' '), Key::Char(','), Key::Char('q'),
+        ]);
+        assert_eq!(parse_keys("<C-[><Nop>a<b", "\\"), vec![Key::Esc, Key::Char('a'), Key::Char('<'), Key::Char('b')]);
+        assert_eq!(parse_keys("<bogus>", "\\").len(), 7);
+        assert_eq!(key_notation(&parse_keys("<C-x>:w<cr><lt> ", "\\")), "<C-X>:w<CR><lt><Space>");
+    }
+
+    #[test]
+    fn add_remove_and_list() {
+        let mut mappings = Mappings::new();
+        mappings.add(mapping("<F5>", ":w<CR>", true, "nvo"));
+        mappings.add(mapping("<F5>", ":q<CR>", false, "v"));
+        mappings.add(mapping("jj", "<Esc>", false, "i"));
+        let lines: Vec<String> = mappings.list(modes("nvo"), &[]).iter().map(|mapping| { mapping.describe() }).collect();
+        assert_eq!(lines, vec!["no <F5>         * :w<CR>", "v  <F5>           :q<CR>"]);
+        assert_eq!(mappings.list(INSERT, &parse_keys("j", "\\")).len(), 1);
+        assert!(mappings.remove(&parse_keys("<F5>", "\\"), NORMAL));
+        assert!(!mappings.remove(&parse_keys("<F5>", "\\"), NORMAL));
+        assert_eq!(mappings.list(NORMAL | OPERATOR, &[]).len(), 1);
+        mappings.clear(modes("nvo"));
+        assert_eq!(mappings.list(modes("nvoic"), &[]).len(), 1);
+    }
+
+    #[test]
+    fn resolve_mappings() {
+        let mut mappings = Mappings::new();
+        mappings.add(mapping("Q", "gq", false, "n"));
+        mappings.add(mapping("gq", ":q<CR>", false, "n"));
+        mappings.add(mapping("ab", "x", false, "n"));
+        mappings.add(mapping("abc", "y", false, "n"));
+        mappings.add(mapping("j", "jzz", false, "n"));
+        mappings.add(mapping("k", "Q", true, "n"));
+        mappings.add(mapping("I", "Z", false, "i"));
+        assert_eq!(resolve(&mappings, "Q"), Ok(":q<CR>".to_string()));
+        assert_eq!(resolve(&mappings, "abcab"), Ok("yx".to_string()));
+        assert_eq!(resolve(&mappings, "aba"), Ok("xa".to_string()));
+        assert_eq!(resolve(&mappings, "jk"), Ok("jzzQ".to_string()));
+        assert_eq!(resolve(&mappings, "I"), Ok("I".to_string()));
+        mappings.add(mapping("ma", "mb", false, "n"));
+        mappings.add(mapping("mb", "c", false, "n"));
+        assert_eq!(resolve(&mappings, "ma"), Ok("c".to_string()));
+
+        mappings.add(mapping("x", "y", false, "n"));
+        mappings.add(mapping("y", "x", false, "n"));
+        assert_eq!(resolve(&mappings, "x"), Err("E223: Recursive mapping".to_string()));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Scope {
+    Global,
+    Buffer,
+    Window,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Kind {
+    Bool,
+    Number,
+    String,
+    /// A comma-separated list of strings.
+    List,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(i64),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Str(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+pub struct OptionDef {
+    pub name: &'static str,
+    pub abbrev: &'static str,
+    pub kind: Kind,
+    pub scope: Scope,
+    pub default: &'static str,
+    /// The values a string option, or each item of a list option, may
+    /// take.  Empty means anything goes.
+    pub choices: &'static [&'static str],
+}
+
+impl OptionDef {
+    pub fn default_value(&self) -> Value {
+        parse_value(self, self.default).unwrap()
+    }
+}
+
+impl fmt::Debug for OptionDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OptionDef({})", self.name)
+    }
+}
+
+impl PartialEq for OptionDef {
+    fn eq(&self, other: &OptionDef) -> bool {
+        self.name == other.name
+    }
+}
+
+macro_rules! option {
+    ($name:expr, $abbrev:expr, $kind:ident, $scope:ident, $default:expr) => {
+        option!($name, $abbrev, $kind, $scope, $default, &[])
+    };
+    ($name:expr, $abbrev:expr, $kind:ident, $scope:ident, $default:expr, $choices:expr) => {
+        OptionDef {
+            name: $name,
+            abbrev: $abbrev,
+            kind: Kind::$kind,
+            scope: Scope::$scope,
+            default: $default,
+            choices: $choices,
+        }
+    };
+}
+
+pub static OPTIONS: &[OptionDef] = &[
+    option!("autoindent", "ai", Bool, Buffer, "off"),
+    option!("autoread", "ar", Bool, Global, "off"),
+    option!("backup", "bk", Bool, Global, "off"),
+    option!("backupdir", "bdir", List, Global, ".,~/tmp,~/"),
+    option!("backupext", "bex", String, Global, "~"),
+    option!("bomb", "bomb", Bool, Buffer, "off"),
+    option!("directory", "dir", List, Global, ".,~/tmp,/var/tmp,/tmp"),
+    option!("endofline", "eol", Bool, Buffer, "on"),
+    option!("expandtab", "et", Bool, Buffer, "off"),
+    option!("exrc", "ex", Bool, Global, "off"),
+    option!("fileencoding", "fenc", String, Buffer, "utf-8"),
+    option!("fileencodings", "fencs", List, Global, "ucs-bom,utf-8,latin1"),
+    option!("fileformat", "ff", String, Buffer, "unix", &["unix", "dos", "mac"]),
+    option!("fixendofline", "fixeol", Bool, Buffer, "off"),
+    option!("history", "hi", Number, Global, "50"),
+    option!("ignorecase", "ic", Bool, Global, "off"),
+    option!("list", "list", Bool, Window, "off"),
+    option!("mapleader", "mapleader", String, Global, "\\"),
+    option!("maxmapdepth", "mmd", Number, Global, "1000"),
+    option!("number", "nu", Bool, Window, "off"),
+    option!("numberwidth", "nuw", Number, Window, "4"),
+    option!("readonly", "ro", Bool, Buffer, "off"),
+    option!("relativenumber", "rnu", Bool, Window, "off"),
+    option!("scrolloff", "so", Number, Global, "0"),
+    option!("secure", "secure", Bool, Global, "off"),
+    option!("shell", "sh", String, Global, "sh"),
+    option!("shellcmdflag", "shcf", String, Global, "-c"),
+    option!("shiftwidth", "sw", Number, Buffer, "8"),
+    option!("smartcase", "scs", Bool, Global, "off"),
+    option!("swapfile", "swf", Bool, Buffer, "on"),
+    option!("tabstop", "ts", Number, Buffer, "8"),
+    option!("timeout", "to", Bool, Global, "on"),
+    option!("timeoutlen", "tm", Number, Global, "1000"),
+    option!("updatecount", "uc", Number, Global, "200"),
+    option!("updatetime", "ut", Number, Global, "4000"),
+    option!("wildmenu", "wmnu", Bool, Global, "on"),
+    option!("wildmode", "wim", List, Global, "full", &["full", "longest", "list", "longest:full", "list:full", "list:longest"]),
+    option!("wrap", "wrap", Bool, Window, "on"),
+    option!("wrapscan", "ws", Bool, Global, "on"),
+    option!("writebackup", "wb", Bool, Global, "on"),
+];
+
+/// Options naming programs to run or places to write, which a `.virsrc`
+/// read with `secure` may not change.
+const SECURE: &[&str] = &["backupdir", "directory", "exrc", "secure", "shell", "shellcmdflag"];
+
+pub fn is_secure(def: &OptionDef) -> bool {
+    SECURE.contains(&def.name)
+}
+
+/// Find an option by its full name or abbreviation.
+pub fn lookup(name: &str) -> Option<&'static OptionDef> {
+    OPTIONS.iter().find(|def| { def.name == name || def.abbrev == name })
+}
+
+fn parse_value(def: &OptionDef, text: &str) -> Result<Value, String> {
+    match def.kind {
+        Kind::Bool => match text {
+            "on" => Ok(Value::Bool(true)),
+            "off" => Ok(Value::Bool(false)),
+            _ => Err(format!("E474: Invalid argument: {}={}", def.name, text)),
+        },
+        Kind::Number => match text.parse() {
+            Ok(n) => number(def, n),
+            Err(_) => Err(format!("E521: Number required after =: {}={}", def.name, text)),
+        },
+        Kind::String | Kind::List => {
+            let items: Vec<&str> = if def.kind == Kind::List { text.split(',').collect() } else { vec![text] };
+            if def.choices.is_empty() || items.iter().all(|item| { def.choices.contains(item) }) {
+                Ok(Value::Str(text.to_string()))
+            } else {
+                Err(format!("E474: Invalid argument: {}={}", def.name, text))
+            }
+        },
+    }
+}
+
+/// `n` as the value of the number option `def`, if it is not below the
+/// option's minimum.
+fn number(def: &OptionDef, n: i64) -> Result<Value, String> {
+    let minimum = match def.name {
+        "maxmapdepth" | "numberwidth" | "tabstop" => 1,
+        _ => 0,
+    };
+    if n < minimum {
+        return Err(format!("E487: Argument must be positive: {}={}", def.name, n));
+    }
+    Ok(Value::Number(n))
+}
+
+/// Render an option the way `:set` displays it, e.g. `noexpandtab` or
+/// `tabstop=8`.
+pub fn format_option(def: &OptionDef, value: &Value) -> String {
+    match *value {
+        Value::Bool(true) => def.name.to_string(),
+        Value::Bool(false) => format!("no{}", def.name),
+        ref value => format!("{}={}", def.name, value),
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Operator {
+    Assign,
+    Add,
+    Subtract,
+    Prepend,
+}
+
+/// A single argument to `:set`.
+#[derive(Debug,PartialEq)]
+pub enum SetArg {
+    ShowAll,
+    ShowChanged,
+    Show(&'static OptionDef),
+    Enable(&'static OptionDef),
+    Disable(&'static OptionDef),
+    Toggle(&'static OptionDef),
+    Reset(&'static OptionDef),
+    Modify(&'static OptionDef, Operator, String),
+}
+
+fn find(name: &str) -> Result<&'static OptionDef, String> {
+    lookup(name).ok_or_else(|| { format!("E518: Unknown option: {}", name) })
+}
+
+pub fn parse_set_arg(arg: &str) -> Result<SetArg, String> {
+    if arg == "all" {
+        return Ok(SetArg::ShowAll);
+    }
+    if let Some(position) = arg.find(|c| { c == '=' || c == ':' }) {
+        let (name, value) = arg.split_at(position);
+        let value = value[1..].to_string();
+        let (name, operator) = if let Some(name) = name.strip_suffix('+') {
+            (name, Operator::Add)
+        } else if let Some(name) = name.strip_suffix('-') {
+            (name, Operator::Subtract)
+        } else if let Some(name) = name.strip_suffix('^') {
+            (name, Operator::Prepend)
+        } else {
+            (name, Operator::Assign)
+        };
+        let def = find(name)?;
+        if def.kind == Kind::Bool {
+            return Err(format!("E474: Invalid argument: {}", arg));
+        }
+        return Ok(SetArg::Modify(def, operator, value));
+    }
+    if let Some(name) = arg.strip_suffix('?') {
+        return find(name).map(SetArg::Show);
+    }
+    if let Some(name) = arg.strip_suffix('&') {
+        return find(name).map(SetArg::Reset);
+    }
+    if let Some(name) = arg.strip_suffix('!') {
+        return find(name).and_then(|def| { toggle(def, arg) });
+    }
+    if let Some(def) = lookup(arg) {
+        return Ok(match def.kind {
+            Kind::Bool => SetArg::Enable(def),
+            _ => SetArg::Show(def),
+        });
+    }
+    if let Some(name) = arg.strip_prefix("no") {
+        return find(name).and_then(|def| {
+            if def.kind == Kind::Bool { Ok(SetArg::Disable(def)) } else { Err(format!("E474: Invalid argument: {}", arg)) }
+        });
+    }
+    if let Some(name) = arg.strip_prefix("inv") {
+        return find(name).and_then(|def| { toggle(def, arg) });
+    }
+    Err(format!("E518: Unknown option: {}", arg))
+}
+
+fn toggle(def: &'static OptionDef, arg: &str) -> Result<SetArg, String> {
+    if def.kind == Kind::Bool {
+        Ok(SetArg::Toggle(def))
+    } else {
+        Err(format!("E474: Invalid argument: {}", arg))
+    }
+}
+
+/// Split the arguments of a `:set` command on whitespace, allowing spaces
+/// to be escaped with a backslash.
+pub fn split_set_args(args: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) => current.push(next),
+                None => current.push(c),
+            },
+            ' ' | '\t' => if !current.is_empty() {
+                parts.push(current);
+                current = String::new();
+            },
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Compute the new value of an option for `:set opt=val`, `opt+=val`,
+/// `opt-=val` and `opt^=val`.
+pub fn apply(def: &OptionDef, current: &Value, operator: Operator, text: &str) -> Result<Value, String> {
+    let value = parse_value(def, text)?;
+    if operator == Operator::Assign {
+        return Ok(value);
+    }
+    match (current, value) {
+        (&Value::Number(current), Value::Number(n)) => {
+            let result = match operator {
+                Operator::Add => current.checked_add(n),
+                Operator::Subtract => current.checked_sub(n),
+                _ => current.checked_mul(n),
+            };
+            number(def, result.ok_or_else(|| { format!("E474: Invalid argument: {}", def.name) })?)
+        },
+        (Value::Str(current), Value::Str(s)) => {
+            let separator = if def.kind == Kind::List && !current.is_empty() && !s.is_empty() { "," } else { "" };
+            let joined = match operator {
+                Operator::Add => format!("{}{}{}", current, separator, s),
+                Operator::Prepend => format!("{}{}{}", s, separator, current),
+                _ if def.kind == Kind::List => {
+                    current.split(',').filter(|item| { *item != s }).collect::<Vec<_>>().join(",")
+                },
+                _ => current.replacen(s.as_str(), "", 1),
+            };
+            parse_value(def, &joined)
+        },
+        _ => Err(format!("E474: Invalid argument: {}", def.name)),
+    }
+}
+
+/// A set of option values.  The engine keeps one holding every global
+/// value; buffers and windows keep one holding only their local overrides.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Options {
+    values: HashMap<&'static str, Value>,
+}
+
+impl Options {
+    /// An empty set of overrides.
+    pub fn new() -> Self {
+        Options { values: HashMap::new() }
+    }
+
+    /// Every option at its default value.
+    pub fn defaults() -> Self {
+        let mut options = Options::new();
+        for def in OPTIONS.iter() {
+            options.values.insert(def.name, def.default_value());
+        }
+        if let Ok(shell) = env::var("SHELL") {
+            options.values.insert("shell", Value::Str(shell));
+        }
+        options
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        lookup(name).and_then(|def| { self.values.get(def.name) })
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        if let Some(def) = lookup(name) {
+            self.values.insert(def.name, value);
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        if let Some(def) = lookup(name) {
+            self.values.remove(def.name);
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_by_abbreviation() {
+        assert_eq!(lookup("ts").map(|def| { def.name }), Some("tabstop"));
+        assert_eq!(lookup("tabstop").map(|def| { def.name }), Some("tabstop"));
+        assert!(lookup("tabsto").is_none());
+    }
+
+    #[test]
+    fn defaults_parse() {
+        let defaults = Options::defaults();
+        assert_eq!(defaults.get("ts"), Some(&Value::Number(8)));
+        assert_eq!(defaults.get("wrapscan"), Some(&Value::Bool(true)));
+        assert_eq!(defaults.get("ff"), Some(&Value::Str("unix".to_string())));
+    }
+
+    #[test]
+    fn parse_set_args() {
+        let ts = lookup("ts").unwrap();
+        let et = lookup("et").unwrap();
+        assert_eq!(parse_set_arg("all"), Ok(SetArg::ShowAll));
+        assert_eq!(parse_set_arg("et"), Ok(SetArg::Enable(et)));
+        assert_eq!(parse_set_arg("noet"), Ok(SetArg::Disable(et)));
+        assert_eq!(parse_set_arg("invet"), Ok(SetArg::Toggle(et)));
+        assert_eq!(parse_set_arg("et!"), Ok(SetArg::Toggle(et)));
+        assert_eq!(parse_set_arg("ts"), Ok(SetArg::Show(ts)));
+        assert_eq!(parse_set_arg("et?"), Ok(SetArg::Show(et)));
+        assert_eq!(parse_set_arg("ts&"), Ok(SetArg::Reset(ts)));
+        assert_eq!(parse_set_arg("ts=4"), Ok(SetArg::Modify(ts, Operator::Assign, "4".to_string())));
+        assert_eq!(parse_set_arg("ts+=4"), Ok(SetArg::Modify(ts, Operator::Add, "4".to_string())));
+        assert_eq!(parse_set_arg("ts:4"), Ok(SetArg::Modify(ts, Operator::Assign, "4".to_string())));
+    }
+
+    #[test]
+    fn bad_set_args() {
+        assert_eq!(parse_set_arg("frobnicate"), Err("E518: Unknown option: frobnicate".to_string()));
+        assert_eq!(parse_set_arg("nots"), Err("E474: Invalid argument: nots".to_string()));
+        assert_eq!(parse_set_arg("et=1"), Err("E474: Invalid argument: et=1".to_string()));
+    }
+
+    #[test]
+    fn apply_operators() {
+        let ts = lookup("ts").unwrap();
+        let ff = lookup("ff").unwrap();
+        assert_eq!(apply(ts, &Value::Number(8), Operator::Add, "2"), Ok(Value::Number(10)));
+        assert_eq!(apply(ts, &Value::Number(8), Operator::Subtract, "2"), Ok(Value::Number(6)));
+        assert!(apply(ts, &Value::Number(8), Operator::Assign, "x").is_err());
+        assert_eq!(apply(ts, &Value::Number(8), Operator::Assign, "0"), Err("E487: Argument must be positive: tabstop=0".to_string()));
+        assert!(apply(ts, &Value::Number(8), Operator::Subtract, "8").is_err());
+        assert!(apply(ts, &Value::Number(8), Operator::Add, "9223372036854775807").is_err());
+        assert!(apply(lookup("sw").unwrap(), &Value::Number(8), Operator::Assign, "-1").is_err());
+        assert_eq!(apply(lookup("sw").unwrap(), &Value::Number(8), Operator::Assign, "0"), Ok(Value::Number(0)));
+        assert!(apply(lookup("nuw").unwrap(), &Value::Number(4), Operator::Assign, "-1").is_err());
+        assert_eq!(apply(ff, &Value::Str("unix".to_string()), Operator::Assign, "dos"), Ok(Value::Str("dos".to_string())));
+        assert!(apply(ff, &Value::Str("unix".to_string()), Operator::Assign, "amiga").is_err());
+        let wim = lookup("wim").unwrap();
+        assert_eq!(apply(wim, &Value::Str("full".to_string()), Operator::Assign, "longest:full,full"), Ok(Value::Str("longest:full,full".to_string())));
+        assert!(apply(wim, &Value::Str("full".to_string()), Operator::Add, "bogus").is_err());
+    }
+
+    #[test]
+    fn split_escaped_spaces() {
+        assert_eq!(split_set_args(" ts=4  sw=4 "), vec!["ts=4".to_string(), "sw=4".to_string()]);
+        assert_eq!(split_set_args("ff=a\\ b"), vec!["ff=a b".to_string()]);
+    }
+
+    #[test]
+    fn format_values() {
+        assert_eq!(format_option(lookup("et").unwrap(), &Value::Bool(false)), "noexpandtab");
+        assert_eq!(format_option(lookup("ts").unwrap(), &Value::Number(4)), "tabstop=4");
+    }
+}
@@ -0,0 +1,444 @@
+//! Vim's regular expressions with `magic` on, as far as `:sort` needs them:
+//! `.`, `[]` collections, `*`, `\+`, `\=`, `\?`, `\{n,m}`, `^`, `$`, `\<`,
+//! `\>`, `\(\)`, `\|` and the character classes `\d`, `\s`, `\w` and their
+//! relatives.  Matching backtracks, so the first alternative that matches
+//! wins and repeats are greedy, as in vim.
+
+/// A character class, as named by `\d`, `[:digit:]` and the like.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Class {
+    Digit,
+    Octal,
+    Hex,
+    Space,
+    Word,
+    Head,
+    Alpha,
+    Alnum,
+    Lower,
+    Upper,
+    Punct,
+}
+
+impl Class {
+    fn contains(self, c: char) -> bool {
+        match self {
+            Class::Digit => c.is_ascii_digit(),
+            Class::Octal => ('0' ..= '7').contains(&c),
+            Class::Hex => c.is_ascii_hexdigit(),
+            Class::Space => c == ' ' || c == '\t',
+            Class::Word => c.is_ascii_alphanumeric() || c == '_',
+            Class::Head => c.is_ascii_alphabetic() || c == '_',
+            Class::Alpha => c.is_ascii_alphabetic(),
+            Class::Alnum => c.is_ascii_alphanumeric(),
+            Class::Lower => c.is_ascii_lowercase(),
+            Class::Upper => c.is_ascii_uppercase(),
+            Class::Punct => c.is_ascii_punctuation(),
+        }
+    }
+
+    /// The class `\{letter}` stands for, and whether it is negated.
+    fn escaped(letter: char) -> Option<(Class, bool)> {
+        let class = match letter.to_ascii_lowercase() {
+            'd' => Class::Digit,
+            'o' => Class::Octal,
+            'x' => Class::Hex,
+            's' => Class::Space,
+            'w' => Class::Word,
+            'h' => Class::Head,
+            'a' => Class::Alpha,
+            'l' => Class::Lower,
+            'u' => Class::Upper,
+            _ => return None,
+        };
+        Some((class, letter.is_ascii_uppercase()))
+    }
+
+    fn named(name: &str) -> Option<Class> {
+        match name {
+            "digit" => Some(Class::Digit),
+            "xdigit" => Some(Class::Hex),
+            "space" | "blank" => Some(Class::Space),
+            "alpha" => Some(Class::Alpha),
+            "alnum" => Some(Class::Alnum),
+            "lower" => Some(Class::Lower),
+            "upper" => Some(Class::Upper),
+            "punct" => Some(Class::Punct),
+            _ => None,
+        }
+    }
+}
+
+/// One item of a `[]` collection.
+#[derive(Debug,Clone,PartialEq)]
+enum Item {
+    Char(char),
+    Range(char, char),
+    Class(Class),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Node {
+    Char(char),
+    Any,
+    Collection(bool, Vec<Item>),
+    Start,
+    End,
+    WordStart,
+    WordEnd,
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+impl Node {
+    /// Whether the node matches `c`, for nodes that match one character.
+    fn accepts(&self, c: char) -> bool {
+        match *self {
+            Node::Char(expected) => c == expected,
+            Node::Any => true,
+            Node::Collection(negated, ref items) => {
+                let found = items.iter().any(|item| {
+                    match *item {
+                        Item::Char(expected) => c == expected,
+                        Item::Range(low, high) => low <= c && c <= high,
+                        Item::Class(class) => class.contains(c),
+                    }
+                });
+                found != negated
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A compiled pattern.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Pattern {
+    source: String,
+    alternatives: Vec<Vec<Node>>,
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Pattern, String> {
+        let mut parser = Parser { chars: source.chars().collect(), at: 0 };
+        let alternatives = parser.alternatives()?;
+        if parser.at < parser.chars.len() {
+            return Err("E55: Unmatched \\)".to_string());
+        }
+        Ok(Pattern { source: source.to_string(), alternatives })
+    }
+
+    /// The byte range of the first match in `text`.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let matcher = Matcher { chars: &chars };
+        let offset = |i: usize| { chars.get(i).map(|&(offset, _)| { offset }).unwrap_or(text.len()) };
+        for start in 0 ..= chars.len() {
+            let mut end = None;
+            let found = self.alternatives.iter().any(|nodes| {
+                matcher.sequence(nodes, start, &mut |at| {
+                    end = Some(at);
+                    true
+                })
+            });
+            if found {
+                return end.map(|end| { (offset(start), offset(end)) });
+            }
+        }
+        None
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.at + ahead).cloned()
+    }
+
+    /// Branches separated by `\|`, up to a `\)` or the end.
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.branch()?];
+        while self.peek(0) == Some('\\') && self.peek(1) == Some('|') {
+            self.at += 2;
+            alternatives.push(self.branch()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn branch(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(c) = self.peek(0) {
+            let next = self.peek(1);
+            if c == '\\' && (next == Some('|') || next == Some(')')) {
+                break;
+            }
+            let multi = match (c, next) {
+                ('*', _) => Some((1, 0, None)),
+                ('\\', Some('+')) => Some((2, 1, None)),
+                ('\\', Some('=')) | ('\\', Some('?')) => Some((2, 0, Some(1))),
+                ('\\', Some('{')) => Some((2, 0, None)),
+                _ => None,
+            };
+            let name = || { if c == '*' { "*".to_string() } else { format!("\\{}", next.unwrap_or(' ')) } };
+            if let Some((length, mut min, mut max)) = multi {
+                match nodes.last() {
+                    Some(&Node::Repeat(..)) => return Err(format!("E61: Nested {}", name())),
+                    // A `*` with nothing to repeat is taken literally.
+                    Some(&Node::Start) | None if c == '*' => {},
+                    Some(&Node::Start) | None => return Err(format!("E64: {} follows nothing", name())),
+                    Some(_) => {
+                        self.at += length;
+                        if c == '\\' && next == Some('{') {
+                            let bounds = self.bounds()?;
+                            min = bounds.0;
+                            max = bounds.1;
+                        }
+                        if let Some(atom) = nodes.pop() {
+                            nodes.push(Node::Repeat(Box::new(atom), min, max));
+                        }
+                        continue;
+                    },
+                }
+            }
+            let node = self.atom(nodes.is_empty())?;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    /// The `n,m}` after `\{`.
+    fn bounds(&mut self) -> Result<(usize, Option<usize>), String> {
+        let close = self.chars[self.at ..].iter().position(|&c| { c == '}' })
+            .ok_or_else(|| { "E554: Syntax error in \\{...}".to_string() })?;
+        let inside: String = self.chars[self.at .. self.at + close].iter().collect();
+        self.at += close + 1;
+        let inside = inside.trim_end_matches('\\');
+        let number = |text: &str| -> Result<Option<usize>, String> {
+            match text {
+                "" => Ok(None),
+                text => text.parse().map(Some).map_err(|_| { "E554: Syntax error in \\{...}".to_string() }),
+            }
+        };
+        match inside.find(',') {
+            Some(i) => Ok((number(&inside[.. i])?.unwrap_or(0), number(&inside[i + 1 ..])?)),
+            None => {
+                let count = number(inside)?;
+                Ok((count.unwrap_or(0), count))
+            },
+        }
+    }
+
+    fn atom(&mut self, first: bool) -> Result<Node, String> {
+        let c = self.chars[self.at];
+        self.at += 1;
+        let node = match c {
+            '^' if first => Node::Start,
+            '$' if self.at_branch_end() => Node::End,
+            '.' => Node::Any,
+            '[' => self.collection().unwrap_or(Node::Char('[')),
+            '~' => return Err("E33: No previous substitute regular expression".to_string()),
+            '\\' => {
+                let escaped = match self.peek(0) {
+                    Some(escaped) => escaped,
+                    None => return Ok(Node::Char('\\')),
+                };
+                self.at += 1;
+                match escaped {
+                    '(' => {
+                        let alternatives = self.alternatives()?;
+                        if self.peek(0) != Some('\\') || self.peek(1) != Some(')') {
+                            return Err("E54: Unmatched \\(".to_string());
+                        }
+                        self.at += 2;
+                        Node::Group(alternatives)
+                    },
+                    '<' => Node::WordStart,
+                    '>' => Node::WordEnd,
+                    't' => Node::Char('\t'),
+                    'e' => Node::Char('\x1b'),
+                    'n' => Node::Char('\n'),
+                    c if c.is_ascii_alphanumeric() => match Class::escaped(c) {
+                        Some((class, negated)) => Node::Collection(negated, vec![Item::Class(class)]),
+                        None => return Err(format!("E474: Invalid argument: \\{}", c)),
+                    },
+                    c => Node::Char(c),
+                }
+            },
+            c => Node::Char(c),
+        };
+        Ok(node)
+    }
+
+    /// Whether nothing but the end of a branch follows, where `$` is an
+    /// anchor.
+    fn at_branch_end(&self) -> bool {
+        matches!((self.peek(0), self.peek(1)), (None, _) | (Some('\\'), Some('|')) | (Some('\\'), Some(')')))
+    }
+
+    /// The rest of a `[]` collection, or `None` without the closing `]`, in
+    /// which case `[` is taken literally.
+    fn collection(&mut self) -> Option<Node> {
+        let start = self.at;
+        let negated = self.peek(0) == Some('^');
+        if negated {
+            self.at += 1;
+        }
+        let mut items = vec![];
+        // A `]` straight after `[` or `[^` is part of the collection.
+        if self.peek(0) == Some(']') {
+            items.push(Item::Char(']'));
+            self.at += 1;
+        }
+        loop {
+            let c = match self.peek(0) {
+                Some(']') => {
+                    self.at += 1;
+                    return Some(Node::Collection(negated, items));
+                },
+                Some(c) => c,
+                None => {
+                    self.at = start;
+                    return None;
+                },
+            };
+            self.at += 1;
+            let c = match c {
+                '[' if self.peek(0) == Some(':') => {
+                    let rest: String = self.chars[self.at ..].iter().collect();
+                    let named = rest[1 ..].find(":]").and_then(|end| {
+                        Class::named(&rest[1 .. 1 + end]).map(|class| { (class, rest[.. 1 + end].chars().count() + 2) })
+                    });
+                    if let Some((class, length)) = named {
+                        items.push(Item::Class(class));
+                        self.at += length;
+                        continue;
+                    }
+                    '['
+                },
+                '\\' => match self.peek(0) {
+                    Some(escaped @ ']') | Some(escaped @ '\\') | Some(escaped @ '-') | Some(escaped @ '^') => {
+                        self.at += 1;
+                        escaped
+                    },
+                    Some('t') => {
+                        self.at += 1;
+                        '\t'
+                    },
+                    Some('e') => {
+                        self.at += 1;
+                        '\x1b'
+                    },
+                    _ => '\\',
+                },
+                c => c,
+            };
+            match (self.peek(0), self.peek(1)) {
+                (Some('-'), Some(high)) if high != ']' => {
+                    self.at += 2;
+                    items.push(Item::Range(c, high));
+                },
+                _ => items.push(Item::Char(c)),
+            }
+        }
+    }
+}
+
+struct Matcher<'a> {
+    chars: &'a [(usize, char)],
+}
+
+impl <'a> Matcher<'a> {
+    fn is_word(&self, at: usize) -> bool {
+        self.chars.get(at).is_some_and(|&(_, c)| { Class::Word.contains(c) })
+    }
+
+    /// Match `nodes` from character `at`, calling `next` with where each
+    /// way of matching them ends until it returns true.
+    fn sequence(&self, nodes: &[Node], at: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        let (node, rest) = match nodes.split_first() {
+            Some(split) => split,
+            None => return next(at),
+        };
+        match *node {
+            Node::Repeat(ref atom, min, max) => self.repeat(atom, (min, max), 0, at, rest, next),
+            ref atom => self.node(atom, at, &mut |end| { self.sequence(rest, end, next) }),
+        }
+    }
+
+    fn node(&self, node: &Node, at: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        match *node {
+            Node::Start => at == 0 && next(at),
+            Node::End => at == self.chars.len() && next(at),
+            Node::WordStart => self.is_word(at) && (at == 0 || !self.is_word(at - 1)) && next(at),
+            Node::WordEnd => at > 0 && self.is_word(at - 1) && !self.is_word(at) && next(at),
+            Node::Group(ref alternatives) => {
+                alternatives.iter().any(|nodes| { self.sequence(nodes, at, next) })
+            },
+            Node::Repeat(ref atom, min, max) => self.repeat(atom, (min, max), 0, at, &[], next),
+            ref single => match self.chars.get(at) {
+                Some(&(_, c)) if single.accepts(c) => next(at + 1),
+                _ => false,
+            },
+        }
+    }
+
+    /// Match `atom` as many times as it will go, then fewer, but at least
+    /// `min` and at most `max` times, each followed by `rest`.
+    fn repeat(&self, atom: &Node, (min, max): (usize, Option<usize>), count: usize, at: usize, rest: &[Node],
+              next: &mut dyn FnMut(usize) -> bool) -> bool {
+        if max.is_none_or(|max| { count < max }) {
+            // An atom matching nothing is not repeated, or this never ends.
+            let more = self.node(atom, at, &mut |end| {
+                (end != at || count < min) && self.repeat(atom, (min, max), count + 1, end, rest, next)
+            });
+            if more {
+                return true;
+            }
+        }
+        count >= min && self.sequence(rest, at, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        Pattern::new(pattern).unwrap().find(text)
+    }
+
+    #[test]
+    fn atoms() {
+        assert_eq!(find("b", "abc"), Some((1, 2)));
+        assert_eq!(find("a.c", "xabcx"), Some((1, 4)));
+        assert_eq!(find("\\d\\+", "id 42, 7"), Some((3, 5)));
+        assert_eq!(find("\\s*\\a", "  ab"), Some((0, 3)));
+        assert_eq!(find("[0-9a-f]\\{2}", "xyz 3f9"), Some((4, 6)));
+        assert_eq!(find("[^ ]*$", "one two"), Some((4, 7)));
+        assert_eq!(find("[[:upper:]]", "abC"), Some((2, 3)));
+        assert_eq!(find("^a", "ba"), None);
+        assert_eq!(find("a^b$c", "a^b$c"), Some((0, 5)));
+        assert_eq!(find("\\<is\\>", "this is"), Some((5, 7)));
+        assert_eq!(find("x\\=y", "y"), Some((0, 1)));
+        assert_eq!(find("\\(ab\\)*c", "ababc"), Some((0, 5)));
+        assert_eq!(find("foo\\|ba\\(r\\|z\\)", "a baz"), Some((2, 5)));
+        assert_eq!(find("*a\\.", "*a."), Some((0, 3)));
+        assert_eq!(find("[a", "[a"), Some((0, 2)));
+        assert_eq!(find("é.", "aéb"), Some((1, 4)));
+        assert_eq!(find("\\(\\)*x", "x"), Some((0, 1)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Pattern::new("\\(a").unwrap_err().starts_with("E54"));
+        assert!(Pattern::new("a\\)").unwrap_err().starts_with("E55"));
+        assert!(Pattern::new("a**").unwrap_err().starts_with("E61"));
+        assert!(Pattern::new("\\+a").unwrap_err().starts_with("E64"));
+        assert!(Pattern::new("a\\{1").unwrap_err().starts_with("E554"));
+        assert!(Pattern::new("\\zs").unwrap_err().starts_with("E474"));
+    }
+}
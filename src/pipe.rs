@@ -0,0 +1,26 @@
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+
+/// Run `process`, feeding it `input` and collecting what it prints.
+pub fn run_piped(mut process: process::Command, input: &[u8]) -> io::Result<process::Output> {
+    let mut child = process
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    // Feed the input from another thread so a command that prints while it
+    // reads cannot fill its output pipe and deadlock with us.
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.to_vec();
+        thread::spawn(move || {
+            // A command that exits without reading its input is not an error.
+            stdin.write_all(&input).ok();
+        })
+    });
+    let output = child.wait_with_output();
+    if let Some(writer) = writer {
+        writer.join().ok();
+    }
+    output
+}
@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+
+use ex::{SortKey, SortOptions};
+
+/// What a line is sorted by.
+#[derive(Debug,PartialEq)]
+enum Key {
+    Text(String),
+    /// Lines without a number sort before all others.
+    Integer(Option<i128>),
+    Float(Option<f64>),
+}
+
+impl Key {
+    fn compare(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            (Key::Text(a), Key::Text(b)) => a.cmp(b),
+            (Key::Integer(a), Key::Integer(b)) => a.cmp(b),
+            (Key::Float(a), Key::Float(b)) => match (a, b) {
+                (Some(a), Some(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+                _ => a.is_some().cmp(&b.is_some()),
+            },
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// The part of `line` the key is taken from, or `None` if the pattern
+/// does not match it.
+fn key_text<'a>(line: &'a str, options: &SortOptions) -> Option<&'a str> {
+    match options.pattern {
+        None => Some(line),
+        Some(ref pattern) => pattern.find(line).map(|(start, end)| {
+            if options.use_match { &line[start .. end] } else { &line[end ..] }
+        }),
+    }
+}
+
+/// The first integer in `text` written in `radix`, with an optional minus
+/// sign and, for hex and binary, an optional `0x` or `0b` prefix.
+fn find_integer(text: &str, radix: u32) -> Option<i128> {
+    let start = text.find(|c: char| { c.is_digit(radix) })?;
+    let mut digits = &text[start ..];
+    let prefix = match radix {
+        16 => Some(['x', 'X']),
+        2 => Some(['b', 'B']),
+        _ => None,
+    };
+    if let Some(prefix) = prefix {
+        let mut chars = digits.chars();
+        if chars.next() == Some('0') && chars.next().is_some_and(|c| { prefix.contains(&c) })
+            && chars.next().is_some_and(|c| { c.is_digit(radix) }) {
+            digits = &digits[2 ..];
+        }
+    }
+    let end = digits.find(|c: char| { !c.is_digit(radix) }).unwrap_or(digits.len());
+    let negative = text[.. start].ends_with('-');
+    let value = i128::from_str_radix(&digits[.. end], radix)
+        .unwrap_or(i128::MAX);
+    Some(if negative { -value } else { value })
+}
+
+/// The first floating point number in `text`, e.g. `-1.5e3`.
+fn find_float(text: &str) -> Option<f64> {
+    let bytes = text.as_bytes();
+    for start in 0 .. bytes.len() {
+        let begins = bytes[start].is_ascii_digit()
+            || ((bytes[start] == b'-' || bytes[start] == b'.') && bytes.get(start + 1).is_some_and(|b| { b.is_ascii_digit() }));
+        if !begins {
+            continue;
+        }
+        let candidate = &text[start ..];
+        let end = candidate.find(|c: char| { !(c.is_ascii_digit() || ".eE+-".contains(c)) }).unwrap_or(candidate.len());
+        // Take the longest prefix that parses, e.g. `2` out of `2-3`.
+        for end in (1 ..= end).rev() {
+            if let Ok(value) = candidate[.. end].parse() {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+fn key(text: &str, options: &SortOptions) -> Key {
+    match options.key {
+        SortKey::Text if options.ignore_case => Key::Text(text.to_lowercase()),
+        SortKey::Text => Key::Text(text.to_string()),
+        SortKey::Decimal => Key::Integer(find_integer(text, 10)),
+        SortKey::Hex => Key::Integer(find_integer(text, 16)),
+        SortKey::Octal => Key::Integer(find_integer(text, 8)),
+        SortKey::Binary => Key::Integer(find_integer(text, 2)),
+        SortKey::Float => Key::Float(find_float(text)),
+    }
+}
+
+/// Sort `lines` the way `:sort` does.  The sort is stable and compares text
+/// byte by byte, independent of the locale.  With a pattern, lines that do
+/// not contain it keep their order and go before the sorted lines, or
+/// after them in reverse order when sorting in reverse.
+pub fn sort_lines(lines: &[String], options: &SortOptions) -> Vec<String> {
+    let mut keyed = vec![];
+    let mut unmatched = vec![];
+    for line in lines {
+        match key_text(line, options) {
+            Some(text) => keyed.push((key(text, options), line.clone())),
+            None => unmatched.push(line.clone()),
+        }
+    }
+    keyed.sort_by(|a, b| {
+        let ordering = a.0.compare(&b.0);
+        if options.reverse { ordering.reverse() } else { ordering }
+    });
+    if options.unique {
+        keyed.dedup_by(|b, a| { a.0.compare(&b.0) == Ordering::Equal });
+    }
+    let sorted = keyed.into_iter().map(|(_, line)| { line });
+    if options.reverse {
+        sorted.chain(unmatched.into_iter().rev()).collect()
+    } else {
+        unmatched.into_iter().chain(sorted).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pattern::Pattern;
+
+    fn sort(lines: &[&str], options: SortOptions) -> Vec<String> {
+        let lines: Vec<String> = lines.iter().map(|line| { line.to_string() }).collect();
+        sort_lines(&lines, &options)
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(sort(&["b", "B", "a", "_"], SortOptions::default()), vec!["B", "_", "a", "b"]);
+        assert_eq!(sort(&["b", "B", "a"], SortOptions { ignore_case: true, ..SortOptions::default() }), vec!["a", "b", "B"]);
+        assert_eq!(sort(&["b", "a", "b", "B"], SortOptions { unique: true, ignore_case: true, ..SortOptions::default() }), vec!["a", "b"]);
+        assert_eq!(sort(&["a", "c", "b"], SortOptions { reverse: true, ..SortOptions::default() }), vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn numbers() {
+        let numeric = SortOptions { key: SortKey::Decimal, ..SortOptions::default() };
+        assert_eq!(sort(&["x10", "x9", "none", "x-2", "y9"], numeric), vec!["none", "x-2", "x9", "y9", "x10"]);
+        let hex = SortOptions { key: SortKey::Hex, ..SortOptions::default() };
+        assert_eq!(sort(&["0x1F", "0xa", "0x100"], hex), vec!["0xa", "0x1F", "0x100"]);
+        let binary = SortOptions { key: SortKey::Binary, ..SortOptions::default() };
+        assert_eq!(sort(&["0b110", "0b11", "1"], binary), vec!["1", "0b11", "0b110"]);
+        let octal = SortOptions { key: SortKey::Octal, ..SortOptions::default() };
+        assert_eq!(sort(&["17", "8 then 7", "2"], octal), vec!["2", "8 then 7", "17"]);
+        let float = SortOptions { key: SortKey::Float, ..SortOptions::default() };
+        assert_eq!(sort(&["v 1.5e1", "v -.5", "v 2-3", "nan"], float), vec!["nan", "v -.5", "v 2-3", "v 1.5e1"]);
+        let unique = SortOptions { key: SortKey::Decimal, unique: true, ..SortOptions::default() };
+        assert_eq!(sort(&["a 1", "b 01", "c 2"], unique), vec!["a 1", "c 2"]);
+    }
+
+    #[test]
+    fn pattern() {
+        let after = SortOptions { pattern: Some(Pattern::new("id=").unwrap()), ..SortOptions::default() };
+        assert_eq!(sort(&["z id=2", "header", "a id=3", "b id=1"], after), vec!["header", "b id=1", "z id=2", "a id=3"]);
+        let reverse = SortOptions { pattern: Some(Pattern::new("id=").unwrap()), reverse: true, ..SortOptions::default() };
+        assert_eq!(sort(&["x", "id=1", "y", "id=2"], reverse), vec!["id=2", "id=1", "y", "x"]);
+        let on_match = SortOptions { pattern: Some(Pattern::new("B").unwrap()), use_match: true, ..SortOptions::default() };
+        assert_eq!(sort(&["2 B", "1 B", "A"], on_match), vec!["A", "2 B", "1 B"]);
+        let regex = SortOptions { pattern: Some(Pattern::new("^\\a\\d\\+ ").unwrap()), ..SortOptions::default() };
+        assert_eq!(sort(&["x1 b", "y22 a", "z b", "3 c"], regex), vec!["z b", "3 c", "y22 a", "x1 b"]);
+    }
+}
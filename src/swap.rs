@@ -0,0 +1,275 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use config;
+
+const MAGIC: &str = "virs swap 1";
+const EXTENSIONS: [&str; 4] = ["swp", "swo", "swn", "swm"];
+
+/// A journal of a buffer's unsaved contents, kept next to the file (or in
+/// one of the `directory` option's directories) while it is being edited.
+#[derive(Debug,PartialEq)]
+pub struct SwapFile {
+    pub path: PathBuf,
+    /// The file being edited, as an absolute path.
+    pub file: PathBuf,
+    pub pid: u32,
+    /// The buffer's change counter when the swap was last written.
+    pub tick: u64,
+}
+
+/// The contents of a swap file left behind by another session.
+#[derive(Debug,PartialEq)]
+pub struct Journal {
+    pub swap: SwapFile,
+    pub content: Vec<String>,
+}
+
+/// What to do when opening a file that already has a swap file.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Choice {
+    OpenReadOnly,
+    EditAnyway,
+    Recover,
+    Delete,
+    Quit,
+}
+
+impl Choice {
+    pub fn from_key(key: char) -> Option<Self> {
+        match key.to_ascii_lowercase() {
+            'o' => Some(Choice::OpenReadOnly),
+            'e' => Some(Choice::EditAnyway),
+            'r' => Some(Choice::Recover),
+            'd' => Some(Choice::Delete),
+            'q' => Some(Choice::Quit),
+            _ => None,
+        }
+    }
+}
+
+fn absolute(file: &Path) -> PathBuf {
+    if file.is_absolute() {
+        file.to_path_buf()
+    } else {
+        env::current_dir().map(|dir| { dir.join(file) }).unwrap_or_else(|_| { file.to_path_buf() })
+    }
+}
+
+/// The directories named by the comma-separated `directory` option, each
+/// paired with whether it is `.`, meaning `file_dir`, the directory of the
+/// edited file.
+fn directories(file_dir: &Path, directory: &str) -> Vec<(PathBuf, bool)> {
+    directory.split(',').filter(|dir| { !dir.is_empty() }).map(|dir| {
+        if dir == "." {
+            (file_dir.to_path_buf(), true)
+        } else {
+            (config::expand_home(dir), false)
+        }
+    }).collect()
+}
+
+/// Candidate swap file names for `file` in `dir`, in the order they are
+/// tried.  Next to the file the swap is hidden (`.name.swp`); elsewhere the
+/// full path is encoded into the name with `%` so files with the same name
+/// do not collide.
+pub fn candidates(file: &Path, dir: &Path, local: bool) -> Vec<PathBuf> {
+    let absolute = absolute(file);
+    let base = if local {
+        format!(".{}", absolute.file_name().map(|name| { name.to_string_lossy().into_owned() }).unwrap_or_default())
+    } else {
+        absolute.to_string_lossy().replace('/', "%")
+    };
+    EXTENSIONS.iter().map(|ext| { dir.join(format!("{}.{}", base, ext)) }).collect()
+}
+
+fn all_candidates(file: &Path, directory: &str) -> Vec<PathBuf> {
+    let file_dir = absolute(file).parent().map(|parent| { parent.to_path_buf() }).unwrap_or_default();
+    let mut all = vec![];
+    for (dir, local) in directories(&file_dir, directory) {
+        all.extend(candidates(file, &dir, local));
+    }
+    all
+}
+
+/// Look for a swap file another session left for `file`.
+pub fn find_existing(file: &Path, directory: &str) -> Option<Journal> {
+    all_candidates(file, directory).into_iter().filter_map(|path| { read(&path).ok() }).next()
+}
+
+/// Create a fresh swap file for `file` in the first usable directory,
+/// skipping names already in use.
+pub fn create(file: &Path, directory: &str, content: &[String], tick: u64) -> io::Result<SwapFile> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No usable directory for swap file");
+    for path in all_candidates(file, directory) {
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(_) => {
+                let mut swap = SwapFile { path, file: absolute(file), pid: process::id(), tick };
+                swap.write(content, tick)?;
+                return Ok(swap);
+            },
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+impl SwapFile {
+    /// Journal `content` as of change `tick`.  The journal is written to a
+    /// new file only its owner can read, which then replaces the old one,
+    /// so a crash while writing leaves the previous journal intact.
+    pub fn write(&mut self, content: &[String], tick: u64) -> io::Result<()> {
+        let mut text = format!("{}\npid {}\nfile {}\ntick {}\n\n", MAGIC, self.pid, self.file.display(), tick);
+        for line in content {
+            text.push_str(line);
+            text.push('\n');
+        }
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".new");
+        let temp = self.path.with_file_name(name);
+        let result = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)
+            .and_then(|mut fh| {
+                fh.write_all(text.as_bytes())?;
+                fh.sync_all()
+            })
+            .and_then(|_| { fs::rename(&temp, &self.path) });
+        if result.is_err() {
+            fs::remove_file(&temp).ok();
+        }
+        result?;
+        self.tick = tick;
+        Ok(())
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+
+    /// Whether the session that owns this swap file is still running.
+    pub fn owner_running(&self) -> bool {
+        Path::new(&format!("/proc/{}", self.pid)).exists()
+    }
+
+    /// Whether another session that is still running owns this swap file,
+    /// so it must not be recovered or deleted.
+    pub fn in_use(&self) -> bool {
+        self.pid != process::id() && self.owner_running()
+    }
+}
+
+/// Read a swap file.
+pub fn read(path: &Path) -> io::Result<Journal> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let invalid = || { io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a swap file", path.display())) };
+    let (header, body) = match text.find("\n\n") {
+        Some(i) => (&text[.. i], &text[i + 2 ..]),
+        None => return Err(invalid()),
+    };
+    let mut lines = header.lines();
+    if lines.next() != Some(MAGIC) {
+        return Err(invalid());
+    }
+    let mut swap = SwapFile { path: path.to_path_buf(), file: PathBuf::new(), pid: 0, tick: 0 };
+    for line in lines {
+        let (key, value) = match line.find(' ') {
+            Some(i) => (&line[.. i], &line[i + 1 ..]),
+            None => return Err(invalid()),
+        };
+        match key {
+            "pid" => swap.pid = value.parse().map_err(|_| { invalid() })?,
+            "file" => swap.file = PathBuf::from(value),
+            "tick" => swap.tick = value.parse().map_err(|_| { invalid() })?,
+            _ => {},
+        }
+    }
+    let content = body.lines().map(|line| { line.to_string() }).collect();
+    Ok(Journal { swap, content })
+}
+
+/// Every swap file in the `directory` option's directories, as seen from
+/// the current directory.
+pub fn list(directory: &str) -> Vec<Journal> {
+    let here = env::current_dir().unwrap_or_default();
+    let mut found = vec![];
+    for (dir, _) in directories(&here, directory) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let mut paths: Vec<PathBuf> = entries.filter_map(|entry| { entry.ok().map(|entry| { entry.path() }) })
+            .filter(|path| { path.extension().is_some_and(|ext| { EXTENSIONS.iter().any(|x| { ext == *x }) }) })
+            .collect();
+        paths.sort();
+        found.extend(paths.iter().filter_map(|path| { read(path).ok() }));
+    }
+    found
+}
+
+/// The message shown when `journal` is found while opening a file.
+pub fn attention(journal: &Journal) -> String {
+    format!(
+        "E325: ATTENTION Found a swap file by the name \"{}\" (process ID: {}{}). \
+         [O]pen Read-Only, (E)dit anyway, (R)ecover, (D)elete it, (Q)uit:",
+        journal.swap.path.display(),
+        journal.swap.pid,
+        if journal.swap.owner_running() { ", STILL RUNNING" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn candidate_names() {
+        let local = candidates(Path::new("/home/user/notes.txt"), Path::new("/home/user"), true);
+        assert_eq!(local[0], PathBuf::from("/home/user/.notes.txt.swp"));
+        assert_eq!(local[1], PathBuf::from("/home/user/.notes.txt.swo"));
+        let remote = candidates(Path::new("/home/user/notes.txt"), Path::new("/tmp"), false);
+        assert_eq!(remote[0], PathBuf::from("/tmp/%home%user%notes.txt.swp"));
+    }
+
+    #[test]
+    fn create_find_and_remove() {
+        fs::create_dir_all("/tmp/virs-swap-test").unwrap();
+        let file = Path::new("/tmp/virs-swap-test/file.txt");
+        let content = vec!["unsaved".to_string(), "work".to_string()];
+        let swap = create(file, ".", &content, 3).unwrap();
+        assert_eq!(swap.path, PathBuf::from("/tmp/virs-swap-test/.file.txt.swp"));
+        let second = create(file, ".", &content, 3).unwrap();
+        assert_eq!(second.path, PathBuf::from("/tmp/virs-swap-test/.file.txt.swo"));
+        second.remove().unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&swap.path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!Path::new("/tmp/virs-swap-test/.file.txt.swp.new").exists());
+        let journal = find_existing(file, ".").unwrap();
+        assert_eq!(journal.content, content);
+        assert_eq!(journal.swap, swap);
+        assert!(journal.swap.owner_running());
+        assert!(!journal.swap.in_use());
+        let other = SwapFile { path: PathBuf::new(), file: PathBuf::new(), pid: 1, tick: 0 };
+        assert!(other.in_use());
+
+        swap.remove().unwrap();
+        assert_eq!(find_existing(file, "."), None);
+    }
+
+    #[test]
+    fn read_rejects_other_files() {
+        assert!(read(Path::new("/etc/hostname")).is_err());
+    }
+
+    #[test]
+    fn choices() {
+        assert_eq!(Choice::from_key('R'), Some(Choice::Recover));
+        assert_eq!(Choice::from_key('x'), None);
+    }
+}
@@ -0,0 +1,155 @@
+/// Scrolling commands available in Normal mode.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Scroll {
+    /// `Ctrl-E`
+    LineDown,
+    /// `Ctrl-Y`
+    LineUp,
+    /// `Ctrl-D`
+    HalfPageDown,
+    /// `Ctrl-U`
+    HalfPageUp,
+    /// `Ctrl-F`
+    PageDown,
+    /// `Ctrl-B`
+    PageUp,
+    /// `zt`
+    CursorTop,
+    /// `zz`
+    CursorMiddle,
+    /// `zb`
+    CursorBottom,
+}
+
+/// The rows of a buffer visible on screen.  Lines are counted from 0 here,
+/// unlike the 1-based line numbers used by ex commands.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Viewport {
+    pub top: usize,
+    pub height: usize,
+    pub line_count: usize,
+    pub scrolloff: usize,
+}
+
+impl Viewport {
+    /// `scrolloff` can never keep more than half the screen around the cursor.
+    fn margin(&self) -> usize {
+        let half = self.height.saturating_sub(1) / 2;
+        if self.scrolloff < half { self.scrolloff } else { half }
+    }
+
+    fn max_top(&self) -> usize {
+        self.line_count.saturating_sub(1)
+    }
+
+    fn last_line(&self) -> usize {
+        self.line_count.saturating_sub(1)
+    }
+
+    /// The top line that keeps `cursor` on screen with `scrolloff` lines of
+    /// context, scrolling as little as possible.
+    pub fn follow(&self, cursor: usize) -> usize {
+        let margin = self.margin();
+        let mut top = self.top;
+        if cursor < top + margin {
+            top = cursor.saturating_sub(margin);
+        } else if cursor + margin >= top + self.height {
+            top = cursor + margin + 1 - self.height;
+        }
+        if top > self.max_top() { self.max_top() } else { top }
+    }
+
+    /// Move `cursor` the least distance needed to keep it on screen with
+    /// `scrolloff` lines of context.
+    pub fn clamp_cursor(&self, cursor: usize) -> usize {
+        let margin = self.margin();
+        let first = if self.top == 0 { 0 } else { self.top + margin };
+        let last = if self.top + self.height >= self.line_count {
+            self.last_line()
+        } else {
+            (self.top + self.height - 1).saturating_sub(margin)
+        };
+        if cursor < first {
+            if first < last { first } else { last }
+        } else if cursor > last {
+            last
+        } else {
+            cursor
+        }
+    }
+
+    /// Apply `scroll`, returning the new top line and cursor line.
+    pub fn scroll(&self, cursor: usize, scroll: Scroll) -> (usize, usize) {
+        let half = if self.height / 2 > 0 { self.height / 2 } else { 1 };
+        let page = if self.height > 2 { self.height - 2 } else { 1 };
+        let margin = self.margin();
+        let (top, cursor) = match scroll {
+            Scroll::LineDown => (self.top + 1, cursor),
+            Scroll::LineUp => (self.top.saturating_sub(1), cursor),
+            Scroll::HalfPageDown => (self.top + half, cursor + half),
+            Scroll::HalfPageUp => (self.top.saturating_sub(half), cursor.saturating_sub(half)),
+            Scroll::PageDown => (self.top + page, cursor),
+            Scroll::PageUp => (self.top.saturating_sub(page), cursor),
+            Scroll::CursorTop => (cursor.saturating_sub(margin), cursor),
+            Scroll::CursorMiddle => (cursor.saturating_sub(self.height / 2), cursor),
+            Scroll::CursorBottom => ((cursor + margin + 1).saturating_sub(self.height), cursor),
+        };
+        let top = if top > self.max_top() { self.max_top() } else { top };
+        let cursor = if cursor > self.last_line() { self.last_line() } else { cursor };
+        let scrolled = Viewport { top, ..*self };
+        (top, scrolled.clamp_cursor(cursor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(top: usize) -> Viewport {
+        Viewport { top, height: 10, line_count: 100, scrolloff: 2 }
+    }
+
+    #[test]
+    fn follow_cursor() {
+        assert_eq!(view(0).follow(5), 0);
+        assert_eq!(view(0).follow(8), 1);
+        assert_eq!(view(20).follow(21), 19);
+        assert_eq!(view(20).follow(50), 43);
+        assert_eq!(view(20).follow(0), 0);
+    }
+
+    #[test]
+    fn scroll_lines() {
+        assert_eq!(view(0).scroll(0, Scroll::LineDown), (1, 3));
+        assert_eq!(view(5).scroll(7, Scroll::LineDown), (6, 8));
+        assert_eq!(view(5).scroll(7, Scroll::LineUp), (4, 7));
+        assert_eq!(view(5).scroll(12, Scroll::LineUp), (4, 11));
+        assert_eq!(view(0).scroll(0, Scroll::LineUp), (0, 0));
+    }
+
+    #[test]
+    fn scroll_pages() {
+        assert_eq!(view(0).scroll(3, Scroll::HalfPageDown), (5, 8));
+        assert_eq!(view(5).scroll(8, Scroll::HalfPageUp), (0, 3));
+        assert_eq!(view(0).scroll(3, Scroll::PageDown), (8, 10));
+        assert_eq!(view(8).scroll(10, Scroll::PageUp), (0, 7));
+        assert_eq!(view(95).scroll(97, Scroll::PageDown), (99, 99));
+    }
+
+    #[test]
+    fn reposition_cursor_line() {
+        assert_eq!(view(0).scroll(30, Scroll::CursorTop), (28, 30));
+        assert_eq!(view(0).scroll(30, Scroll::CursorMiddle), (25, 30));
+        assert_eq!(view(0).scroll(30, Scroll::CursorBottom), (23, 30));
+        assert_eq!(view(10).scroll(1, Scroll::CursorBottom), (0, 1));
+    }
+
+    #[test]
+    fn short_buffer() {
+        let short = Viewport { top: 0, height: 10, line_count: 3, scrolloff: 0 };
+        assert_eq!(short.follow(2), 0);
+        assert_eq!(short.scroll(0, Scroll::PageDown), (2, 2));
+        let empty = Viewport { top: 0, height: 10, line_count: 0, scrolloff: 0 };
+        assert_eq!(empty.scroll(0, Scroll::HalfPageDown), (0, 0));
+    }
+}
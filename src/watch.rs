@@ -0,0 +1,183 @@
+#[cfg(target_os = "linux")]
+pub use self::inotify::Watcher;
+#[cfg(not(target_os = "linux"))]
+pub use self::poll::Watcher;
+
+/// Watches the directory of the edited file with inotify, so changes made
+/// by other programs are noticed without polling.  The directory is watched
+/// rather than the file because tools often replace a file by renaming a
+/// new one over it.
+#[cfg(target_os = "linux")]
+mod inotify {
+    use libc::{self, c_char, c_int, c_void};
+    use std::ffi::{CString, OsString};
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::Path;
+    use std::ptr;
+
+    // Not bound by the libc crate.
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+        fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int;
+    }
+
+    // The kernel defines these as the matching open(2) flags.
+    const IN_NONBLOCK: c_int = libc::O_NONBLOCK;
+    const IN_CLOEXEC: c_int = libc::O_CLOEXEC;
+
+    const IN_MODIFY: u32 = 0x2;
+    const IN_ATTRIB: u32 = 0x4;
+    const IN_CLOSE_WRITE: u32 = 0x8;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+
+    /// `struct inotify_event` without the name that follows it.
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct Event {
+        wd: c_int,
+        mask: u32,
+        cookie: u32,
+        len: u32,
+    }
+
+    pub struct Watcher {
+        fd: c_int,
+        watch: Option<c_int>,
+        name: Option<OsString>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watcher { fd, watch: None, name: None })
+        }
+
+        /// Watch `file` instead of the previous one.
+        pub fn watch(&mut self, file: &Path) -> io::Result<()> {
+            if let Some(watch) = self.watch.take() {
+                unsafe { inotify_rm_watch(self.fd, watch) };
+            }
+            let dir = match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|err| { io::Error::new(io::ErrorKind::InvalidInput, err) })?;
+            let mask = IN_MODIFY | IN_ATTRIB | IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE;
+            let watch = unsafe { inotify_add_watch(self.fd, dir.as_ptr(), mask) };
+            if watch < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watch = Some(watch);
+            self.name = file.file_name().map(|name| { name.to_os_string() });
+            Ok(())
+        }
+
+        /// Read the pending events and report whether any concern the
+        /// watched file.  Never blocks.
+        pub fn changed(&mut self) -> bool {
+            let header = mem::size_of::<Event>();
+            let mut changed = false;
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+                if n <= 0 {
+                    return changed;
+                }
+                let mut events = &buf[.. n as usize];
+                while events.len() >= header {
+                    let event = unsafe { ptr::read_unaligned(events.as_ptr() as *const Event) };
+                    let len = event.len as usize;
+                    let name = &events[header .. header + len];
+                    let name = &name[.. name.iter().position(|&b| { b == 0 }).unwrap_or(name.len())];
+                    if Some(event.wd) == self.watch && self.name == Some(OsString::from_vec(name.to_vec())) {
+                        changed = true;
+                    }
+                    events = &events[header + len ..];
+                }
+            }
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+/// Where inotify is missing, notices changes by comparing the file's
+/// status each time it is asked.
+#[cfg(not(target_os = "linux"))]
+mod poll {
+    use buffer::FileStat;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    pub struct Watcher {
+        file: Option<PathBuf>,
+        stat: Option<FileStat>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            Ok(Watcher { file: None, stat: None })
+        }
+
+        /// Watch `file` instead of the previous one.
+        pub fn watch(&mut self, file: &Path) -> io::Result<()> {
+            self.stat = FileStat::of(file);
+            self.file = Some(file.to_path_buf());
+            Ok(())
+        }
+
+        /// Whether the watched file's status differs from the last call.
+        pub fn changed(&mut self) -> bool {
+            let stat = match self.file {
+                Some(ref file) => FileStat::of(file),
+                None => return false,
+            };
+            let changed = stat != self.stat;
+            self.stat = stat;
+            changed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn notices_writes_and_renames() {
+        fs::create_dir_all("/tmp/virs-watch").unwrap();
+        let path = Path::new("/tmp/virs-watch/watched");
+        File::create(path).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch(path).unwrap();
+        assert!(!watcher.changed());
+
+        File::create("/tmp/virs-watch/other").unwrap().write_all(b"x").unwrap();
+        assert!(!watcher.changed());
+
+        File::create(path).unwrap().write_all(b"changed").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        File::create("/tmp/virs-watch/new").unwrap().write_all(b"replaced").unwrap();
+        fs::rename("/tmp/virs-watch/new", path).unwrap();
+        assert!(watcher.changed());
+    }
+}